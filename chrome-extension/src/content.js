@@ -65,19 +65,11 @@ const inject = async () => {
     scriptElement.src = chrome.runtime.getURL("src/module_content.js");
     document.body.appendChild(scriptElement);
 
-    if (extPrefs.highlightHuff) {
-        if (extPrefs.customCssStyles.length > 0) {
-            // inject our custom styles
-            const extStyles = document.createElement('style');
-            extStyles.innerHTML = extPrefs.customCssStyles;
-            document.head.appendChild(extStyles);
-        }
-        else {
-            // inject the highlight.js stylesheet
-            const hljsStyles = document.createElement('link');
-            hljsStyles.rel = 'stylesheet';
-            hljsStyles.href = 'https://cdn.jsdelivr.net/gh/highlightjs/cdn-release@11.9.0/build/styles/dark.min.css';
-            document.head.appendChild(hljsStyles);
-        }
+    // module_content.js injects the layout styles of the HUFF output first,
+    // the custom styles are added after them, so they take precedence
+    if (extPrefs.customCssStyles.length > 0) {
+        const extStyles = document.createElement('style');
+        extStyles.innerHTML = extPrefs.customCssStyles;
+        document.head.appendChild(extStyles);
     }
 };
\ No newline at end of file
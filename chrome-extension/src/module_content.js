@@ -1,72 +1,54 @@
 
-import hljs from 'https://cdnjs.cloudflare.com/ajax/libs/highlight.js/11.9.0/es/highlight.min.js';
-import yaml from 'https://cdnjs.cloudflare.com/ajax/libs/highlight.js/11.9.0/es/languages/yaml.min.js';
-hljs.registerLanguage('yaml', yaml);
-
-import * as linkify from 'https://cdn.jsdelivr.net/npm/linkifyjs@4.1.3/+esm';
-import linkifyHtml from "https://cdn.jsdelivr.net/npm/linkify-html@4.1.3/+esm";
-
-import init, { js_fhir_to_huff, js_fhir_to_huff_custom } from "./static/hff-wasm.js";
-
-// inject the highlight.js stylesheet
-const hljsStyles = document.createElement('link');
-hljsStyles.rel = 'stylesheet';
-hljsStyles.href = 'https://cdn.jsdelivr.net/gh/highlightjs/cdn-release@11.9.0/build/styles/dark.min.css';
-document.head.appendChild(hljsStyles);
-
+import init, { js_fhir_to_huff_with_options, js_guess_base_url, js_stylesheet } from "./static/hff-wasm.js";
 
 init().then(() => {
 
     // inject preferenes into the page
     const hidden = document.getElementById('extPrefs');
     const extPrefs = JSON.parse(hidden.value);
+
+    // the default styles lay out the collapsible sections, so they are needed without highlighting too,
+    // put them before the custom styles injected by content.js
+    const baseStyles = document.createElement('style');
+    baseStyles.textContent = js_stylesheet();
+    document.head.prepend(baseStyles);
     
     // get rid of the source code checkbox
     document.body.removeChild(document.querySelector('body > div'));
 
     // format all pre elements (should be just one)
-    const preElements = document.getElementsByTagName('pre');
+    const preElements = Array.from(document.getElementsByTagName('pre'));
     for (const preElement of preElements) {
         try {            
             
-            // Create HUFF (optionally with custom mappings if present)
+            // Create HUFF as HTML (optionally with custom mappings if present)
+            const options = {
+                format: 'html',
+                links: extPrefs.makeLinksClickable,
+            };
+            if (extPrefs.customMappings) {
+                options.mapping = extPrefs.customMappings;
+            }
+            if (extPrefs.makeReferencesClickable) {
+                // references are relative to the FHIR server base URL
+                options.base_url = js_guess_base_url(window.location.href) || window.location.origin;
+            }
             const result = JSON.parse(
-                extPrefs.customMappings 
-                    ? js_fhir_to_huff_custom(preElement.textContent, extPrefs.customMappings) 
-                    : js_fhir_to_huff(preElement.textContent)
+                js_fhir_to_huff_with_options(preElement.textContent, JSON.stringify(options))
             );
 
             if (result.success === true) {
-                let yaml = result.yaml;
-                // highlight yaml
-                if (extPrefs.highlightHuff) {
-                    yaml = hljs.highlight(yaml, {language: 'yaml'}).value;
-                }
-
-                // posprocess to make relatve URLs absolute
-                //yaml = yaml.replace(/\>((?:\/|\.\.?\/)[^\s]+(?:\/|\b))/g, '>'+window.location.origin+'$1');
-
-                // postprocess to make all URLs clickable
-                if (extPrefs.makeLinksClickable) {
-                    yaml = linkifyHtml(yaml, {});
-                }
-                
-                // postprocess Reference() to make it clickable
-                if (extPrefs.makeReferencesClickable) {
-                    // poor algo to get the FHIR server base URL
-                    let fhirServerUrl = window.location.origin;
-                    if (window.location.search.length > 0) {
-                        fhirServerUrl += window.location.pathname.split('/').slice(0, -1).join('/');
-                    }
-                    else {
-                        fhirServerUrl += window.location.pathname.split('/').slice(0, -2).join('/');
+                // update document
+                const container = document.createElement('div');
+                container.innerHTML = result.html;
+                if (!extPrefs.highlightHuff) {
+                    // keep the layout, but drop the classes that color keys, codes and references
+                    const colored = '.hff-key, .hff-literal, .hff-system, .hff-code, .hff-separator, .hff-reference';
+                    for (const element of container.querySelectorAll(colored)) {
+                        element.removeAttribute('class');
                     }
-
-                    yaml = yaml.replace(/Reference\((.+?)\)/g, '<a href="'+fhirServerUrl+'/$1">$1</a>');
                 }
-
-                // update document
-                preElement.innerHTML = `<code class="language-yaml">${yaml}</code>`;
+                preElement.replaceWith(container.firstElementChild);
             }
             else {
                 console.log("Error in WASM code: " + result.error);
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+
+/**
+ * Mapping coverage over a corpus of resources.
+ * Feed it trees that were reformatted with `explain` enabled; it counts the objects that a rule
+ * formatted and collects the signatures of those that no rule matched, along with their key paths.
+ */
+#[derive(Debug, Default)]
+pub struct Coverage {
+    pub matched: usize,
+    unmatched: HashMap<String, UnmatchedSignature>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnmatchedSignature {
+    pub signature: String,
+    pub count: usize,
+    // key path (e.g. `Observation.code.coding`) -> count
+    pub paths: BTreeMap<String, usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn add(&mut self, explained: &serde_json::Value) {
+        let root = explained
+            .get("resourceType")
+            .and_then(|t| t.as_str())
+            .unwrap_or("$")
+            .to_string();
+        if let Some(map) = explained.as_object() {
+            for (k, v) in map {
+                self.visit(v, &format!("{}.{}", root, k));
+            }
+        }
+    }
+
+    fn visit(&mut self, v: &serde_json::Value, path: &str) {
+        if let Some(arr) = v.as_array() {
+            arr.iter().for_each(|v2| self.visit(v2, path));
+            return;
+        }
+        let Some(map) = v.as_object() else {
+            return;
+        };
+        if map.contains_key("@value") {
+            self.matched += 1;
+            return;
+        }
+        // whole resources (e.g. contained resources) and their wrappers (e.g. Bundle entries)
+        // are not worth a rule
+        let is_resource = map.contains_key("resourceType");
+        let wraps_resource = map.values().any(|v2| v2.get("resourceType").is_some());
+        if !is_resource
+            && !wraps_resource
+            && map.get("@rule").and_then(|r| r.as_str()) == Some("none")
+            && let Some(signature) = map.get("@signature").and_then(|s| s.as_str())
+        {
+            let entry = self
+                .unmatched
+                .entry(signature.to_string())
+                .or_insert_with(|| UnmatchedSignature {
+                    signature: signature.to_string(),
+                    count: 0,
+                    paths: BTreeMap::new(),
+                });
+            entry.count += 1;
+            *entry.paths.entry(path.to_string()).or_default() += 1;
+        }
+        let path = match map.get("resourceType").and_then(|t| t.as_str()) {
+            Some(resource_type) if is_resource => resource_type.to_string(),
+            _ => path.to_string(),
+        };
+        for (k, v2) in map.iter().filter(|(k, _)| !k.starts_with('@')) {
+            self.visit(v2, &format!("{}.{}", path, k));
+        }
+    }
+
+    /**
+     * Unmatched signatures, most frequent first.
+     */
+    pub fn unmatched(&self) -> Vec<&UnmatchedSignature> {
+        let mut unmatched = self
+            .unmatched
+            .values()
+            .collect::<Vec<&UnmatchedSignature>>();
+        unmatched.sort_by(|a, b| b.count.cmp(&a.count).then(a.signature.cmp(&b.signature)));
+        unmatched
+    }
+
+    /**
+     * Report in *.hfc format: statistics as comments, followed by a rule skeleton for every
+     * unmatched signature, ready to be edited and pasted into a mapping file.
+     */
+    pub fn to_report(&self) -> String {
+        let unmatched = self.unmatched();
+        let unmatched_total = unmatched.iter().map(|u| u.count).sum::<usize>();
+        let mut report = format!(
+            "// {} of {} objects formatted by a rule, {} distinct unmatched signatures\n",
+            self.matched,
+            self.matched + unmatched_total,
+            unmatched.len()
+        );
+        for u in unmatched {
+            let paths = u
+                .paths
+                .iter()
+                .map(|(path, count)| format!("{} ({}x)", path, count))
+                .collect::<Vec<String>>()
+                .join(", ");
+            report.push_str(&format!("// {}x {}\n", u.count, paths));
+            report.push_str(&rule_skeleton(&u.signature));
+        }
+        report
+    }
+}
+
+/**
+ * Signature and template lines of a rule for a signature string like `code|system`.
+ */
+pub fn rule_skeleton(signature: &str) -> String {
+    let keys = signature.split('|').collect::<Vec<&str>>();
+    let template = keys
+        .iter()
+        .map(|k| format!("{{$.{}}}", k))
+        .collect::<Vec<String>>()
+        .join(" | ");
+    format!("#[{}]\n{}\n", keys.join(", "), template)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_coverage_report() {
+        let explained = json!({
+            "resourceType": "Observation",
+            "code": {
+                "@signature": "coding|text",
+                "@rule": "none",
+                "coding": {
+                    "@signature": "code|system",
+                    "@rule": "line 2",
+                    "@value": "http://loinc.org | 8867-4"
+                },
+                "text": "Heart rate"
+            },
+            "component": [
+                { "@signature": "code|valueString", "@rule": "none", "code": "a", "valueString": "x" },
+                { "@signature": "code|valueString", "@rule": "none", "code": "b", "valueString": "y" }
+            ]
+        });
+
+        let mut coverage = Coverage::new();
+        coverage.add(&explained);
+        assert_eq!(coverage.matched, 1);
+        assert_eq!(
+            coverage.to_report(),
+            "// 1 of 4 objects formatted by a rule, 2 distinct unmatched signatures\n\
+             // 2x Observation.component (2x)\n\
+             #[code, valueString]\n\
+             {$.code} | {$.valueString}\n\
+             // 1x Observation.code (1x)\n\
+             #[coding, text]\n\
+             {$.coding} | {$.text}\n"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+/**
+ * Structural diff of two reformatted (HUFF) trees.
+ * Arrays are matched by element identity (e.g. `id` or `system|code`) rather than by position,
+ * so reordered or inserted elements don't show up as a cascade of changes.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffTag {
+    Same,
+    Removed,
+    Added,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffLine {
+    pub tag: DiffTag,
+    pub depth: usize,
+    pub text: String,
+}
+
+pub fn diff_trees(a: &serde_json::Value, b: &serde_json::Value) -> Vec<DiffLine> {
+    let mut out = Vec::new();
+    match (a.as_object(), b.as_object()) {
+        (Some(a_map), Some(b_map)) => diff_entries(a_map, b_map, 0, &mut out),
+        _ => diff_values(a, b, 0, "", &mut out),
+    }
+    out
+}
+
+/**
+ * Render diff lines in unified style, i.e. prefixed with ` `, `-` or `+`.
+ * With `color` set, removed and added lines are highlighted with ANSI escape codes.
+ */
+pub fn to_unified(lines: &[DiffLine], color: bool) -> String {
+    lines
+        .iter()
+        .map(|line| {
+            let (sign, ansi) = match line.tag {
+                DiffTag::Same => (' ', ""),
+                DiffTag::Removed => ('-', "\x1b[31m"),
+                DiffTag::Added => ('+', "\x1b[32m"),
+            };
+            let text = format!("{} {}{}", sign, "  ".repeat(line.depth), line.text);
+            if color && !ansi.is_empty() {
+                format!("{}{}\x1b[0m\n", ansi, text)
+            } else {
+                format!("{}\n", text)
+            }
+        })
+        .collect()
+}
+
+/**
+ * True if the diff contains any changes.
+ */
+pub fn has_changes(lines: &[DiffLine]) -> bool {
+    lines.iter().any(|line| line.tag != DiffTag::Same)
+}
+
+fn push(out: &mut Vec<DiffLine>, tag: DiffTag, depth: usize, text: String) {
+    out.push(DiffLine { tag, depth, text });
+}
+
+/**
+ * Push a scalar, with a line of its own for every line of a multi-line string (a literal block),
+ * so that each of them is marked.
+ */
+fn push_scalar(
+    out: &mut Vec<DiffLine>,
+    tag: DiffTag,
+    depth: usize,
+    prefix: &str,
+    v: &serde_json::Value,
+) {
+    let text = scalar_to_str(v);
+    let mut lines = text.split('\n');
+    let first = lines.next().unwrap_or_default();
+    push(out, tag, depth, format!("{}{}", prefix, first));
+    for line in lines {
+        push(out, tag, depth, line.to_string());
+    }
+}
+
+fn scalar_to_str(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::Object(map) if map.is_empty() => "{}".to_string(),
+        serde_json::Value::Array(arr) if arr.is_empty() => "[]".to_string(),
+        _ => crate::hff::yaml::to_yaml(v, 0).trim_end().to_string(),
+    }
+}
+
+fn is_container(v: &serde_json::Value) -> bool {
+    match v {
+        serde_json::Value::Object(map) => !map.is_empty(),
+        serde_json::Value::Array(arr) => !arr.is_empty(),
+        _ => false,
+    }
+}
+
+/**
+ * Emit a whole subtree with the same tag, e.g. for removed or added entries.
+ */
+fn emit_value(
+    tag: DiffTag,
+    depth: usize,
+    prefix: &str,
+    v: &serde_json::Value,
+    out: &mut Vec<DiffLine>,
+) {
+    if !is_container(v) {
+        push_scalar(out, tag, depth, prefix, v);
+        return;
+    }
+    push(out, tag, depth, prefix.trim_end().to_string());
+    if let Some(map) = v.as_object() {
+        for (k, v2) in map {
+            emit_value(tag, depth + 1, &format!("{}: ", k), v2, out);
+        }
+    } else if let Some(arr) = v.as_array() {
+        for v2 in arr {
+            emit_value(tag, depth + 1, "- ", v2, out);
+        }
+    }
+}
+
+/**
+ * Emit an unchanged subtree as context. Nested structures are elided.
+ */
+fn emit_same(depth: usize, prefix: &str, v: &serde_json::Value, out: &mut Vec<DiffLine>) {
+    let text = match v {
+        serde_json::Value::Object(map) if !map.is_empty() => "{…}",
+        serde_json::Value::Array(arr) if !arr.is_empty() => "[…]",
+        _ => return push_scalar(out, DiffTag::Same, depth, prefix, v),
+    };
+    push(out, DiffTag::Same, depth, format!("{}{}", prefix, text));
+}
+
+fn diff_entries(
+    a_map: &serde_json::Map<String, serde_json::Value>,
+    b_map: &serde_json::Map<String, serde_json::Value>,
+    depth: usize,
+    out: &mut Vec<DiffLine>,
+) {
+    let keys = a_map
+        .keys()
+        .chain(b_map.keys().filter(|k| !a_map.contains_key(*k)));
+    for k in keys {
+        let prefix = format!("{}: ", k);
+        match (a_map.get(k), b_map.get(k)) {
+            (Some(a), Some(b)) => diff_values(a, b, depth, &prefix, out),
+            (Some(a), None) => emit_value(DiffTag::Removed, depth, &prefix, a, out),
+            (None, Some(b)) => emit_value(DiffTag::Added, depth, &prefix, b, out),
+            (None, None) => {}
+        }
+    }
+}
+
+fn diff_values(
+    a: &serde_json::Value,
+    b: &serde_json::Value,
+    depth: usize,
+    prefix: &str,
+    out: &mut Vec<DiffLine>,
+) {
+    if a == b {
+        emit_same(depth, prefix, a, out);
+        return;
+    }
+    match (a, b) {
+        (serde_json::Value::Object(a_map), serde_json::Value::Object(b_map)) => {
+            push(out, DiffTag::Same, depth, prefix.trim_end().to_string());
+            diff_entries(a_map, b_map, depth + 1, out);
+        }
+        // single-element arrays are unlisted during reformatting, so compare them as arrays
+        (serde_json::Value::Array(_), _) | (_, serde_json::Value::Array(_)) => {
+            let a_arr = as_list(a);
+            let b_arr = as_list(b);
+            push(out, DiffTag::Same, depth, prefix.trim_end().to_string());
+            diff_arrays(&a_arr, &b_arr, depth + 1, out);
+        }
+        _ => {
+            emit_value(DiffTag::Removed, depth, prefix, a, out);
+            emit_value(DiffTag::Added, depth, prefix, b, out);
+        }
+    }
+}
+
+fn as_list(v: &serde_json::Value) -> Vec<serde_json::Value> {
+    match v.as_array() {
+        Some(arr) => arr.clone(),
+        None => vec![v.clone()],
+    }
+}
+
+/**
+ * Identity of an array element, used to pair up elements of both sides.
+ * Objects are identified by `id`, `fullUrl`, `system|code`, `system|value`, `url` or `reference`,
+ * or else by their (first) `code` or `coding`.
+ * Strings rendered by a mapping rule like `system | code | display` are identified by their
+ * first two segments, so a changed display shows up as a change of the same element.
+ */
+fn identity(v: &serde_json::Value) -> Option<String> {
+    match v {
+        serde_json::Value::Object(map) => {
+            let get = |k: &str| map.get(k).and_then(|v| v.as_str());
+            if let Some(id) = get("id") {
+                Some(format!("id:{}", id))
+            } else if let Some(url) = get("fullUrl") {
+                Some(format!("fullUrl:{}", url))
+            } else if let (Some(system), Some(code)) = (get("system"), get("code")) {
+                Some(format!("{}|{}", system, code))
+            } else if let (Some(system), Some(value)) = (get("system"), get("value")) {
+                Some(format!("{}|{}", system, value))
+            } else if let Some(url) = get("url") {
+                Some(format!("url:{}", url))
+            } else if let Some(reference) = get("reference") {
+                Some(format!("reference:{}", reference))
+            } else {
+                // elements without an id of their own, like the `component` of an Observation
+                ["code", "coding"].iter().find_map(|k| {
+                    let nested = map.get(*k)?;
+                    let first = nested.as_array().map_or(Some(nested), |arr| arr.first())?;
+                    identity(first).map(|id| format!("{}:{}", k, id))
+                })
+            }
+        }
+        serde_json::Value::String(s) => {
+            let segments = s.split(" | ").collect::<Vec<&str>>();
+            if segments.len() > 2 {
+                Some(segments[..2].join(" | "))
+            } else {
+                Some(s.clone())
+            }
+        }
+        serde_json::Value::Array(_) => None,
+        _ => Some(v.to_string()),
+    }
+}
+
+fn diff_arrays(
+    a_arr: &[serde_json::Value],
+    b_arr: &[serde_json::Value],
+    depth: usize,
+    out: &mut Vec<DiffLine>,
+) {
+    // pair elements by identity, elements without identity are paired by position
+    let mut a_by_identity: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut a_anonymous = Vec::new();
+    for (i, v) in a_arr.iter().enumerate() {
+        match identity(v) {
+            Some(id) => a_by_identity.entry(id).or_default().push(i),
+            None => a_anonymous.push(i),
+        }
+    }
+    a_by_identity
+        .values_mut()
+        .for_each(|indices| indices.reverse());
+    a_anonymous.reverse();
+
+    let mut emitted = vec![false; a_arr.len()];
+    let mut next_removed = 0;
+    for b in b_arr {
+        let paired = match identity(b) {
+            Some(id) => a_by_identity.get_mut(&id).and_then(|indices| indices.pop()),
+            None => a_anonymous.pop(),
+        };
+        match paired {
+            Some(i) => {
+                // keep removed elements close to where they were
+                while next_removed < i {
+                    if !emitted[next_removed]
+                        && !is_pending(&a_by_identity, &a_anonymous, next_removed)
+                    {
+                        emit_value(DiffTag::Removed, depth, "- ", &a_arr[next_removed], out);
+                        emitted[next_removed] = true;
+                    }
+                    next_removed += 1;
+                }
+                emitted[i] = true;
+                diff_values(&a_arr[i], b, depth, "- ", out);
+            }
+            None => emit_value(DiffTag::Added, depth, "- ", b, out),
+        }
+    }
+    for (i, a) in a_arr.iter().enumerate() {
+        if !emitted[i] {
+            emit_value(DiffTag::Removed, depth, "- ", a, out);
+        }
+    }
+}
+
+/**
+ * An element is still waiting for its partner if it is left in one of the pairing queues.
+ */
+fn is_pending(by_identity: &HashMap<String, Vec<usize>>, anonymous: &[usize], i: usize) -> bool {
+    by_identity.values().any(|indices| indices.contains(&i)) || anonymous.contains(&i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_arrays_by_identity() {
+        let a = json!({
+            "status": "preliminary",
+            "code": [
+                "http://loinc.org | 1234-5 | Old display",
+                "http://snomed.info/sct | 123"
+            ]
+        });
+        let b = json!({
+            "status": "final",
+            "code": [
+                "http://snomed.info/sct | 123",
+                "http://loinc.org | 1234-5 | New display"
+            ]
+        });
+
+        let lines = diff_trees(&a, &b);
+        assert!(has_changes(&lines));
+        let expected = [
+            "- status: preliminary",
+            "+ status: final",
+            "  code:",
+            "    - http://snomed.info/sct | 123",
+            "-   - http://loinc.org | 1234-5 | Old display",
+            "+   - http://loinc.org | 1234-5 | New display",
+        ];
+        assert_eq!(to_unified(&lines, false), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_diff_unlisted_array() {
+        let a = json!({ "name": "Jane Doe" });
+        let b = json!({ "name": ["Jane Doe", "Janie"] });
+
+        let lines = diff_trees(&a, &b);
+        assert_eq!(
+            to_unified(&lines, false),
+            "  name:\n    - Jane Doe\n+   - Janie\n"
+        );
+        assert!(!has_changes(&diff_trees(&a, &a)));
+    }
+
+    #[test]
+    fn test_diff_multi_line_string() {
+        let a = json!({ "text": "line 1\nline 2" });
+        let b = json!({ "text": "line 1\nline 3" });
+
+        let lines = diff_trees(&a, &b);
+        assert_eq!(
+            to_unified(&lines, false),
+            "- text: |-\n-   line 1\n-   line 2\n+ text: |-\n+   line 1\n+   line 3\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_reordered_components() {
+        let a = json!({ "component": [
+            { "code": { "coding": "http://loinc.org | 8480-6 | Systolic" }, "value": "120 mm[Hg]" },
+            { "code": { "coding": "http://loinc.org | 8462-4 | Diastolic" }, "value": "80 mm[Hg]" }
+        ] });
+        let b = json!({ "component": [
+            { "code": { "coding": "http://loinc.org | 8462-4 | Diastolic" }, "value": "85 mm[Hg]" },
+            { "code": { "coding": "http://loinc.org | 8480-6 | Systolic" }, "value": "120 mm[Hg]" }
+        ] });
+
+        let lines = diff_trees(&a, &b);
+        let expected = [
+            "  component:",
+            "    -",
+            "      code: {…}",
+            "-     value: 80 mm[Hg]",
+            "+     value: 85 mm[Hg]",
+            "    - {…}",
+        ];
+        assert_eq!(to_unified(&lines, false), expected.join("\n") + "\n");
+    }
+}
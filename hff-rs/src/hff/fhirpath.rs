@@ -0,0 +1,1085 @@
+use crate::hff::resolve::relative_key;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+/**
+ * Evaluator for the subset of FHIRPath (http://hl7.org/fhirpath/) that is useful in templates:
+ * path navigation (including choice types, e.g. `value` for `valueQuantity`), indexers, literals
+ * (dates and times like `@2024-01-31` are compared as the strings they are in JSON), `$this`, the
+ * environment variables `%resource`, `%rootResource`, `%context` and `%ucum`, the operators `=`, `!=`, `<`, `>`, `<=`, `>=`, `|`, `&`, `+`, `-`, `and`, `or` and the
+ * most common functions like `where()`, `select()`, `first()` or `exists()`, as well as `extension()`,
+ * `getResourceKey()` and `getReferenceKey()` for SQL on FHIR views.
+ * There is no type information beyond the JSON itself, so `ofType()` picks choice elements by
+ * their key (e.g. `valueQuantity`), resources by `resourceType` and primitives by their JSON type.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FhirPath {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    This,
+    Variable(String),
+    Literal(Value),
+    Empty,
+    // member access on the result of the base, or on the focus if there is none
+    Member(Option<Box<Expr>>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Function(Option<Box<Expr>>, String, Vec<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+    LtEq,
+    GtEq,
+    Union,
+    Concat,
+    Plus,
+    Minus,
+}
+
+// name, minimal and maximal number of arguments
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("where", 1, 1),
+    ("select", 1, 1),
+    ("all", 1, 1),
+    ("exists", 0, 1),
+    ("empty", 0, 0),
+    ("count", 0, 0),
+    ("first", 0, 0),
+    ("last", 0, 0),
+    ("tail", 0, 0),
+    ("skip", 1, 1),
+    ("take", 1, 1),
+    ("distinct", 0, 0),
+    ("not", 0, 0),
+    ("iif", 2, 3),
+    ("join", 0, 1),
+    ("startsWith", 1, 1),
+    ("endsWith", 1, 1),
+    ("contains", 1, 1),
+    ("matches", 1, 1),
+    ("lower", 0, 0),
+    ("upper", 0, 0),
+    ("length", 0, 0),
+    ("substring", 1, 2),
+    ("toString", 0, 0),
+    ("ofType", 1, 1),
+    // functions of SQL on FHIR views (https://sql-on-fhir.org)
+    ("extension", 1, 1),
+    ("getResourceKey", 0, 0),
+    ("getReferenceKey", 0, 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(Value),
+    This,
+    Variable(String),
+    Sym(&'static str),
+}
+
+/** The environment variables an expression can refer to as `%name`. */
+pub(crate) const VARIABLES: &[&str] = &["resource", "rootResource", "context", "ucum"];
+
+const SYMBOLS: &[&str] = &[
+    "!=", "<=", ">=", "{}", "(", ")", "[", "]", ".", ",", "=", "<", ">", "|", "&", "+", "-",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars = input.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '`' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".to_string()),
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(escaped) => s.push(*escaped),
+                            None => return Err("unterminated string".to_string()),
+                        }
+                        i += 2;
+                    }
+                    Some(end) if *end == c => break,
+                    Some(other) => {
+                        s.push(*other);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            tokens.push(if c == '`' {
+                Token::Ident(s)
+            } else {
+                Token::Str(s)
+            });
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let number = chars[start..i].iter().collect::<String>();
+            tokens.push(Token::Num(
+                serde_json::from_str(&number).map_err(|e| e.to_string())?,
+            ));
+        } else if c == '@' {
+            // a date, date time or time literal, e.g. `@2024-01-31`, `@2024-01-31T10:00:00Z` or `@T10:00`
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || "-:.+TZ".contains(chars[i])) {
+                i += 1;
+            }
+            let literal = chars[start..i].iter().collect::<String>();
+            let literal = literal.strip_prefix('T').unwrap_or(&literal);
+            let literal = literal.strip_suffix('T').unwrap_or(literal);
+            if literal.is_empty() {
+                return Err("unexpected `@`".to_string());
+            }
+            tokens.push(Token::Str(literal.to_string()));
+        } else if c == '%' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name = chars[start..i].iter().collect::<String>();
+            if !VARIABLES.contains(&name.as_str()) {
+                return Err(format!("unknown variable `%{}`", name));
+            }
+            tokens.push(Token::Variable(name));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let ident = chars[start..i].iter().collect::<String>();
+            tokens.push(match ident.as_str() {
+                "$this" => Token::This,
+                _ if c == '$' => return Err(format!("unknown variable `{}`", ident)),
+                _ => Token::Ident(ident),
+            });
+        } else {
+            let rest = chars[i..].iter().take(2).collect::<String>();
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                Some(symbol) => {
+                    tokens.push(Token::Sym(symbol));
+                    i += symbol.len();
+                }
+                None => return Err(format!("unexpected `{}`", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Sym(s)) if *s == symbol) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", symbol))
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        // operators by increasing precedence
+        const LEVELS: &[&[(&str, Op)]] = &[
+            &[("or", Op::Or)],
+            &[("and", Op::And)],
+            &[("=", Op::Eq), ("!=", Op::NotEq)],
+            &[
+                ("<=", Op::LtEq),
+                (">=", Op::GtEq),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ],
+            &[("|", Op::Union)],
+            &[("&", Op::Concat), ("+", Op::Plus), ("-", Op::Minus)],
+        ];
+        if level == LEVELS.len() {
+            return self.postfix();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for (symbol, op) in LEVELS[level] {
+                if self.eat(symbol) || self.eat_keyword(symbol) {
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(*op, Box::new(left), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            if self.eat(".") {
+                expr = match self.next() {
+                    Some(Token::Ident(name)) => self.invocation(Some(expr), name)?,
+                    _ => return Err("expected a name after `.`".to_string()),
+                };
+            } else if self.eat("[") {
+                let index = self.binary(0)?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Ident(ident)) if ident == "true" || ident == "false" => {
+                Ok(Expr::Literal(Value::Bool(ident == "true")))
+            }
+            Some(Token::Ident(name)) => self.invocation(None, name),
+            Some(Token::Str(s)) => Ok(Expr::Literal(Value::String(s))),
+            Some(Token::Num(n)) => Ok(Expr::Literal(n)),
+            Some(Token::This) => Ok(Expr::This),
+            Some(Token::Variable(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Sym("{}")) => Ok(Expr::Empty),
+            Some(Token::Sym("(")) => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Sym(symbol)) => Err(format!("unexpected `{}`", symbol)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn invocation(&mut self, base: Option<Expr>, name: String) -> Result<Expr, String> {
+        let base = base.map(Box::new);
+        if !self.eat("(") {
+            return Ok(Expr::Member(base, name));
+        }
+        let mut args = Vec::new();
+        if !self.eat(")") {
+            loop {
+                args.push(self.binary(0)?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        match FUNCTIONS.iter().find(|(f, _, _)| *f == name) {
+            None => Err(format!("unknown function `{}()`", name)),
+            Some((_, min, max)) if args.len() < *min || args.len() > *max => Err(format!(
+                "`{}()` takes {} to {} arguments, got {}",
+                name,
+                min,
+                max,
+                args.len()
+            )),
+            Some(_) => Ok(Expr::Function(base, name, args)),
+        }
+    }
+}
+
+impl FhirPath {
+    pub fn parse(expression: &str) -> Result<FhirPath, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            pos: 0,
+        };
+        let expr = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(FhirPath { expr }),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    /**
+     * Evaluate the expression with `v` as focus. The result is always a collection.
+     */
+    pub fn evaluate(&self, v: &Value) -> Result<Vec<Value>, String> {
+        self.evaluate_in(v, v)
+    }
+
+    /**
+     * Evaluate the expression with `v` as focus, which is part of `resource`: `%resource` and
+     * `%rootResource` refer to the resource, `%context` to `v`.
+     */
+    pub fn evaluate_in(&self, v: &Value, resource: &Value) -> Result<Vec<Value>, String> {
+        let env = Env {
+            resource,
+            context: v,
+        };
+        eval(&self.expr, std::slice::from_ref(v), &env)
+    }
+
+    /**
+     * Name of the first member the expression navigates to from the focus, e.g. `coding` for
+     * `coding.where(system = 'x').code`.
+     */
+    pub fn first_key(&self) -> Option<&str> {
+        let mut expr = &self.expr;
+        loop {
+            match expr {
+                Expr::Member(None, name) => return Some(name),
+                Expr::Member(Some(base), _)
+                | Expr::Index(base, _)
+                | Expr::Function(Some(base), _, _) => expr = base,
+                _ => return None,
+            }
+        }
+    }
+}
+
+/**
+ * Evaluate a FHIRPath expression and join the primitive results with whitespace, like
+ * `xjsonp_first` does for JSONPath. Invalid expressions render empty.
+ */
+pub fn xfhirpath(v: &Value, expression: &str) -> String {
+    FhirPath::parse(expression)
+        .and_then(|path| path.evaluate(v))
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(primitive_to_string)
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn primitive_to_string(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/**
+ * Children of a node with the given name. Arrays are flattened and a name without an exact match
+ * also matches the keys of a choice type, e.g. `value` matches `valueQuantity`.
+ */
+/**
+ * FHIR data types a choice element can have, e.g. `Quantity` in `valueQuantity`.
+ */
+const CHOICE_TYPES: &[&str] = &[
+    "Base64Binary",
+    "Boolean",
+    "Canonical",
+    "Code",
+    "Date",
+    "DateTime",
+    "Decimal",
+    "Id",
+    "Instant",
+    "Integer",
+    "Integer64",
+    "Markdown",
+    "Oid",
+    "PositiveInt",
+    "String",
+    "Time",
+    "UnsignedInt",
+    "Uri",
+    "Url",
+    "Uuid",
+    "Address",
+    "Age",
+    "Annotation",
+    "Attachment",
+    "Availability",
+    "CodeableConcept",
+    "CodeableReference",
+    "Coding",
+    "ContactDetail",
+    "ContactPoint",
+    "Contributor",
+    "Count",
+    "DataRequirement",
+    "Distance",
+    "Dosage",
+    "Duration",
+    "Expression",
+    "ExtendedContactDetail",
+    "HumanName",
+    "Identifier",
+    "Meta",
+    "Money",
+    "ParameterDefinition",
+    "Period",
+    "Quantity",
+    "Range",
+    "Ratio",
+    "RatioRange",
+    "Reference",
+    "RelatedArtifact",
+    "SampledData",
+    "Signature",
+    "Timing",
+    "TriggerDefinition",
+    "UsageContext",
+];
+
+/**
+ * Whether `key` is the choice element `name` of some data type, e.g. `valueQuantity` for `value`,
+ * but not `statusReason` for `status`.
+ */
+pub(crate) fn is_choice_of(key: &str, name: &str) -> bool {
+    key.strip_prefix(name)
+        .is_some_and(|data_type| CHOICE_TYPES.contains(&data_type))
+}
+
+fn member(v: &Value, name: &str, out: &mut Vec<Value>) {
+    let Some(map) = v.as_object() else {
+        return;
+    };
+    let found = map.get(name).or_else(|| {
+        map.iter()
+            .find(|(k, _)| is_choice_of(k, name))
+            .map(|(_, child)| child)
+    });
+    match found {
+        Some(Value::Array(arr)) => out.extend(arr.iter().filter(|c| !c.is_null()).cloned()),
+        Some(Value::Null) | None => {}
+        Some(child) => out.push(child.clone()),
+    }
+}
+
+/**
+ * Boolean value of a collection: empty is unknown, a single boolean is itself and any other
+ * single item is true.
+ */
+fn to_bool(values: &[Value]) -> Result<Option<bool>, String> {
+    match values {
+        [] => Ok(None),
+        [Value::Bool(b)] => Ok(Some(*b)),
+        [_] => Ok(Some(true)),
+        _ => Err("expected a single boolean, got a collection".to_string()),
+    }
+}
+
+fn from_bool(b: Option<bool>) -> Vec<Value> {
+    b.map(Value::Bool).into_iter().collect()
+}
+
+fn singleton<'a>(values: &'a [Value], what: &str) -> Result<Option<&'a Value>, String> {
+    match values {
+        [] => Ok(None),
+        [v] => Ok(Some(v)),
+        _ => Err(format!("{} expects a single item, got a collection", what)),
+    }
+}
+
+fn string_arg(values: &[Value], what: &str) -> Result<Option<String>, String> {
+    Ok(singleton(values, what)?.and_then(primitive_to_string))
+}
+
+fn integer_arg(values: &[Value], what: &str) -> Result<usize, String> {
+    singleton(values, what)?
+        .and_then(|v| v.as_u64())
+        .map(|n| n as usize)
+        .ok_or_else(|| format!("{} expects a non-negative integer", what))
+}
+
+/** Compiles a `matches()` pattern once; expressions are re-evaluated per resource with the same patterns. */
+fn cached_regex(pattern: &str) -> Result<Regex, String> {
+    static CACHE: LazyLock<Mutex<HashMap<String, Regex>>> = LazyLock::new(Default::default);
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(regex) = cache.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+    cache.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
+
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x == y,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        _ => None,
+    }
+}
+
+/** What the environment variables of an evaluation refer to. */
+struct Env<'a> {
+    resource: &'a Value,
+    context: &'a Value,
+}
+
+fn eval(expr: &Expr, focus: &[Value], env: &Env) -> Result<Vec<Value>, String> {
+    match expr {
+        Expr::This => Ok(focus.to_vec()),
+        Expr::Variable(name) => Ok(vec![match name.as_str() {
+            "resource" | "rootResource" => env.resource.clone(),
+            "context" => env.context.clone(),
+            _ => Value::String("http://unitsofmeasure.org".to_string()),
+        }]),
+        Expr::Literal(v) => Ok(vec![v.clone()]),
+        Expr::Empty => Ok(Vec::new()),
+        Expr::Member(base, name) => {
+            let input = match base {
+                Some(base) => eval(base, focus, env)?,
+                None => focus.to_vec(),
+            };
+            let mut out = Vec::new();
+            input.iter().for_each(|v| member(v, name, &mut out));
+            Ok(out)
+        }
+        Expr::Index(base, index) => {
+            let index = integer_arg(&eval(index, focus, env)?, "an indexer")?;
+            Ok(eval(base, focus, env)?
+                .into_iter()
+                .skip(index)
+                .take(1)
+                .collect())
+        }
+        Expr::Function(base, name, args) => {
+            if name == "ofType"
+                && let Some(base) = base
+                && let Expr::Member(member_base, member_name) = base.as_ref()
+            {
+                return choice_of_type(member_base.as_deref(), member_name, args, focus, env);
+            }
+            let input = match base {
+                Some(base) => eval(base, focus, env)?,
+                None => focus.to_vec(),
+            };
+            call(name, input, args, focus, env)
+        }
+        Expr::Binary(op, left, right) => {
+            binary(*op, eval(left, focus, env)?, eval(right, focus, env)?)
+        }
+    }
+}
+
+/**
+ * `ofType()` on a member: `value.ofType(Quantity)` is the choice element `valueQuantity`,
+ * any other member is filtered by type like a collection.
+ */
+fn choice_of_type(
+    base: Option<&Expr>,
+    name: &str,
+    args: &[Expr],
+    focus: &[Value],
+    env: &Env,
+) -> Result<Vec<Value>, String> {
+    let type_name = type_specifier(args, "ofType")?;
+    let choice = format!("{}{}", name, capitalized(type_name));
+    let input = match base {
+        Some(base) => eval(base, focus, env)?,
+        None => focus.to_vec(),
+    };
+    let mut out = Vec::new();
+    for v in &input {
+        if v.get(&choice).is_some() {
+            member(v, &choice, &mut out);
+        } else {
+            let mut found = Vec::new();
+            member(v, name, &mut found);
+            out.extend(found.into_iter().filter(|v| is_of_type(v, type_name)));
+        }
+    }
+    Ok(out)
+}
+
+fn binary(op: Op, left: Vec<Value>, right: Vec<Value>) -> Result<Vec<Value>, String> {
+    match op {
+        Op::Union => {
+            let mut out = Vec::new();
+            for v in left.into_iter().chain(right) {
+                if !out.iter().any(|o| equals(o, &v)) {
+                    out.push(v);
+                }
+            }
+            Ok(out)
+        }
+        Op::And => Ok(from_bool(match (to_bool(&left)?, to_bool(&right)?) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        })),
+        Op::Or => Ok(from_bool(match (to_bool(&left)?, to_bool(&right)?) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        })),
+        Op::Eq | Op::NotEq => {
+            if left.is_empty() || right.is_empty() {
+                return Ok(Vec::new());
+            }
+            let same = left.len() == right.len()
+                && left.iter().zip(right.iter()).all(|(a, b)| equals(a, b));
+            Ok(vec![Value::Bool(same == (op == Op::Eq))])
+        }
+        Op::Lt | Op::Gt | Op::LtEq | Op::GtEq => {
+            let (Some(a), Some(b)) = (
+                singleton(&left, "a comparison")?,
+                singleton(&right, "a comparison")?,
+            ) else {
+                return Ok(Vec::new());
+            };
+            Ok(from_bool(compare(a, b).map(|ordering| match op {
+                Op::Lt => ordering.is_lt(),
+                Op::Gt => ordering.is_gt(),
+                Op::LtEq => ordering.is_le(),
+                _ => ordering.is_ge(),
+            })))
+        }
+        Op::Concat => {
+            let a = string_arg(&left, "`&`")?.unwrap_or_default();
+            let b = string_arg(&right, "`&`")?.unwrap_or_default();
+            Ok(vec![Value::String(a + &b)])
+        }
+        Op::Plus | Op::Minus => {
+            let (Some(a), Some(b)) = (singleton(&left, "`+`")?, singleton(&right, "`+`")?) else {
+                return Ok(Vec::new());
+            };
+            match (a, b, op) {
+                (Value::String(x), Value::String(y), Op::Plus) => {
+                    Ok(vec![Value::String(format!("{}{}", x, y))])
+                }
+                (Value::Number(x), Value::Number(y), _) => {
+                    let result = match (x.as_i64(), y.as_i64(), op) {
+                        (Some(i), Some(j), Op::Plus) => Value::from(i + j),
+                        (Some(i), Some(j), _) => Value::from(i - j),
+                        _ => {
+                            let (i, j) = (x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+                            Value::from(if op == Op::Plus { i + j } else { i - j })
+                        }
+                    };
+                    Ok(vec![result])
+                }
+                _ => Err("arithmetic needs two numbers".to_string()),
+            }
+        }
+    }
+}
+
+/**
+ * Name of the type given to a function like `ofType()`, without a namespace like `FHIR.`.
+ */
+fn type_specifier<'a>(args: &'a [Expr], name: &str) -> Result<&'a str, String> {
+    match args.first() {
+        Some(Expr::Member(_, type_name)) => Ok(type_name),
+        _ => Err(format!("`{}()` takes a type", name)),
+    }
+}
+
+fn capitalized(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/**
+ * Whether a value is of the given FHIR or System type, as far as its JSON tells:
+ * resources by `resourceType`, primitives by their JSON type.
+ */
+fn is_of_type(v: &Value, type_name: &str) -> bool {
+    match v {
+        Value::Object(map) => map.get("resourceType").and_then(|t| t.as_str()) == Some(type_name),
+        Value::Bool(_) => matches!(type_name, "boolean" | "Boolean"),
+        Value::Number(n) => match type_name {
+            "decimal" | "Decimal" => true,
+            "integer" | "Integer" | "integer64" | "positiveInt" | "unsignedInt" => n.is_i64(),
+            _ => false,
+        },
+        Value::String(_) => matches!(
+            type_name,
+            "string"
+                | "String"
+                | "code"
+                | "id"
+                | "markdown"
+                | "uri"
+                | "url"
+                | "canonical"
+                | "oid"
+                | "uuid"
+                | "base64Binary"
+                | "date"
+                | "Date"
+                | "dateTime"
+                | "DateTime"
+                | "instant"
+                | "time"
+                | "Time"
+        ),
+        _ => false,
+    }
+}
+
+fn call(
+    name: &str,
+    input: Vec<Value>,
+    args: &[Expr],
+    focus: &[Value],
+    env: &Env,
+) -> Result<Vec<Value>, String> {
+    // arguments are evaluated against the original focus, criteria (like in `where()`) per item
+    let arg = |i: usize| eval(&args[i], focus, env);
+    let per_item = |v: &Value| eval(&args[0], std::slice::from_ref(v), env);
+    let string_fn = |f: &dyn Fn(&str) -> Result<Value, String>| -> Result<Vec<Value>, String> {
+        match singleton(&input, name)? {
+            Some(Value::String(s)) => Ok(vec![f(s)?]),
+            Some(_) | None => Ok(Vec::new()),
+        }
+    };
+    match name {
+        "where" => {
+            let mut out = Vec::new();
+            for v in input {
+                if to_bool(&per_item(&v)?)? == Some(true) {
+                    out.push(v);
+                }
+            }
+            Ok(out)
+        }
+        "select" => {
+            let mut out = Vec::new();
+            for v in &input {
+                out.extend(per_item(v)?);
+            }
+            Ok(out)
+        }
+        "all" => {
+            for v in &input {
+                if to_bool(&per_item(v)?)? != Some(true) {
+                    return Ok(vec![Value::Bool(false)]);
+                }
+            }
+            Ok(vec![Value::Bool(true)])
+        }
+        "exists" if !args.is_empty() => {
+            for v in &input {
+                if to_bool(&per_item(v)?)? == Some(true) {
+                    return Ok(vec![Value::Bool(true)]);
+                }
+            }
+            Ok(vec![Value::Bool(false)])
+        }
+        "exists" => Ok(vec![Value::Bool(!input.is_empty())]),
+        "empty" => Ok(vec![Value::Bool(input.is_empty())]),
+        "count" => Ok(vec![Value::from(input.len())]),
+        "first" => Ok(input.into_iter().take(1).collect()),
+        "last" => Ok(input.into_iter().last().into_iter().collect()),
+        "tail" => Ok(input.into_iter().skip(1).collect()),
+        "skip" => Ok(input
+            .into_iter()
+            .skip(integer_arg(&arg(0)?, name)?)
+            .collect()),
+        "take" => Ok(input
+            .into_iter()
+            .take(integer_arg(&arg(0)?, name)?)
+            .collect()),
+        "distinct" => binary(Op::Union, input, Vec::new()),
+        "not" => Ok(from_bool(to_bool(&input)?.map(|b| !b))),
+        "iif" => match to_bool(&arg(0)?)? {
+            Some(true) => arg(1),
+            _ if args.len() == 3 => arg(2),
+            _ => Ok(Vec::new()),
+        },
+        "join" => {
+            let separator = match args.is_empty() {
+                true => None,
+                false => string_arg(&arg(0)?, name)?,
+            };
+            let joined = input
+                .iter()
+                .filter_map(primitive_to_string)
+                .collect::<Vec<String>>()
+                .join(separator.as_deref().unwrap_or(""));
+            Ok(vec![Value::String(joined)])
+        }
+        "startsWith" | "endsWith" | "contains" | "matches" => {
+            let Some(other) = string_arg(&arg(0)?, name)? else {
+                return Ok(Vec::new());
+            };
+            let pattern = match name {
+                "matches" => Some(cached_regex(&other)?),
+                _ => None,
+            };
+            string_fn(&|s| {
+                Ok(Value::Bool(match (name, &pattern) {
+                    ("startsWith", _) => s.starts_with(&other),
+                    ("endsWith", _) => s.ends_with(&other),
+                    ("contains", _) => s.contains(&other),
+                    (_, pattern) => pattern.as_ref().is_some_and(|p| p.is_match(s)),
+                }))
+            })
+        }
+        "lower" => string_fn(&|s| Ok(Value::String(s.to_lowercase()))),
+        "upper" => string_fn(&|s| Ok(Value::String(s.to_uppercase()))),
+        "length" => string_fn(&|s| Ok(Value::from(s.chars().count()))),
+        "substring" => {
+            let start = integer_arg(&arg(0)?, name)?;
+            let length = match args.len() {
+                2 => Some(integer_arg(&arg(1)?, name)?),
+                _ => None,
+            };
+            string_fn(&|s| {
+                let rest = s.chars().skip(start);
+                Ok(Value::String(match length {
+                    Some(length) => rest.take(length).collect(),
+                    None => rest.collect(),
+                }))
+            })
+        }
+        "toString" => Ok(singleton(&input, name)?
+            .and_then(primitive_to_string)
+            .map(Value::String)
+            .into_iter()
+            .collect()),
+        "ofType" => {
+            let type_name = type_specifier(args, name)?;
+            Ok(input
+                .into_iter()
+                .filter(|v| is_of_type(v, type_name))
+                .collect())
+        }
+        "extension" => {
+            let url = string_arg(&arg(0)?, name)?;
+            let mut extensions = Vec::new();
+            input
+                .iter()
+                .for_each(|v| member(v, "extension", &mut extensions));
+            Ok(extensions
+                .into_iter()
+                .filter(|ext| ext.get("url").and_then(|u| u.as_str()) == url.as_deref())
+                .collect())
+        }
+        "getResourceKey" => Ok(singleton(&input, name)?
+            .and_then(|v| v.get("id"))
+            .cloned()
+            .into_iter()
+            .collect()),
+        "getReferenceKey" => {
+            // the type is a type specifier like `Patient`, not an expression to evaluate
+            let resource_type = if args.is_empty() {
+                None
+            } else {
+                Some(type_specifier(args, name)?)
+            };
+            Ok(input
+                .iter()
+                .filter_map(|v| v.get("reference").and_then(|r| r.as_str()))
+                .filter_map(|reference| {
+                    // `Patient/1`, also below a base URL or with a `_history` suffix
+                    let key = relative_key(reference)?;
+                    let (t, id) = key.split_once('/')?;
+                    match resource_type {
+                        Some(expected) if expected != t => None,
+                        _ => Some(Value::String(id.to_string())),
+                    }
+                })
+                .collect())
+        }
+        _ => Err(format!("unknown function `{}()`", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fhirpath() {
+        let concept = json!({
+            "coding": [
+                { "system": "http://snomed.info/sct", "code": "271649006", "display": "Systolic" },
+                { "system": "http://loinc.org", "code": "8480-6", "display": "Systolic BP" }
+            ],
+            "text": "Systolic blood pressure"
+        });
+        let eval = |v: &Value, expression: &str| xfhirpath(v, expression);
+
+        assert_eq!(
+            eval(&concept, "coding.where(system = 'http://loinc.org').code"),
+            "8480-6"
+        );
+        assert_eq!(eval(&concept, "coding.code"), "271649006 8480-6");
+        assert_eq!(eval(&concept, "coding[1].display.upper()"), "SYSTOLIC BP");
+        assert_eq!(
+            eval(&concept, "coding.display.join(', ')"),
+            "Systolic, Systolic BP"
+        );
+        assert_eq!(
+            eval(&concept, "coding.count() > 1 and text.exists()"),
+            "true"
+        );
+        assert_eq!(
+            eval(&concept, "iif(coding.exists(code = 'x'), 'x', text & '!')"),
+            "Systolic blood pressure!"
+        );
+        assert_eq!(eval(&concept, "missing.first()"), "");
+
+        let name = json!({ "given": ["Peter", "James"], "family": "Chalmers" });
+        assert_eq!(
+            eval(&name, "given.first() + ' ' + family"),
+            "Peter Chalmers"
+        );
+        assert_eq!(eval(&name, "given.tail()"), "James");
+
+        let observation = json!({ "valueQuantity": { "value": 120, "unit": "mmHg" } });
+        assert_eq!(eval(&observation, "value.value + 5"), "125");
+        let request = json!({ "statusReason": { "text": "on hold" }, "codeFilter": "x" });
+        assert_eq!(eval(&request, "status"), "");
+        assert_eq!(eval(&request, "code"), "");
+
+        let patient = json!({
+            "id": "p1",
+            "generalPractitioner": [{ "reference": "https://fhir.com/Practitioner/7/_history/2" }],
+            "extension": [{ "url": "http://x.org/birthsex", "valueCode": "F" }]
+        });
+        assert_eq!(eval(&patient, "getResourceKey()"), "p1");
+        assert_eq!(eval(&patient, "generalPractitioner.getReferenceKey()"), "7");
+        assert_eq!(
+            eval(&patient, "generalPractitioner.getReferenceKey(Patient)"),
+            ""
+        );
+        let encounter = json!({
+            "subject": { "reference": "Patient/ABC/_history/2" },
+            "participant": [{ "individual": { "reference": "https://fhir.com/Practitioner/X1" } }]
+        });
+        assert_eq!(eval(&encounter, "subject.getReferenceKey()"), "ABC");
+        assert_eq!(eval(&encounter, "subject.getReferenceKey(Patient)"), "ABC");
+        assert_eq!(eval(&encounter, "subject.getReferenceKey(Group)"), "");
+        assert_eq!(
+            eval(
+                &encounter,
+                "participant.individual.getReferenceKey(Practitioner)"
+            ),
+            "X1"
+        );
+        assert_eq!(
+            eval(&patient, "extension('http://x.org/birthsex').value"),
+            "F"
+        );
+
+        let observation = json!({
+            "valueQuantity": { "value": 120, "unit": "mmHg" },
+            "component": [
+                { "valueString": "high" },
+                { "valueQuantity": { "value": 80, "unit": "mmHg" } }
+            ],
+            "contained": [
+                { "resourceType": "Patient", "id": "p" },
+                { "resourceType": "Practitioner", "id": "d" }
+            ],
+            "note": ["a", 1, true, 2.5]
+        });
+        assert_eq!(eval(&observation, "value.ofType(Quantity).unit"), "mmHg");
+        assert_eq!(
+            eval(&observation, "value.ofType(FHIR.Quantity).value"),
+            "120"
+        );
+        assert_eq!(eval(&observation, "value.ofType(string)"), "");
+        assert_eq!(eval(&observation, "component.value.ofType(string)"), "high");
+        assert_eq!(
+            eval(&observation, "component.value.ofType(Quantity).value"),
+            "80"
+        );
+        assert_eq!(eval(&observation, "contained.ofType(Practitioner).id"), "d");
+        assert_eq!(eval(&observation, "note.ofType(integer)"), "1");
+        assert_eq!(eval(&observation, "note.ofType(System.Boolean)"), "true");
+        assert_eq!(eval(&observation, "note.ofType(decimal).count()"), "2");
+        assert_eq!(
+            eval(&observation, "contained.select(ofType(Patient)).id"),
+            "p"
+        );
+        assert!(FhirPath::parse("value.ofType()").is_err());
+
+        let condition = json!({ "id": "c", "onsetDateTime": "2024-01-31T10:00:00Z", "recordedDate": "2024-02-01" });
+        assert_eq!(eval(&condition, "recordedDate = @2024-02-01"), "true");
+        assert_eq!(eval(&condition, "recordedDate > @2024-01-31"), "true");
+        assert_eq!(eval(&condition, "onset = @2024-01-31T10:00:00Z"), "true");
+        assert_eq!(
+            eval(&condition, "@T10:00 & ' ' & %ucum"),
+            "10:00 http://unitsofmeasure.org"
+        );
+        assert!(FhirPath::parse("@").is_err());
+        assert!(FhirPath::parse("%unknown").is_err());
+        let path = FhirPath::parse("%resource.id & %rootResource.id & %context.text").unwrap();
+        let note = json!({ "text": "x" });
+        assert_eq!(
+            path.evaluate_in(&note, &condition).unwrap(),
+            vec![json!("ccx")]
+        );
+
+        let path = FhirPath::parse("coding.where(system = 'x').code").unwrap();
+        assert_eq!(path.first_key(), Some("coding"));
+        assert!(FhirPath::parse("coding.where(").is_err());
+        assert!(FhirPath::parse("coding.frist()").is_err());
+    }
+}
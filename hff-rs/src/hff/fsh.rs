@@ -0,0 +1,350 @@
+use serde_json::Value;
+
+// aliases of code systems without a name in their URL
+const WELL_KNOWN_SYSTEMS: &[(&str, &str)] = &[
+    ("http://loinc.org", "$loinc"),
+    ("http://snomed.info/sct", "$sct"),
+    ("http://www.nlm.nih.gov/research/umls/rxnorm", "$rxnorm"),
+    ("http://unitsofmeasure.org", "$ucum"),
+];
+
+const HL7_CODE_SYSTEMS: &str = "http://terminology.hl7.org/CodeSystem/";
+
+// there is no type information, so strings of these keys are taken as codes
+const CODE_KEYS: &[&str] = &[
+    "status",
+    "gender",
+    "use",
+    "intent",
+    "priority",
+    "mode",
+    "language",
+    "type",
+    "category",
+    "comparator",
+    "severity",
+    "criticality",
+    "contentType",
+    "kind",
+];
+
+/**
+ * Render a FHIR resource (in JSON form, not reformatted) as a FHIR Shorthand `Instance:` with
+ * assignment rules like `* name.given = "Jane"`. Like the rules of the default mapping, objects
+ * are recognized by their keys and written in shorthand: Codings as `$loinc#8867-4 "Heart rate"`
+ * (with an `Alias:` for well-known and HL7 code systems), CodeableConcepts with a single Coding
+ * the same way, UCUM Quantities as `72 '/min' "/min"` and References as `Reference(Patient/1)`.
+ * Nested resources (contained resources, Bundle entries) become instances with `Usage: #inline`.
+ */
+pub fn to_fsh(resource: &Value) -> Result<String, String> {
+    let mut writer = Writer::default();
+    writer.instance(resource, "#example")?;
+    let mut out = String::new();
+    for (alias, system) in &writer.aliases {
+        out.push_str(&format!("Alias: {} = {}\n", alias, system));
+    }
+    if !writer.aliases.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(&writer.instances.join("\n"));
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Writer {
+    aliases: Vec<(String, String)>,
+    instances: Vec<String>,
+    names: Vec<String>,
+}
+
+impl Writer {
+    /**
+     * Add the instance of a resource, after the ones of its parents, and return its name.
+     */
+    fn instance(&mut self, resource: &Value, usage: &str) -> Result<String, String> {
+        let map = resource.as_object().ok_or("not a FHIR resource")?;
+        let resource_type = map
+            .get("resourceType")
+            .and_then(|t| t.as_str())
+            .ok_or("not a FHIR resource, there is no `resourceType`")?;
+        // the name of an instance is its id, unless it is taken or no valid name
+        let id = map.get("id").and_then(|id| id.as_str()).filter(|id| {
+            !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        });
+        let base = id.unwrap_or(resource_type);
+        let mut name = base.to_string();
+        let mut n = 2;
+        while self.names.contains(&name) {
+            name = format!("{}-{}", base, n);
+            n += 1;
+        }
+        self.names.push(name.clone());
+        let index = self.instances.len();
+        self.instances.push(String::new());
+
+        let mut rules = Vec::new();
+        for (key, v) in map {
+            if key == "resourceType" || (key == "id" && id == Some(name.as_str())) {
+                continue;
+            }
+            let key = key.strip_prefix('_').unwrap_or(key);
+            self.rules(key, key, v, &mut rules)?;
+        }
+        let mut out = format!(
+            "Instance: {}\nInstanceOf: {}\nUsage: {}\n",
+            name, resource_type, usage
+        );
+        for rule in rules {
+            out.push_str(&format!("* {}\n", rule));
+        }
+        self.instances[index] = out;
+        Ok(name)
+    }
+
+    fn rules(
+        &mut self,
+        path: &str,
+        key: &str,
+        v: &Value,
+        out: &mut Vec<String>,
+    ) -> Result<(), String> {
+        match v {
+            Value::Null => {}
+            Value::Array(arr) => {
+                for (i, item) in arr.iter().enumerate() {
+                    // single elements don't need an index
+                    let item_path = match arr.len() {
+                        1 => path.to_string(),
+                        _ => format!("{}[{}]", path, i),
+                    };
+                    self.rules(&item_path, key, item, out)?;
+                }
+            }
+            Value::Object(map) if map.contains_key("resourceType") => {
+                let name = self.instance(v, "#inline")?;
+                out.push(format!("{} = {}", path, name));
+            }
+            Value::Object(map) => {
+                let used = match self.shorthand(v) {
+                    Some((value, used)) => {
+                        out.push(format!("{} = {}", path, value));
+                        used
+                    }
+                    None => Vec::new(),
+                };
+                for (k, v2) in map.iter().filter(|(k, _)| !used.contains(&k.as_str())) {
+                    // extensions of primitives, e.g. `_birthDate`
+                    let k = k.strip_prefix('_').unwrap_or(k);
+                    self.rules(&format!("{}.{}", path, k), k, v2, out)?;
+                }
+            }
+            Value::String(s) if is_code(key, s) => out.push(format!("{} = {}", path, code(s))),
+            Value::String(s) => out.push(format!("{} = {}", path, string(s))),
+            _ => out.push(format!("{} = {}", path, v)),
+        }
+        Ok(())
+    }
+
+    /**
+     * The shorthand of a Coding, CodeableConcept, Quantity or Reference and the keys it covers.
+     */
+    fn shorthand(&mut self, v: &Value) -> Option<(String, Vec<&'static str>)> {
+        let map = v.as_object()?;
+        let str_of = |key: &str| map.get(key).and_then(|s| s.as_str());
+        let has_only = |keys: &[&str]| map.keys().all(|k| keys.contains(&k.as_str()));
+        let display = |text: Option<&str>| {
+            text.map(|text| format!(" {}", string(text)))
+                .unwrap_or_default()
+        };
+
+        // Coding
+        if let Some(c) = str_of("code")
+            && !map.contains_key("value")
+            && has_only(&[
+                "system",
+                "code",
+                "display",
+                "version",
+                "userSelected",
+                "id",
+                "extension",
+            ])
+        {
+            let system = str_of("system").map(|s| self.alias(s)).unwrap_or_default();
+            return Some((
+                format!("{}{}{}", system, code(c), display(str_of("display"))),
+                vec!["system", "code", "display"],
+            ));
+        }
+        // CodeableConcept with a single Coding, e.g. `code = $loinc#8867-4`
+        if let Some([coding]) = map
+            .get("coding")
+            .and_then(|c| c.as_array())
+            .map(|c| c.as_slice())
+            && coding.as_object().is_some_and(|c| {
+                c.keys()
+                    .all(|k| ["system", "code", "display"].contains(&k.as_str()))
+            })
+            && let Some((value, _)) = self.shorthand(coding)
+        {
+            return Some((value, vec!["coding"]));
+        }
+        // Quantity with a UCUM unit
+        if map.get("value").is_some_and(|value| value.is_number())
+            && str_of("system") == Some("http://unitsofmeasure.org")
+            && let Some(c) = str_of("code")
+            && has_only(&["value", "unit", "system", "code"])
+        {
+            return Some((
+                format!(
+                    "{} '{}'{}",
+                    map["value"],
+                    c.replace('\'', "\\'"),
+                    display(str_of("unit"))
+                ),
+                vec!["value", "unit", "system", "code"],
+            ));
+        }
+        // Reference
+        if let Some(reference) = str_of("reference")
+            && !reference.contains([' ', ')'])
+        {
+            return Some((
+                format!("Reference({}){}", reference, display(str_of("display"))),
+                vec!["reference", "display"],
+            ));
+        }
+        None
+    }
+
+    /**
+     * The alias of a code system, added to the aliases on first use, or the system itself.
+     */
+    fn alias(&mut self, system: &str) -> String {
+        let alias = match WELL_KNOWN_SYSTEMS.iter().find(|(s, _)| *s == system) {
+            Some((_, alias)) => alias.to_string(),
+            None => match system.strip_prefix(HL7_CODE_SYSTEMS) {
+                Some(name) if !name.is_empty() && !name.contains(['/', ' ', '#']) => {
+                    format!("${}", name)
+                }
+                _ => return system.to_string(),
+            },
+        };
+        if !self.aliases.iter().any(|(a, _)| *a == alias) {
+            self.aliases.push((alias.clone(), system.to_string()));
+        }
+        alias
+    }
+}
+
+/**
+ * Strings of code elements, and systems of ContactPoints (which are no URLs). Types with a capital
+ * letter are resource types of References, which are URIs.
+ */
+fn is_code(key: &str, s: &str) -> bool {
+    match key {
+        "system" => !s.contains(':'),
+        _ => {
+            CODE_KEYS.contains(&key)
+                && !s.is_empty()
+                && !s.contains(char::is_whitespace)
+                && !s.starts_with(|c: char| c.is_ascii_uppercase())
+        }
+    }
+}
+
+fn code(c: &str) -> String {
+    match c.contains(char::is_whitespace) || c.contains('"') {
+        true => format!("#{}", string(c)),
+        false => format!("#{}", c),
+    }
+}
+
+/**
+ * A string literal, line breaks (like in a narrative) are escaped, so every rule stays on one line.
+ */
+fn string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_fsh() {
+        let observation = json!({
+            "resourceType": "Observation",
+            "id": "bp",
+            "status": "final",
+            "category": [{ "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                "code": "vital-signs"
+            }] }],
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "85354-9", "display": "Blood pressure" }],
+                "text": "BP"
+            },
+            "subject": { "reference": "Patient/1", "display": "Jane \"J\" Doe" },
+            "effectiveDateTime": "2024-01-01",
+            "_effectiveDateTime": { "extension": [{ "url": "http://x.org/ext", "valueBoolean": true }] },
+            "component": [
+                {
+                    "code": { "coding": [{ "system": "http://loinc.org", "code": "8480-6" }] },
+                    "valueQuantity": { "value": 120, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" }
+                },
+                {
+                    "code": { "coding": [
+                        { "system": "http://loinc.org", "code": "8462-4" },
+                        { "system": "urn:oid:1.2.3", "code": "dia bp" }
+                    ] },
+                    "valueQuantity": { "value": 80, "unit": "mmHg" }
+                }
+            ],
+            "contained": [{ "resourceType": "Patient", "id": "bp", "gender": "female" }],
+            "text": { "status": "generated", "div": "<div>\n  BP\n</div>" }
+        });
+        assert_eq!(
+            to_fsh(&observation).unwrap(),
+            "Alias: $observation-category = http://terminology.hl7.org/CodeSystem/observation-category\n\
+             Alias: $loinc = http://loinc.org\n\
+             \n\
+             Instance: bp\n\
+             InstanceOf: Observation\n\
+             Usage: #example\n\
+             * status = #final\n\
+             * category = $observation-category#vital-signs\n\
+             * code = $loinc#85354-9 \"Blood pressure\"\n\
+             * code.text = \"BP\"\n\
+             * subject = Reference(Patient/1) \"Jane \\\"J\\\" Doe\"\n\
+             * effectiveDateTime = \"2024-01-01\"\n\
+             * effectiveDateTime.extension.url = \"http://x.org/ext\"\n\
+             * effectiveDateTime.extension.valueBoolean = true\n\
+             * component[0].code = $loinc#8480-6\n\
+             * component[0].valueQuantity = 120 'mm[Hg]' \"mmHg\"\n\
+             * component[1].code.coding[0] = $loinc#8462-4\n\
+             * component[1].code.coding[1] = urn:oid:1.2.3#\"dia bp\"\n\
+             * component[1].valueQuantity.value = 80\n\
+             * component[1].valueQuantity.unit = \"mmHg\"\n\
+             * contained = bp-2\n\
+             * text.status = #generated\n\
+             * text.div = \"<div>\\n  BP\\n</div>\"\n\
+             \n\
+             Instance: bp-2\n\
+             InstanceOf: Patient\n\
+             Usage: #inline\n\
+             * id = \"bp\"\n\
+             * gender = #female\n"
+        );
+        assert!(to_fsh(&json!({ "id": "x" })).is_err());
+    }
+}
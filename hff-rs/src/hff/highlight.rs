@@ -0,0 +1,111 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+const RESET: &str = "\x1b[0m";
+const KEY: &str = "\x1b[1;34m";
+const MARKER: &str = "\x1b[2m";
+const LITERAL: &str = "\x1b[33m";
+const REFERENCE: &str = "\x1b[35m";
+const SYSTEM: &str = "\x1b[36m";
+const SEPARATOR: &str = "\x1b[2m";
+
+/**
+ * Highlight the YAML output of hff with ANSI escape codes for terminals: keys, list markers,
+ * numbers and booleans, and the tokens of HUFF strings, i.e. `Reference(...)`, ` | ` separators
+ * and code systems (`http://...`, `urn:...`). Block scalars (like a rendered narrative) are left as they are.
+ */
+pub fn highlight_yaml(yaml: &str) -> String {
+    static LINE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(concat!(
+            r#"^(?P<indent>\s*)(?P<marker>(?:- )*)"#,
+            r#"(?:(?P<key>[^\s'"][^:]*?|'[^']*'|"[^"]*"):(?: |$))?(?P<value>.*)$"#
+        ))
+        .unwrap()
+    });
+    let mut out = String::new();
+    // indentation of the key of the block scalar we are in, if any
+    let mut block: Option<usize> = None;
+    for line in yaml.lines() {
+        let indent = line.len() - line.trim_start().len();
+        if let Some(block_indent) = block {
+            if line.trim().is_empty() || indent > block_indent {
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            }
+            block = None;
+        }
+        let Some(caps) = LINE.captures(line) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        out.push_str(&caps["indent"]);
+        let marker = &caps["marker"];
+        if !marker.is_empty() {
+            out.push_str(&format!("{}{}{}", MARKER, marker, RESET));
+        }
+        if let Some(key) = caps.name("key") {
+            out.push_str(&format!("{}{}{}:", KEY, key.as_str(), RESET));
+            if !caps["value"].is_empty() || line.ends_with(": ") {
+                out.push(' ');
+            }
+        }
+        let value = &caps["value"];
+        if value.starts_with('|') || value.starts_with('>') {
+            // the lines of a block scalar are indented deeper than its key (or list marker)
+            block = Some(indent + marker.len());
+            out.push_str(value);
+        } else {
+            out.push_str(&highlight_value(value));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn highlight_value(value: &str) -> String {
+    if matches!(value, "true" | "false" | "null" | "~") || value.parse::<f64>().is_ok() {
+        return format!("{}{}{}", LITERAL, value, RESET);
+    }
+    static TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"Reference\([^)]*\)|(?:https?|urn):[^\s|'"]+| \| "#).unwrap()
+    });
+    TOKEN
+        .replace_all(value, |caps: &regex::Captures| {
+            let token = &caps[0];
+            let style = match token {
+                " | " => SEPARATOR,
+                _ if token.starts_with("Reference(") => REFERENCE,
+                _ => SYSTEM,
+            };
+            format!("{}{}{}", style, token, RESET)
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_yaml() {
+        let yaml = "resourceType: Observation\n\
+                    subject: Reference(Patient/1)\n\
+                    code:\n  coding:\n  - http://loinc.org | 8867-4\n  - urn:local | 12\n\
+                    valueQuantity: 72\n\
+                    text:\n  div: |-\n    a | b\n\n    key: c\n\
+                    issued: '2024-01-01'\n";
+        assert_eq!(
+            highlight_yaml(yaml),
+            "\x1b[1;34mresourceType\x1b[0m: Observation\n\
+             \x1b[1;34msubject\x1b[0m: \x1b[35mReference(Patient/1)\x1b[0m\n\
+             \x1b[1;34mcode\x1b[0m:\n  \x1b[1;34mcoding\x1b[0m:\n  \
+             \x1b[2m- \x1b[0m\x1b[36mhttp://loinc.org\x1b[0m\x1b[2m | \x1b[0m8867-4\n  \
+             \x1b[2m- \x1b[0m\x1b[36murn:local\x1b[0m\x1b[2m | \x1b[0m12\n\
+             \x1b[1;34mvalueQuantity\x1b[0m: \x1b[33m72\x1b[0m\n\
+             \x1b[1;34mtext\x1b[0m:\n  \x1b[1;34mdiv\x1b[0m: |-\n    a | b\n\n    key: c\n\
+             \x1b[1;34missued\x1b[0m: '2024-01-01'\n"
+        );
+    }
+}
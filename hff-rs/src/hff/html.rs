@@ -0,0 +1,303 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+/**
+ * Options of the HTML renderer.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct HtmlOptions {
+    // base URL of the FHIR server that relative references are linked to, references are not
+    // linked without one
+    pub base_url: Option<String>,
+    // turn http(s) URLs into links
+    pub links: bool,
+}
+
+/**
+ * Default styles for the classes of `to_html`, also used by `to_html_document`.
+ */
+pub const STYLESHEET: &str = "\
+.hff { font-family: monospace; line-height: 1.4; padding: 0 1em; }
+.hff details, .hff .hff-entry { margin-left: 1.5em; }
+.hff > details, .hff > .hff-entry { margin-left: 0; }
+.hff summary { cursor: pointer; margin-left: -1em; }
+.hff ol { margin: 0; padding-left: 2em; }
+.hff-key { color: #a1a143; font-weight: bold; }
+.hff-literal { color: #a96ba9; }
+.hff-system { color: #438ba1; }
+.hff-code { color: #dd8888; }
+.hff-separator { color: #888888; }
+.hff-reference { color: #43a16b; }
+.hff-count { color: #888888; font-weight: normal; }
+.hff-text { margin: 0 0 0 1.5em; white-space: pre-wrap; }
+";
+
+/**
+ * Render a reformatted tree (see `reformat`) as semantic HTML: objects and lists become collapsible
+ * `<details>` sections, keys and values are spans with `hff-*` classes, code systems and codes are
+ * `<code>` spans, `Reference(...)` values link to the referenced resource (given a base URL) and
+ * multi-line text, like a rendered narrative, is preformatted.
+ */
+pub fn to_html(tree: &serde_json::Value, options: &HtmlOptions) -> String {
+    let mut out = String::from("<div class=\"hff\">\n");
+    match tree {
+        serde_json::Value::Object(map) => {
+            for (key, v) in map {
+                entry(&mut out, Some(key), v, options);
+            }
+        }
+        _ => entry(&mut out, None, tree, options),
+    }
+    out.push_str("</div>\n");
+    out
+}
+
+/**
+ * A standalone HTML page with the output of `to_html` and the default styles, e.g. for static reports.
+ */
+pub fn to_html_document(tree: &serde_json::Value, options: &HtmlOptions) -> String {
+    let title = match (
+        tree.get("resourceType").and_then(|t| t.as_str()),
+        tree.get("id").and_then(|id| id.as_str()),
+    ) {
+        (Some(resource_type), Some(id)) => format!("{}/{}", resource_type, id),
+        (Some(resource_type), None) => resource_type.to_string(),
+        _ => "HUFF".to_string(),
+    };
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>\n{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(&title),
+        STYLESHEET,
+        to_html(tree, options)
+    )
+}
+
+fn entry(out: &mut String, key: Option<&str>, v: &serde_json::Value, options: &HtmlOptions) {
+    let key_html = key.map(|key| format!("<span class=\"hff-key\">{}</span>", escape(key)));
+    match v {
+        serde_json::Value::Object(map) => {
+            out.push_str("<details open>");
+            out.push_str(&format!(
+                "<summary>{}</summary>\n",
+                key_html.unwrap_or_default()
+            ));
+            for (key, v2) in map {
+                entry(out, Some(key), v2, options);
+            }
+            out.push_str("</details>\n");
+        }
+        serde_json::Value::Array(arr) => {
+            out.push_str("<details open>");
+            out.push_str(&format!(
+                "<summary>{} <span class=\"hff-count\">({})</span></summary>\n<ol>\n",
+                key_html.unwrap_or_default(),
+                arr.len()
+            ));
+            for v2 in arr {
+                out.push_str("<li>");
+                match v2 {
+                    serde_json::Value::Object(map) => {
+                        out.push('\n');
+                        for (key, v3) in map {
+                            entry(out, Some(key), v3, options);
+                        }
+                    }
+                    serde_json::Value::Array(_) => {
+                        out.push('\n');
+                        entry(out, None, v2, options);
+                    }
+                    _ => out.push_str(&value(v2, options)),
+                }
+                out.push_str("</li>\n");
+            }
+            out.push_str("</ol>\n</details>\n");
+        }
+        serde_json::Value::String(s) if s.contains('\n') => {
+            out.push_str(&format!(
+                "<div class=\"hff-entry\">{}:</div>\n<pre class=\"hff-text\">{}</pre>\n",
+                key_html.unwrap_or_default(),
+                escape(s)
+            ));
+        }
+        _ => match key_html {
+            Some(key_html) => out.push_str(&format!(
+                "<div class=\"hff-entry\">{}: {}</div>\n",
+                key_html,
+                value(v, options)
+            )),
+            None => out.push_str(&format!(
+                "<div class=\"hff-entry\">{}</div>\n",
+                value(v, options)
+            )),
+        },
+    }
+}
+
+/**
+ * A scalar value. Strings are split at ` | ` separators, a segment that is a URL or URN is a code
+ * system and the segment after it its code.
+ */
+fn value(v: &serde_json::Value, options: &HtmlOptions) -> String {
+    let s = match v {
+        serde_json::Value::String(s) => s,
+        serde_json::Value::Null => return "<span class=\"hff-literal\">null</span>".to_string(),
+        _ => return format!("<span class=\"hff-literal\">{}</span>", v),
+    };
+    let mut segments = Vec::new();
+    let mut after_system = false;
+    for segment in s.split(" | ") {
+        let is_system = is_uri(segment);
+        segments.push(match (is_system, after_system) {
+            (true, _) => system(segment, options),
+            (false, true) => format!("<code class=\"hff-code\">{}</code>", escape(segment)),
+            (false, false) => text(segment, options),
+        });
+        after_system = is_system;
+    }
+    segments.join("<span class=\"hff-separator\"> | </span>")
+}
+
+fn is_uri(s: &str) -> bool {
+    !s.contains(char::is_whitespace)
+        && (s.starts_with("http://") || s.starts_with("https://") || s.starts_with("urn:"))
+}
+
+fn system(uri: &str, options: &HtmlOptions) -> String {
+    match options.links && !uri.starts_with("urn:") {
+        true => format!(
+            "<a class=\"hff-system\" href=\"{}\"><code>{}</code></a>",
+            escape(uri),
+            escape(uri)
+        ),
+        false => format!("<code class=\"hff-system\">{}</code>", escape(uri)),
+    }
+}
+
+/**
+ * Free text with `Reference(...)` and URLs in it.
+ */
+fn text(s: &str, options: &HtmlOptions) -> String {
+    static TOKEN: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"Reference\(([^)]*)\)|https?://[^\s<>]+").unwrap());
+    let mut out = String::new();
+    let mut offset = 0;
+    for caps in TOKEN.captures_iter(s) {
+        let whole = caps.get(0).unwrap();
+        out.push_str(&escape(&s[offset..whole.start()]));
+        offset = whole.end();
+        match caps.get(1) {
+            Some(target) => match reference_url(target.as_str(), options.base_url.as_deref()) {
+                Some(url) => out.push_str(&format!(
+                    "<a class=\"hff-reference\" href=\"{}\">{}</a>",
+                    escape(&url),
+                    escape(whole.as_str())
+                )),
+                None => out.push_str(&format!(
+                    "<span class=\"hff-reference\">{}</span>",
+                    escape(whole.as_str())
+                )),
+            },
+            None if options.links => out.push_str(&format!(
+                "<a href=\"{}\">{}</a>",
+                escape(whole.as_str()),
+                escape(whole.as_str())
+            )),
+            None => out.push_str(&escape(whole.as_str())),
+        }
+    }
+    out.push_str(&escape(&s[offset..]));
+    out
+}
+
+/**
+ * Where a reference points to: absolute URLs and local (`#...`) references as they are, relative
+ * ones (`Patient/123`) below the base URL. Others, like `urn:uuid:...`, can't be linked.
+ */
+pub fn reference_url(target: &str, base_url: Option<&str>) -> Option<String> {
+    if target.starts_with("http://") || target.starts_with("https://") || target.starts_with('#') {
+        return Some(target.to_string());
+    }
+    if target.contains(':') {
+        return None;
+    }
+    base_url.map(|base| format!("{}/{}", base.trim_end_matches('/'), target))
+}
+
+/**
+ * Guess the base URL of a FHIR server from the URL of a page with one of its responses: everything
+ * before the first path segment that is a resource type, e.g. `https://fhir.com/r4` for
+ * `https://fhir.com/r4/Patient/123?_format=json`.
+ */
+pub fn guess_base_url(page_url: &str) -> Option<String> {
+    let url = page_url.split(['?', '#']).next().unwrap_or(page_url);
+    let path_start = url.find("://").map_or(0, |scheme_end| {
+        let host_start = scheme_end + 3;
+        url[host_start..]
+            .find('/')
+            .map_or(url.len(), |i| host_start + i)
+    });
+    static RESOURCE_TYPE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"/[A-Z][a-z][A-Za-z]*(?:/|$)").unwrap());
+    let found = RESOURCE_TYPE.find(&url[path_start..])?;
+    Some(url[..path_start + found.start()].to_string())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_html() {
+        let tree = json!({
+            "resourceType": "Observation",
+            "subject": "Reference(Patient/1)",
+            "code": { "coding": ["http://loinc.org | 8867-4 | Heart rate <HR>"] },
+            "valueQuantity": 72,
+            "text": { "div": "line 1\nline 2" }
+        });
+        let options = HtmlOptions {
+            base_url: Some("https://fhir.com/r4/".to_string()),
+            links: false,
+        };
+        assert_eq!(
+            to_html(&tree, &options),
+            "<div class=\"hff\">\n\
+             <div class=\"hff-entry\"><span class=\"hff-key\">resourceType</span>: Observation</div>\n\
+             <div class=\"hff-entry\"><span class=\"hff-key\">subject</span>: \
+             <a class=\"hff-reference\" href=\"https://fhir.com/r4/Patient/1\">Reference(Patient/1)</a></div>\n\
+             <details open><summary><span class=\"hff-key\">code</span></summary>\n\
+             <details open><summary><span class=\"hff-key\">coding</span> <span class=\"hff-count\">(1)</span></summary>\n<ol>\n\
+             <li><code class=\"hff-system\">http://loinc.org</code><span class=\"hff-separator\"> | </span>\
+             <code class=\"hff-code\">8867-4</code><span class=\"hff-separator\"> | </span>Heart rate &lt;HR&gt;</li>\n\
+             </ol>\n</details>\n</details>\n\
+             <div class=\"hff-entry\"><span class=\"hff-key\">valueQuantity</span>: <span class=\"hff-literal\">72</span></div>\n\
+             <details open><summary><span class=\"hff-key\">text</span></summary>\n\
+             <div class=\"hff-entry\"><span class=\"hff-key\">div</span>:</div>\n\
+             <pre class=\"hff-text\">line 1\nline 2</pre>\n</details>\n\
+             </div>\n"
+        );
+        assert!(
+            to_html(&json!("Reference(urn:uuid:1)"), &options)
+                .contains("<span class=\"hff-reference\">Reference(urn:uuid:1)</span>")
+        );
+
+        assert_eq!(
+            guess_base_url("https://fhir.com/r4/Patient/123/_history/2?_format=json"),
+            Some("https://fhir.com/r4".to_string())
+        );
+        assert_eq!(
+            guess_base_url("https://fhir.com/fhir/R4/Encounter?_id=1"),
+            Some("https://fhir.com/fhir/R4".to_string())
+        );
+        assert_eq!(guess_base_url("https://fhir.com/metadata"), None);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::hff::fhirpath;
+
+/**
+ * Key rules of a mapping: `@rename <pattern> -> <key>` renames keys in the output,
+ * `@hide <pattern>` drops keys (and their subtrees) before any rule is applied and
+ * `@lookup <pattern> -> <table>` replaces scalar values with their label from a `@table`.
+ *
+ * A pattern is a dotted key path like `meta.versionId`, where `*` matches any single key and `**`
+ * any number of keys. Paths start at the nearest enclosing resource and array elements share the
+ * key of their array. Patterns match at any depth, unless they start with a resource type
+ * (a capitalized first segment like in `Observation.effectiveDateTime`), which anchors them at
+ * resources of that type. So `*.id` matches `id` on datatypes but not the `id` of the resource.
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyAction {
+    Hide,
+    Rename(String),
+    Lookup(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRule {
+    pub resource_type: Option<String>,
+    pub pattern: Vec<String>,
+    pub action: KeyAction,
+    pub line: usize,
+    // name of the included mapping the rule comes from, `None` for the mapping itself
+    pub source: Option<String>,
+}
+
+impl KeyRule {
+    /**
+     * Parse the arguments of `@hide <pattern>`, `@rename <pattern> -> <key>` or
+     * `@lookup <pattern> -> <table>`, `directive` being one of those.
+     */
+    pub fn parse(line: usize, args: &str, directive: &str) -> Result<KeyRule, String> {
+        let (pattern, action) = match directive {
+            "@hide" => (args.trim(), KeyAction::Hide),
+            _ => match args.split_once("->") {
+                Some((pattern, key)) if is_key(key.trim()) => {
+                    let key = key.trim().to_string();
+                    let action = match directive {
+                        "@lookup" => KeyAction::Lookup(key),
+                        _ => KeyAction::Rename(key),
+                    };
+                    (pattern.trim(), action)
+                }
+                Some(_) => return Err("expected a single name after `->`".to_string()),
+                None => {
+                    return Err(format!("expected `{} <pattern> -> <name>`", directive));
+                }
+            },
+        };
+        let mut segments = pattern
+            .split('.')
+            .map(|s| s.trim().to_string())
+            .collect::<Vec<String>>();
+        if segments.iter().any(|s| s.is_empty() || !is_segment(s)) {
+            return Err(format!("invalid key pattern `{}`", pattern));
+        }
+        let resource_type = match segments[0].starts_with(|c: char| c.is_ascii_uppercase()) {
+            true => Some(segments.remove(0)),
+            false => None,
+        };
+        if segments.is_empty() {
+            return Err(format!("key pattern `{}` names no key", pattern));
+        }
+        Ok(KeyRule {
+            resource_type,
+            pattern: segments,
+            action,
+            line,
+            source: None,
+        })
+    }
+
+    /**
+     * The pattern as written in the mapping, e.g. `Observation.effectiveDateTime`.
+     */
+    pub fn pattern_str(&self) -> String {
+        self.resource_type
+            .iter()
+            .chain(self.pattern.iter())
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(".")
+    }
+
+    /**
+     * Can both rules apply to keys of the same object? That is if they have the same scope and the
+     * same pattern up to different last keys, which are not variants of one choice element like
+     * `effectiveDateTime` and `effectivePeriod` (an object has only one of those).
+     */
+    pub fn shares_object_with(&self, other: &KeyRule) -> bool {
+        let (Some((key, parent)), Some((other_key, other_parent))) =
+            (self.pattern.split_last(), other.pattern.split_last())
+        else {
+            return false;
+        };
+        let same_choice = (1..key.len()).any(|end| {
+            fhirpath::is_choice_of(key, &key[..end])
+                && fhirpath::is_choice_of(other_key, &key[..end])
+        });
+        self.resource_type == other.resource_type
+            && parent == other_parent
+            && key != other_key
+            && is_key(key)
+            && is_key(other_key)
+            && !same_choice
+    }
+
+    /**
+     * Does the rule apply to the key at the end of `path` (keys below a resource of `resource_type`)?
+     */
+    pub fn matches(&self, resource_type: Option<&str>, path: &[&str]) -> bool {
+        match &self.resource_type {
+            Some(t) => resource_type == Some(t.as_str()) && glob(&self.pattern, path),
+            None => (0..path.len()).any(|start| glob(&self.pattern, &path[start..])),
+        }
+    }
+}
+
+fn is_key(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+fn is_segment(s: &str) -> bool {
+    s == "*" || s == "**" || is_key(s)
+}
+
+fn glob(pattern: &[String], path: &[&str]) -> bool {
+    match (pattern.first().map(|s| s.as_str()), path.first()) {
+        (None, None) => true,
+        (Some("**"), _) => {
+            glob(&pattern[1..], path) || (!path.is_empty() && glob(pattern, &path[1..]))
+        }
+        (Some(segment), Some(key)) if segment == "*" || segment == *key => {
+            glob(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+/**
+ * The key rules in effect for a mapping. Later rules take precedence over earlier ones.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyRules {
+    pub rules: Vec<KeyRule>,
+}
+
+impl KeyRules {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn is_hidden(&self, resource_type: Option<&str>, path: &[&str]) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.action == KeyAction::Hide && rule.matches(resource_type, path))
+    }
+
+    /**
+     * The table for the value at the end of `path`, if a `@lookup` rule applies to it.
+     */
+    pub fn lookup_table(&self, resource_type: Option<&str>, path: &[&str]) -> Option<&str> {
+        self.rules.iter().rev().find_map(|rule| match &rule.action {
+            KeyAction::Lookup(table) if rule.matches(resource_type, path) => Some(table.as_str()),
+            _ => None,
+        })
+    }
+
+    /**
+     * The new name of the key at the end of `path`, if a rule renames it.
+     */
+    pub fn renamed(&self, resource_type: Option<&str>, path: &[&str]) -> Option<&str> {
+        self.rules.iter().rev().find_map(|rule| match &rule.action {
+            KeyAction::Rename(key) if rule.matches(resource_type, path) => Some(key.as_str()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_patterns() {
+        let rules = KeyRules {
+            rules: vec![
+                KeyRule::parse(1, "*.id", "@hide").unwrap(),
+                KeyRule::parse(2, "meta.versionId", "@hide").unwrap(),
+                KeyRule::parse(3, "Observation.effectiveDateTime -> effective", "@rename").unwrap(),
+                KeyRule::parse(4, "Observation.**.valueQuantity -> value", "@rename").unwrap(),
+            ],
+        };
+        let observation = Some("Observation");
+        assert!(!rules.is_hidden(observation, &["id"]));
+        assert!(rules.is_hidden(observation, &["code", "coding", "id"]));
+        assert!(rules.is_hidden(Some("Patient"), &["meta", "versionId"]));
+        assert_eq!(
+            rules.renamed(observation, &["effectiveDateTime"]),
+            Some("effective")
+        );
+        assert_eq!(
+            rules.renamed(Some("Encounter"), &["effectiveDateTime"]),
+            None
+        );
+        assert_eq!(
+            rules.renamed(observation, &["component", "valueQuantity"]),
+            Some("value")
+        );
+        assert_eq!(
+            rules.renamed(observation, &["valueQuantity"]),
+            Some("value")
+        );
+
+        let rename = |args: &str| KeyRule::parse(1, args, "@rename").unwrap();
+        assert!(rename("code.text -> label").shares_object_with(&rename("code.display -> label")));
+        assert!(
+            !rename("effectiveDateTime -> t").shares_object_with(&rename("effectivePeriod -> t"))
+        );
+        assert!(!rename("Patient.name -> n").shares_object_with(&rename("Group.id -> n")));
+
+        assert!(KeyRule::parse(1, "a..b", "@hide").is_err());
+        assert!(KeyRule::parse(1, "a -> b.c", "@rename").is_err());
+        assert!(KeyRule::parse(1, "Observation", "@hide").is_err());
+    }
+}
@@ -1,18 +1,38 @@
 use jsonpath_lib as jsonpath;
 use serde_json;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fs::File;
 use std::io::{self, BufRead, ErrorKind};
 use std::path::Path;
 extern crate regex;
 use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::hff::fhirpath::{self, FhirPath};
+use crate::hff::keys::{KeyAction, KeyRule, KeyRules};
+use crate::hff::structured;
+use crate::hff::yaml;
+
+/**
+ * Run a JSONPath query and return the serialized list of matches.
+ */
+#[allow(dead_code)]
+pub(crate) fn xjsonp(
+    v: &serde_json::Value,
+    json_path: &str,
+) -> Result<String, jsonpath::JsonPathError> {
+    let mut selector = jsonpath::selector(v);
+    let ret = selector(json_path)?;
+    Ok(serde_json::to_string(&ret).unwrap_or_default())
+}
 
 /**
  * Extract first element from JSONPath query.
  * This function is very quiet and does not report any errors but returns empty strings instead.
  * Todo: Add debug messages on errors.
  */
-fn xjsonp_first(v: &serde_json::Value, json_path: &str) -> String {
+pub(crate) fn xjsonp_first(v: &serde_json::Value, json_path: &str) -> String {
     let mut selector = jsonpath::selector(v);
     match selector(json_path) {
         Ok(ret) => {
@@ -23,8 +43,8 @@ fn xjsonp_first(v: &serde_json::Value, json_path: &str) -> String {
                         Some(arr) => {
                             return arr
                                 .iter()
-                                .filter_map(|opt| opt.as_str())
-                                .collect::<Vec<&str>>()
+                                .filter_map(fhirpath::primitive_to_string)
+                                .collect::<Vec<String>>()
                                 .join(" ");
                         }
                         None => match ret[0].as_number() {
@@ -39,137 +59,1767 @@ fn xjsonp_first(v: &serde_json::Value, json_path: &str) -> String {
             } else if ret.len() > 1 {
                 return ret
                     .iter()
-                    .filter_map(|opt| opt.as_str())
-                    .collect::<Vec<&str>>()
+                    .filter_map(|opt| fhirpath::primitive_to_string(opt))
+                    .collect::<Vec<String>>()
                     .join(" ");
             }
 
-            return "".to_string();
+            "".to_string()
+        }
+        _ => "".to_string(),
+    }
+}
+
+/**
+ * Parse a signature line `#[key, ...]`, optionally followed by a guard `where <conditions>`,
+ * which is returned as written.
+ */
+fn parse_signature(input: &str) -> Result<(Vec<String>, Option<&str>), std::io::Error> {
+    static PATTERN: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^#\[(.*?)\](?:\s+where\s+(.*))?$").unwrap());
+    if let Some(captures) = PATTERN.captures(input) {
+        let trimmed = captures.get(1).unwrap().as_str(); // Get inner contents
+        let guard = captures.get(2).map(|guard| guard.as_str());
+        Ok((
+            trimmed.split(',').map(|s| s.trim().to_string()).collect(),
+            guard,
+        ))
+    } else {
+        Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid signature format: {}", input),
+        ))
+    }
+}
+
+/**
+ * Placeholders of a template: `{$...}` holds a JSONPath, `{%...}` a FHIRPath expression, both
+ * relative to the current node. `{@render ...}` renders the selected nodes with the mapping,
+ * a trailing `lookup <table>` replaces the values with their labels from a `@table` and
+ * `join "..."` sets the separator for multiple results (whitespace by default).
+ */
+pub(crate) fn placeholder_regex() -> &'static Regex {
+    static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(concat!(
+            r#"\{(?P<render>@render\s+)?(?P<path>[$%].+?)"#,
+            r#"(?:\s+lookup\s+(?P<table>[\w-]+))?"#,
+            r#"(?:\s+join\s+"(?P<separator>(?:[^"\\]|\\.)*)")?\}"#
+        ))
+        .expect("Failed to compile regex")
+    });
+    &PLACEHOLDER
+}
+
+/**
+ * Opening and closing tags of `{#each <path> [join "..."]}...{/each}` blocks. The body of a block
+ * is rendered for every node the path selects, with placeholders relative to that node.
+ */
+fn each_regex() -> &'static Regex {
+    static EACH: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"\{#each\s+([$%].+?)(?:\s+join\s+"((?:[^"\\]|\\.)*)")?\}|\{/each\}"#)
+            .expect("Failed to compile regex")
+    });
+    &EACH
+}
+
+/**
+ * A top-level `{#each}` block of a template, positions are byte offsets into the template.
+ */
+pub(crate) struct EachBlock<'a> {
+    pub start: usize,
+    pub end: usize,
+    pub path: &'a str,
+    pub separator: String,
+    pub body: &'a str,
+    pub body_start: usize,
+}
+
+/**
+ * Find the top-level `{#each}` blocks of a template. Nested blocks are part of the body.
+ */
+pub(crate) fn each_blocks(template: &str) -> Result<Vec<EachBlock<'_>>, String> {
+    let mut blocks = Vec::new();
+    let mut open: Vec<regex::Captures> = Vec::new();
+    for caps in each_regex().captures_iter(template) {
+        if caps.get(1).is_some() {
+            open.push(caps);
+            continue;
+        }
+        let Some(opening) = open.pop() else {
+            return Err("`{/each}` without `{#each ...}`".to_string());
+        };
+        if open.is_empty() {
+            let (tag, close) = (opening.get(0).unwrap(), caps.get(0).unwrap());
+            let path = opening.get(1).unwrap();
+            blocks.push(EachBlock {
+                start: tag.start(),
+                end: close.end(),
+                path: path.as_str(),
+                separator: unescape(opening.get(2).map_or(" ", |m| m.as_str())),
+                body: &template[tag.end()..close.start()],
+                body_start: tag.end(),
+            });
         }
-        _ => {
-            return "".to_string();
+    }
+    match open.first() {
+        Some(_) => Err("`{#each ...}` without `{/each}`".to_string()),
+        None => Ok(blocks),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(escaped) => out.push(escaped),
+                None => out.push('\\'),
+            },
+            (c, false) => out.push(c),
         }
     }
+    out
 }
 
+// guards against rules that render themselves, e.g. `{@render %$this}`
+const MAX_RENDER_DEPTH: usize = 16;
+
 /**
- * Check if a JSON Path expression matches.
+ * A lookup table declared with `@table`, mapping values to labels.
  */
-/*
-fn xjsonp_match(v: &serde_json::Value, json_path: &str) -> String {
-    let mut selector = jsonpath::selector(v);
-    match selector(json_path) {
-        Ok(ret) => {
-            if ret.len() == 1 {
-                match ret[0].as_str() {
-                    Some(s) => return s.to_string(),
-                    None => match ret[0].as_array() {
-                        Some(arr) => return arr.iter().filter_map(|opt| opt.as_str()).collect::<Vec<&str>>().join(" "),
-                        None => match ret[0].as_number() {
-                            Some(n) => return n.to_string(),
-                            None => match ret[0].as_bool() {
-                                Some(b) => return b.to_string(),
-                                None => return "".to_string()
-                            }
-                        }
-                    }
-                }
-            }
-            else if ret.len() > 1 {
-                return ret.iter().filter_map(|opt| opt.as_str()).collect::<Vec<&str>>().join(" ");
-            }
+pub type Table = HashMap<String, String>;
+
+/**
+ * What templates are rendered with: the rules for `{@render ...}` and the `@table`s for `lookup`.
+ */
+#[derive(Clone, Copy)]
+pub struct RenderContext<'a> {
+    pub rules: &'a HashMap<String, Rule>,
+    pub tables: &'a HashMap<String, Table>,
+    depth: usize,
+}
 
-            return "".to_string();
+impl<'a> RenderContext<'a> {
+    pub fn new(
+        rules: &'a HashMap<String, Rule>,
+        tables: &'a HashMap<String, Table>,
+    ) -> RenderContext<'a> {
+        RenderContext {
+            rules,
+            tables,
+            depth: 0,
         }
-        _ => {
-            return "".to_string();
+    }
+
+    fn deeper(&self) -> RenderContext<'a> {
+        RenderContext {
+            depth: self.depth + 1,
+            ..*self
         }
     }
+
+    /**
+     * Look up a value in a table, values that are not in the table are kept.
+     */
+    fn lookup(&self, table: Option<&str>, value: String) -> String {
+        table
+            .and_then(|table| self.tables.get(table))
+            .and_then(|table| table.get(&value))
+            .cloned()
+            .unwrap_or(value)
+    }
 }
-*/
 
-fn parse_signature(input: &str) -> Result<Vec<String>, std::io::Error> {
-    let pattern = Regex::new(r"^#\[(.*)\]$").unwrap();
-    if let Some(captures) = pattern.captures(input) {
-        let trimmed = captures.get(1).unwrap().as_str(); // Get inner contents
-        Ok(trimmed.split(',').map(|s| s.trim().to_string()).collect())
+pub fn apply_format(v: &serde_json::Value, input: &str) -> String {
+    apply_format_with(
+        v,
+        input,
+        &RenderContext::new(&HashMap::new(), &HashMap::new()),
+    )
+}
+
+/**
+ * Like `apply_format_with`, but a template that is a single placeholder keeps the JSON type of
+ * the value it selects, so e.g. `{$.value}` yields a number rather than a string.
+ */
+pub fn apply_format_typed(
+    v: &serde_json::Value,
+    input: &str,
+    ctx: &RenderContext,
+) -> serde_json::Value {
+    if let Some(caps) = placeholder_regex().captures(input)
+        && caps.get(0).unwrap().as_str() == input
+        && caps.name("render").is_none()
+        && caps.name("separator").is_none()
+        && let [value] = flatten(select(v, &caps["path"])).as_slice()
+        && (value.is_number() || value.is_boolean() || value.is_string())
+    {
+        return match caps.name("table") {
+            Some(table) => {
+                let text = fhirpath::primitive_to_string(value).unwrap_or_default();
+                serde_json::Value::String(ctx.lookup(Some(table.as_str()), text))
+            }
+            None => value.clone(),
+        };
+    }
+    serde_json::Value::String(apply_format_with(v, input, ctx))
+}
+
+/**
+ * Apply a rule to a node: the template of a plain rule renders a single value (see
+ * `apply_format_typed`), the fields of a structured rule render an object or a list.
+ * Fields that render empty are left out.
+ */
+pub fn apply_rule(v: &serde_json::Value, rule: &Rule, ctx: &RenderContext) -> serde_json::Value {
+    if rule.fields.is_empty() {
+        return apply_format_typed(v, &rule.format, ctx);
+    }
+    let values = rule.fields.iter().filter_map(|field| {
+        let value = apply_format_typed(v, &field.format, ctx);
+        (value != serde_json::Value::String(String::new())).then_some((&field.key, value))
+    });
+    if rule.is_list() {
+        serde_json::Value::Array(values.map(|(_, value)| value).collect())
     } else {
-        Err(std::io::Error::new(
-            ErrorKind::InvalidData,
-            format!("Invalid signature format: {}", input),
-        ))
+        serde_json::Value::Object(
+            values
+                .map(|(key, value)| (key.clone().unwrap_or_default(), value))
+                .collect(),
+        )
     }
 }
 
-pub fn apply_format(v: &serde_json::Value, input: &str) -> String {
-    let re = Regex::new(r"\{(\$.+?)\}").expect("Failed to compile regex");
-    return re
-        .replace_all(input, |caps: &regex::Captures| xjsonp_first(v, &caps[1]))
-        .to_string();
+/**
+ * Text form of a rendered value, e.g. for `@test` lines: strings as they are, anything else as JSON.
+ */
+pub fn value_to_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/**
+ * Like `apply_format`, with the rules that `{@render ...}` placeholders are rendered with and the
+ * tables for `lookup`.
+ */
+pub fn apply_format_with(v: &serde_json::Value, input: &str, ctx: &RenderContext) -> String {
+    // unbalanced blocks are left as they are, like invalid placeholders this is quiet
+    let blocks = each_blocks(input).unwrap_or_default();
+    let mut out = String::new();
+    let mut pos = 0;
+    for block in blocks {
+        out.push_str(&render_placeholders(v, &input[pos..block.start], ctx));
+        out.push_str(
+            &flatten(select(v, block.path))
+                .iter()
+                .map(|item| apply_format_with(item, block.body, ctx))
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<String>>()
+                .join(&block.separator),
+        );
+        pos = block.end;
+    }
+    out.push_str(&render_placeholders(v, &input[pos..], ctx));
+    out
+}
+
+fn render_placeholders(v: &serde_json::Value, input: &str, ctx: &RenderContext) -> String {
+    placeholder_regex()
+        .replace_all(input, |caps: &regex::Captures| {
+            let path = &caps["path"];
+            let table = caps.name("table").map(|m| m.as_str());
+            let separator = caps.name("separator").map(|m| unescape(m.as_str()));
+            if caps.name("render").is_some() {
+                return select(v, path)
+                    .iter()
+                    .map(|node| render_node(node, &ctx.deeper()))
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<String>>()
+                    .join(separator.as_deref().unwrap_or(" "));
+            }
+            match (separator, table, path.strip_prefix('%')) {
+                (None, None, Some(expression)) => fhirpath::xfhirpath(v, expression),
+                (None, None, None) => xjsonp_first(v, path),
+                (separator, table, _) => flatten(select(v, path))
+                    .iter()
+                    .filter_map(fhirpath::primitive_to_string)
+                    .map(|value| ctx.lookup(table, value))
+                    .collect::<Vec<String>>()
+                    .join(separator.as_deref().unwrap_or(" ")),
+            }
+        })
+        .to_string()
+}
+
+/**
+ * JSONPath returns an array as a single match, unnest it so it can be iterated.
+ */
+fn flatten(nodes: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+    nodes
+        .into_iter()
+        .flat_map(|node| match node {
+            serde_json::Value::Array(arr) => arr,
+            node => vec![node],
+        })
+        .collect()
+}
+
+/**
+ * Nodes selected by a JSONPath (`$...`) or FHIRPath (`%...`) expression.
+ */
+fn select(v: &serde_json::Value, path: &str) -> Vec<serde_json::Value> {
+    match path.strip_prefix('%') {
+        Some(expression) => FhirPath::parse(expression)
+            .and_then(|fhir_path| fhir_path.evaluate(v))
+            .unwrap_or_default(),
+        None => jsonpath::selector(v)(path)
+            .map(|nodes| nodes.into_iter().cloned().collect())
+            .unwrap_or_default(),
+    }
+}
+
+/**
+ * Render a node the way the rule for its signature formats it. Objects without a rule render empty.
+ */
+fn render_node(node: &serde_json::Value, ctx: &RenderContext) -> String {
+    match node {
+        serde_json::Value::Object(map) if ctx.depth <= MAX_RENDER_DEPTH => {
+            let keys = map.keys().cloned().collect::<Vec<String>>();
+            ctx.rules
+                .get(&signature_to_str(keys))
+                .and_then(|rule| rule.select(node))
+                .map(|rule| value_to_text(&apply_rule(node, rule, ctx)))
+                .unwrap_or_default()
+        }
+        serde_json::Value::Array(arr) => arr
+            .iter()
+            .map(|v| render_node(v, ctx))
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<String>>()
+            .join(" "),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Number(n) => n.to_string(),
+        serde_json::Value::Bool(b) => b.to_string(),
+        _ => String::new(),
+    }
 }
 
 pub fn signature_to_str(signature: Vec<String>) -> String {
     // sort and join signature
     let mut sorted_signature = signature.clone();
     sorted_signature.sort();
-    return sorted_signature.join("|");
+    sorted_signature.join("|")
 }
 
 pub fn load_default_mapping() -> io::Result<Vec<String>> {
     load_mapping_from_str(default_mapping())
 }
 
-pub fn process_mapping(mapping: &Vec<String>) -> Result<HashMap<String, String>, std::io::Error> {
-    let mut mappers = HashMap::new();
-    for pair in mapping.chunks(2) {
-        match pair {
-            [signature_str, format_str] => {
-                if let Ok(parsed_signature) = parse_signature(signature_str.trim()) {
-                    mappers.insert(
-                        signature_to_str(parsed_signature),
-                        format_str.trim().to_string(),
-                    );
+/**
+ * A formatting rule of a mapping. `line` is the (1-based) line of its signature in the mapping source.
+ * Structured rules have `fields` (and an empty `format`) and render an object or a list.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub format: String,
+    pub line: usize,
+    // name of the included mapping the rule comes from, `None` for the mapping itself
+    pub source: Option<String>,
+    pub fields: Vec<Field>,
+    // `where` conditions on the values of the node, `None` if the rule applies to any node
+    pub guard: Option<Guard>,
+    // rules for the same signature with a guard, the ones that take precedence first
+    pub guarded: Vec<Rule>,
+}
+
+/**
+ * A `@field <key>: <template>` (object entry) or `@item <template>` (list element) of a structured rule.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub key: Option<String>,
+    pub format: String,
+    pub line: usize,
+}
+
+impl Rule {
+    /**
+     * True for structured rules made of `@item` lines.
+     */
+    pub fn is_list(&self) -> bool {
+        self.fields.first().is_some_and(|field| field.key.is_none())
+    }
+
+    /**
+     * The rule that formats `v`: the first guarded rule whose conditions hold, or else this rule
+     * unless its own guard fails.
+     */
+    pub fn select(&self, v: &serde_json::Value) -> Option<&Rule> {
+        self.guarded
+            .iter()
+            .chain(std::iter::once(self))
+            .find(|rule| rule.guard.as_ref().is_none_or(|guard| guard.matches(v)))
+    }
+
+    /**
+     * Put `rule` for the same signature into effect. A rule without guard replaces the current one
+     * without guard, a guarded rule takes precedence over the guarded rules so far and replaces
+     * the one with the same conditions.
+     */
+    fn merge(&mut self, mut rule: Rule) {
+        match (&rule.guard, &self.guard) {
+            (Some(guard), _) => {
+                self.guarded.retain(|r| r.guard.as_ref() != Some(guard));
+                if self.guard.as_ref() == Some(guard) {
+                    // the new rule takes the place of this one, keeping the other guarded rules
+                    rule.guarded = std::mem::take(&mut self.guarded);
+                    *self = rule;
                 } else {
-                    return Err(std::io::Error::new(
-                        ErrorKind::InvalidData,
-                        "Failed to parse signature",
-                    ));
+                    self.guarded.insert(0, rule);
                 }
             }
-            _ => {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Invalid format: unequal number of lines in mapping file",
+            (None, None) => {
+                rule.guarded = std::mem::take(&mut self.guarded);
+                *self = rule;
+            }
+            (None, Some(_)) => {
+                let mut guarded = std::mem::take(&mut self.guarded);
+                guarded.push(std::mem::replace(self, rule));
+                self.guarded = guarded;
+            }
+        }
+    }
+
+    /**
+     * Where the rule is defined, e.g. `line 12`, or `default line 12` for a rule from an included mapping.
+     */
+    pub fn location(&self) -> String {
+        match &self.source {
+            Some(source) => format!("{} line {}", source, self.line),
+            None => format!("line {}", self.line),
+        }
+    }
+}
+
+/**
+ * The `where` guard of a rule, e.g. `#[system, code] where system = http://loinc.org`. Conditions
+ * are joined with `and`; each compares a FHIRPath expression (in the simplest case a key) or a
+ * `$` JSONPath with a value, which can be put in double quotes. `=` holds if any of the selected
+ * values is equal, `!=` if none is.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Guard {
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    // `%` FHIRPath or `$` JSONPath, like in placeholders
+    pub path: String,
+    pub negated: bool,
+    pub value: String,
+    // offset of the condition in the guard
+    start: usize,
+}
+
+impl Guard {
+    pub fn parse(input: &str) -> Result<Guard, String> {
+        static CONDITION: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(concat!(
+                r#"^\s*(?P<path>[^=!"]+?)\s*(?P<op>!=|=)\s*"#,
+                r#"(?:"(?P<quoted>(?:[^"\\]|\\.)*)"|(?P<value>[^\s"]+))\s*(?:$|and\s+)"#
+            ))
+            .unwrap()
+        });
+        let mut conditions = Vec::new();
+        let mut rest = input;
+        while !rest.trim().is_empty() {
+            let Some(caps) = CONDITION.captures(rest) else {
+                return Err(format!(
+                    "expected `<path> = <value>` or `<path> != <value>` in `{}`",
+                    rest.trim()
                 ));
+            };
+            let path = caps.name("path").unwrap().as_str();
+            let path = match path.starts_with(['$', '%']) {
+                true => path.to_string(),
+                false => format!("%{}", path),
+            };
+            match path.strip_prefix('%') {
+                Some(expression) => {
+                    FhirPath::parse(expression)
+                        .map_err(|e| format!("invalid FHIRPath `{}`: {}", expression, e))?;
+                }
+                None if jsonpath::Parser::compile(&path).is_err() => {
+                    return Err(format!("invalid JSONPath `{}`", path));
+                }
+                None => {}
             }
+            let value = match caps.name("quoted") {
+                Some(quoted) => unescape(quoted.as_str()),
+                None => caps.name("value").unwrap().as_str().to_string(),
+            };
+            conditions.push(Condition {
+                path,
+                negated: &caps["op"] == "!=",
+                value,
+                start: input.len() - rest.len() + caps.name("path").unwrap().start(),
+            });
+            rest = &rest[caps.get(0).unwrap().end()..];
+        }
+        if conditions.is_empty() {
+            return Err("expected conditions after `where`".to_string());
+        }
+        Ok(Guard { conditions })
+    }
+
+    pub fn matches(&self, v: &serde_json::Value) -> bool {
+        self.conditions.iter().all(|condition| {
+            let equal = select(v, &condition.path)
+                .iter()
+                .filter_map(fhirpath::primitive_to_string)
+                .any(|value| value == condition.value);
+            equal != condition.negated
+        })
+    }
+}
+
+impl std::fmt::Display for Guard {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|condition| {
+                let path = condition.path.strip_prefix('%').unwrap_or(&condition.path);
+                let operator = if condition.negated { "!=" } else { "=" };
+                match condition
+                    .value
+                    .contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+                {
+                    true => format!("{} {} {}", path, operator, json_quote(&condition.value)),
+                    false => format!("{} {} {}", path, operator, condition.value),
+                }
+            })
+            .collect::<Vec<String>>();
+        write!(f, "{}", conditions.join(" and "))
+    }
+}
+
+fn json_quote(s: &str) -> String {
+    serde_json::Value::String(s.to_string()).to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/**
+ * A problem found in a mapping, located by (1-based) line and column.
+ * Errors make `process_mapping` fail, warnings point at rules that most likely don't do what was intended.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.line, self.column, severity, self.message
+        )
+    }
+}
+
+fn diagnostic(line: usize, column: usize, severity: Severity, message: String) -> Diagnostic {
+    Diagnostic {
+        line,
+        column,
+        severity,
+        message,
+    }
+}
+
+/**
+ * A rule as it was parsed, before it is put into the lookup table.
+ */
+pub(crate) struct ParsedRule {
+    pub(crate) signature: Vec<String>,
+    pub(crate) rule: Rule,
+    format_line: usize,
+    // marked with `@override`, i.e. meant to replace an inherited rule
+    pub(crate) is_override: bool,
+}
+
+impl ParsedRule {
+    /**
+     * The signature and guard that identify the rule, rules with the same key replace each other.
+     */
+    fn key(&self) -> String {
+        match &self.rule.guard {
+            Some(guard) => format!(
+                "{} where {}",
+                signature_to_str(self.signature.clone()),
+                guard
+            ),
+            None => signature_to_str(self.signature.clone()),
+        }
+    }
+
+    /**
+     * The signature line, e.g. `#[system, code] where system = http://loinc.org`.
+     */
+    fn signature_str(&self) -> String {
+        match &self.rule.guard {
+            Some(guard) => format!("#[{}] where {}", self.signature.join(", "), guard),
+            None => format!("#[{}]", self.signature.join(", ")),
         }
     }
+}
+
+/**
+ * An `@include` directive. `position` is the number of rules defined before it.
+ */
+pub(crate) struct Include {
+    line: usize,
+    pub(crate) target: String,
+    pub(crate) position: usize,
+    // the included mapping, once it is loaded
+    mapping: Option<ParsedMapping>,
+}
+
+/**
+ * An example from a `@test <json> => <expected>` line of a mapping.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappingTest {
+    pub line: usize,
+    pub input: serde_json::Value,
+    pub expected: String,
+}
+
+/**
+ * Everything found in a mapping: its rules, directives and the problems that prevented parsing parts of it.
+ */
+pub(crate) struct ParsedTable {
+    pub(crate) name: String,
+    // in the order they are declared
+    pub(crate) entries: Vec<(String, String)>,
+    line: usize,
+    pub(crate) position: usize,
+}
 
-    Ok(mappers)
+pub(crate) struct ParsedMapping {
+    pub(crate) rules: Vec<ParsedRule>,
+    pub(crate) includes: Vec<Include>,
+    pub(crate) tests: Vec<MappingTest>,
+    // `@rename`, `@hide` and `@lookup` rules with their position among the rules
+    pub(crate) key_rules: Vec<(usize, KeyRule)>,
+    pub(crate) tables: Vec<ParsedTable>,
+    pub(crate) diagnostics: Vec<Diagnostic>,
+    pending_override: bool,
+}
+
+const DIRECTIVES: &[&str] = &[
+    "@test",
+    "@include",
+    "@override",
+    "@table",
+    "@hide",
+    "@rename",
+    "@lookup",
+    "@field",
+    "@item",
+];
+
+/**
+ * Only known directives, templates can start with `@` as well, e.g. `@{$.handle}`.
+ */
+fn is_directive(line: &str) -> bool {
+    let name = line.split(char::is_whitespace).next().unwrap_or(line);
+    DIRECTIVES.contains(&name)
+}
+
+/**
+ * Parse a mapping into its rules and directives. Comments and blank lines are skipped, every
+ * signature line must be followed by its template line. Structural problems are reported as errors.
+ */
+pub(crate) fn parse_rules(mapping: &[String]) -> ParsedMapping {
+    let mut parsed = ParsedMapping {
+        rules: Vec::new(),
+        includes: Vec::new(),
+        tests: Vec::new(),
+        key_rules: Vec::new(),
+        tables: Vec::new(),
+        diagnostics: Vec::new(),
+        pending_override: false,
+    };
+    let mut lines = mapping
+        .iter()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with("//")) // remove comments
+        .peekable();
+    while let Some((line_nr, signature_str)) = lines.next() {
+        if is_directive(signature_str) {
+            parse_directive(line_nr, signature_str, &mut parsed);
+            continue;
+        }
+        let (parsed_signature, guard) = match parse_signature(signature_str) {
+            Ok(parsed_signature) => parsed_signature,
+            Err(_) if signature_str.starts_with('#') => {
+                parsed.diagnostics.push(diagnostic(
+                    line_nr,
+                    1,
+                    Severity::Error,
+                    format!(
+                        "malformed signature `{}`, expected `#[key, ...]`",
+                        signature_str
+                    ),
+                ));
+                // skip the template of the broken rule
+                lines.next_if(|(_, line)| !line.starts_with('#') && !is_directive(line));
+                continue;
+            }
+            Err(_) => {
+                let name = signature_str.split(char::is_whitespace).next();
+                let message = match name.filter(|name| name.starts_with('@')) {
+                    Some(name) => format!("unknown directive `{}`", name),
+                    None => {
+                        "expected a signature `#[key, ...]`, found a template without signature"
+                            .to_string()
+                    }
+                };
+                parsed
+                    .diagnostics
+                    .push(diagnostic(line_nr, 1, Severity::Error, message));
+                continue;
+            }
+        };
+        let guard = match guard.map(|guard| (guard, Guard::parse(guard))) {
+            Some((_, Ok(guard))) => Some(guard),
+            Some((guard, Err(e))) => {
+                let offset = signature_str.len() - guard.len();
+                let column = signature_str[..offset].chars().count() + 1;
+                parsed
+                    .diagnostics
+                    .push(diagnostic(line_nr, column, Severity::Error, e));
+                None
+            }
+            None => None,
+        };
+        let mut fields = Vec::new();
+        while let Some((field_line, field_str)) = lines.next_if(|(_, line)| is_field(line)) {
+            match parse_field(field_line, field_str) {
+                Ok(field) => fields.push(field),
+                Err(e) => parsed.diagnostics.push(e),
+            }
+        }
+        if fields.iter().any(|f| f.key.is_some()) && fields.iter().any(|f| f.key.is_none()) {
+            parsed.diagnostics.push(diagnostic(
+                line_nr,
+                1,
+                Severity::Error,
+                "a rule has either `@field` or `@item` lines, not both".to_string(),
+            ));
+        }
+        if let Some(first) = fields.first() {
+            parsed.rules.push(ParsedRule {
+                signature: parsed_signature,
+                rule: Rule {
+                    format: String::new(),
+                    line: line_nr,
+                    source: None,
+                    fields: fields.clone(),
+                    guard,
+                    guarded: Vec::new(),
+                },
+                format_line: first.line,
+                is_override: std::mem::take(&mut parsed.pending_override),
+            });
+            continue;
+        }
+        match lines.next_if(|(_, line)| parse_signature(line).is_err() && !is_directive(line)) {
+            Some((format_line, format_str)) => parsed.rules.push(ParsedRule {
+                signature: parsed_signature,
+                rule: Rule {
+                    format: format_str.to_string(),
+                    line: line_nr,
+                    source: None,
+                    fields: Vec::new(),
+                    guard,
+                    guarded: Vec::new(),
+                },
+                format_line,
+                is_override: std::mem::take(&mut parsed.pending_override),
+            }),
+            None => parsed.diagnostics.push(diagnostic(
+                line_nr,
+                signature_str.chars().count() + 1,
+                Severity::Error,
+                "signature without template".to_string(),
+            )),
+        }
+    }
+    parsed
+}
+
+fn is_field(line: &str) -> bool {
+    let name = line.split(char::is_whitespace).next().unwrap_or(line);
+    name == "@field" || name == "@item"
+}
+
+/**
+ * Parse `@field <key>: <template>` or `@item <template>`.
+ */
+fn parse_field(line_nr: usize, line: &str) -> Result<Field, Diagnostic> {
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let column = name.len() + 2;
+    let error = |message: &str| diagnostic(line_nr, column, Severity::Error, message.to_string());
+    let (key, format) = match name {
+        "@item" => (None, args.trim()),
+        _ => match args.split_once(':') {
+            Some((key, format)) if !key.trim().is_empty() => {
+                (Some(key.trim().to_string()), format.trim())
+            }
+            _ => return Err(error("expected `@field <key>: <template>`")),
+        },
+    };
+    if format.is_empty() {
+        return Err(error("field without template"));
+    }
+    Ok(Field {
+        key,
+        format: format.to_string(),
+        line: line_nr,
+    })
+}
+
+fn parse_directive(line_nr: usize, line: &str, parsed: &mut ParsedMapping) {
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let column = name.len() + 2;
+    match name {
+        "@test" => match parse_test(line_nr, args.trim()) {
+            Ok(test) => parsed.tests.push(test),
+            Err(message) => {
+                parsed
+                    .diagnostics
+                    .push(diagnostic(line_nr, column, Severity::Error, message))
+            }
+        },
+        "@include" if !args.trim().is_empty() => parsed.includes.push(Include {
+            line: line_nr,
+            target: args.trim().to_string(),
+            position: parsed.rules.len(),
+            mapping: None,
+        }),
+        "@include" => parsed.diagnostics.push(diagnostic(
+            line_nr,
+            column,
+            Severity::Error,
+            "expected a path or `default` after `@include`".to_string(),
+        )),
+        "@override" => parsed.pending_override = true,
+        "@table" => match parse_table(line_nr, args.trim(), parsed.rules.len()) {
+            Ok(table) => parsed.tables.push(table),
+            Err(message) => {
+                parsed
+                    .diagnostics
+                    .push(diagnostic(line_nr, column, Severity::Error, message))
+            }
+        },
+        "@hide" | "@rename" | "@lookup" => match KeyRule::parse(line_nr, args, name) {
+            Ok(key_rule) => parsed.key_rules.push((parsed.rules.len(), key_rule)),
+            Err(message) => {
+                parsed
+                    .diagnostics
+                    .push(diagnostic(line_nr, column, Severity::Error, message))
+            }
+        },
+        _ => parsed.diagnostics.push(diagnostic(
+            line_nr,
+            1,
+            Severity::Error,
+            format!("`{}` must follow a signature or another field", name),
+        )),
+    }
+}
+
+pub(crate) fn first_error(diagnostics: &[Diagnostic]) -> Option<&Diagnostic> {
+    diagnostics.iter().find(|d| d.severity == Severity::Error)
+}
+
+/**
+ * Load, parse and resolve the mapping of an `@include`, recursively, and tag its rules and key
+ * rules with the name of the mapping. Problems are reported at the `@include` line.
+ */
+fn load_include(include: &mut Include, stack: &mut Vec<String>) -> Result<(), Diagnostic> {
+    // point at the target, i.e. after `@include `
+    let error = |message: String| diagnostic(include.line, 10, Severity::Error, message);
+    if stack.contains(&include.target) {
+        return Err(error(format!(
+            "`{}` is included recursively",
+            include.target
+        )));
+    }
+    let lines = match include.target.as_str() {
+        "default" => load_default_mapping(),
+        path => load_mapping_from_file(Path::new(path)),
+    }
+    .map_err(|e| error(format!("cannot include `{}`: {}", include.target, e)))?;
+
+    let mut included = parse_rules(&lines);
+    if let Some(e) = first_error(&included.diagnostics) {
+        return Err(error(format!(
+            "in included mapping `{}`: {}",
+            include.target, e
+        )));
+    }
+    stack.push(include.target.clone());
+    let resolved = resolve_includes(&mut included, stack);
+    stack.pop();
+    resolved.map_err(|e| error(format!("in included mapping `{}`: {}", include.target, e)))?;
+    let source = || Some(include.target.clone());
+    for parsed_rule in included.rules.iter_mut() {
+        parsed_rule.rule.source = parsed_rule.rule.source.take().or_else(source);
+    }
+    for (_, key_rule) in included.key_rules.iter_mut() {
+        key_rule.source = key_rule.source.take().or_else(source);
+    }
+    include.mapping = Some(included);
+    Ok(())
+}
+
+fn resolve_includes(parsed: &mut ParsedMapping, stack: &mut Vec<String>) -> Result<(), Diagnostic> {
+    for include in parsed.includes.iter_mut() {
+        load_include(include, stack)?;
+    }
+    Ok(())
+}
+
+impl ParsedMapping {
+    /**
+     * Rules in the order they take effect, with the rules of included mappings in place of their
+     * `@include`.
+     */
+    fn effective_rules(&self) -> Vec<&ParsedRule> {
+        let mut rules = Vec::new();
+        let mut includes = self.includes.iter().peekable();
+        for (position, parsed_rule) in self.rules.iter().enumerate() {
+            while let Some(include) = includes.next_if(|include| include.position == position) {
+                rules.extend(include.mapping.iter().flat_map(|m| m.effective_rules()));
+            }
+            rules.push(parsed_rule);
+        }
+        for include in includes {
+            rules.extend(include.mapping.iter().flat_map(|m| m.effective_rules()));
+        }
+        rules
+    }
+
+    /**
+     * This mapping and the mappings it includes, recursively, in the order their key rules and
+     * tables take effect: included mappings come first so that a mapping can replace what it
+     * inherits.
+     */
+    fn chain(&self) -> Vec<&ParsedMapping> {
+        let mut chain = self
+            .includes
+            .iter()
+            .flat_map(|include| include.mapping.iter().flat_map(|m| m.chain()))
+            .collect::<Vec<&ParsedMapping>>();
+        chain.push(self);
+        chain
+    }
+
+    fn tables(&self) -> HashMap<String, Table> {
+        self.chain()
+            .into_iter()
+            .flat_map(|parsed| &parsed.tables)
+            .map(|table| (table.name.clone(), table.entries.iter().cloned().collect()))
+            .collect()
+    }
+}
+
+/**
+ * Everything needed to render with a mapping, including what it inherits from included mappings:
+ * the rules by signature, the `@rename`, `@hide` and `@lookup` rules and the `@table`s (a later
+ * table replaces an earlier one with the same name).
+ */
+pub struct ProcessedMapping {
+    pub rules: HashMap<String, Rule>,
+    pub keys: KeyRules,
+    pub tables: HashMap<String, Table>,
+}
+
+/**
+ * Parse the arguments of `@table <name> { <value>: <label>, ... }`.
+ */
+fn parse_table(line_nr: usize, args: &str, position: usize) -> Result<ParsedTable, String> {
+    let (name, entries) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("expected `@table <name> { <value>: <label>, ... }`".to_string());
+    }
+    let entries = entries.trim();
+    if !entries.starts_with('{') {
+        return Err("expected `{ <value>: <label>, ... }` after the table name".to_string());
+    }
+    let mapping = match yaml::from_yaml(entries) {
+        Ok(serde_json::Value::Object(mapping)) => mapping,
+        Ok(_) => {
+            return Err("expected `{ <value>: <label>, ... }` after the table name".to_string());
+        }
+        Err(e) => return Err(format!("invalid table: {}", e.message)),
+    };
+    let entries = mapping
+        .into_iter()
+        .map(|(k, v)| match v {
+            serde_json::Value::String(v) => Ok((k, v)),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Ok((k, v.to_string())),
+            _ => Err("table entries must map values to labels".to_string()),
+        })
+        .collect::<Result<Vec<(String, String)>, String>>()?;
+    Ok(ParsedTable {
+        name: name.to_string(),
+        entries,
+        line: line_nr,
+        position,
+    })
+}
+
+/**
+ * Parse the arguments of `@test {"system":"x","value":"1"} => x | 1`.
+ */
+fn parse_test(line_nr: usize, args: &str) -> Result<MappingTest, String> {
+    let mut stream = serde_json::Deserializer::from_str(args).into_iter::<serde_json::Value>();
+    let input = match stream.next() {
+        Some(Ok(input)) if input.is_object() => input,
+        Some(Err(e)) => return Err(format!("invalid test input: {}", e)),
+        _ => return Err("test input must be a JSON object".to_string()),
+    };
+    match args[stream.byte_offset()..].trim_start().strip_prefix("=>") {
+        Some(expected) => Ok(MappingTest {
+            line: line_nr,
+            input,
+            expected: expected.trim().to_string(),
+        }),
+        None => Err("expected `=> <output>` after the test input".to_string()),
+    }
+}
+
+/**
+ * Parse a mapping and the mappings it includes, each of them once.
+ */
+pub fn process_mapping(mapping: &[String]) -> Result<ProcessedMapping, std::io::Error> {
+    let invalid = |error: &Diagnostic| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid mapping: {}", error),
+        )
+    };
+    let mut parsed = parse_rules(mapping);
+    if let Some(error) = first_error(&parsed.diagnostics) {
+        return Err(invalid(error));
+    }
+    resolve_includes(&mut parsed, &mut Vec::new()).map_err(|e| invalid(&e))?;
+
+    // later rules replace earlier ones with the same signature (and guard), including the ones of
+    // included mappings
+    let mut mappers: HashMap<String, Rule> = HashMap::new();
+    for parsed_rule in parsed.effective_rules() {
+        match mappers.entry(signature_to_str(parsed_rule.signature.clone())) {
+            Entry::Occupied(mut entry) => entry.get_mut().merge(parsed_rule.rule.clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(parsed_rule.rule.clone());
+            }
+        }
+    }
+    let keys = KeyRules {
+        rules: parsed
+            .chain()
+            .into_iter()
+            .flat_map(|m| m.key_rules.iter().map(|(_, key_rule)| key_rule.clone()))
+            .collect(),
+    };
+    Ok(ProcessedMapping {
+        rules: mappers,
+        keys,
+        tables: parsed.tables(),
+    })
+}
+
+/**
+ * Put the rules of an included mapping into effect, warning about own rules they replace.
+ */
+fn include_into(
+    seen: &mut HashMap<String, (Rule, bool)>,
+    diagnostics: &mut Vec<Diagnostic>,
+    include: &Include,
+) {
+    // includes that can't be loaded are reported on their own
+    let Some(included) = &include.mapping else {
+        return;
+    };
+    for parsed in included.effective_rules() {
+        let sign_str = parsed.key();
+        if let Some((own, true)) = seen.get(&sign_str) {
+            diagnostics.push(diagnostic(
+                own.line,
+                1,
+                Severity::Warning,
+                format!(
+                    "rule for `{}` is replaced by the rule in {} included in line {}",
+                    parsed.signature_str(),
+                    parsed.rule.location(),
+                    include.line
+                ),
+            ));
+        }
+        seen.insert(sign_str, (parsed.rule.clone(), false));
+    }
+}
+
+/**
+ * Lint a mapping. Besides the structural errors that make `process_mapping` fail, this reports
+ * duplicate signatures that override each other, placeholders with invalid JSONPath and placeholders
+ * that reference keys which are not part of the signature (and therefore always render empty).
+ * Rules that replace a rule of an included mapping need to be marked with `@override`, and
+ * `@rename` rules that can rename two keys of an object to the same key are reported.
+ */
+pub fn validate_mapping(mapping: &[String]) -> Vec<Diagnostic> {
+    let mut parsed_mapping = parse_rules(mapping);
+    // the includes that can't be loaded are reported, and left out
+    let mut include_errors = Vec::new();
+    for include in parsed_mapping.includes.iter_mut() {
+        if let Err(e) = load_include(include, &mut Vec::new()) {
+            include_errors.push(e);
+        }
+    }
+    // names of the tables in effect, unknown if the mapping or an include is broken
+    let known_tables = (include_errors.is_empty()
+        && first_error(&parsed_mapping.diagnostics).is_none())
+    .then(|| parsed_mapping.tables());
+    let ParsedMapping {
+        rules,
+        includes,
+        key_rules,
+        tables,
+        mut diagnostics,
+        ..
+    } = parsed_mapping;
+    diagnostics.extend(include_errors);
+
+    let unknown_table = |name: &str| known_tables.as_ref().is_some_and(|t| !t.contains_key(name));
+    for (i, table) in tables.iter().enumerate() {
+        if let Some(previous) = tables[..i].iter().find(|t| t.name == table.name) {
+            diagnostics.push(diagnostic(
+                table.line,
+                1,
+                Severity::Warning,
+                format!(
+                    "duplicate table `{}` replaces the table in line {}",
+                    table.name, previous.line
+                ),
+            ));
+        }
+    }
+    for (i, (_, key_rule)) in key_rules.iter().enumerate() {
+        if let KeyAction::Rename(key) = &key_rule.action
+            && let Some((_, previous)) = key_rules[..i].iter().find(|(_, previous)| {
+                previous.action == key_rule.action && previous.shares_object_with(key_rule)
+            })
+        {
+            diagnostics.push(diagnostic(
+                key_rule.line,
+                1,
+                Severity::Warning,
+                format!(
+                    "`{}` and `{}` in line {} are both renamed to `{}`, objects with both keep their names",
+                    key_rule.pattern_str(),
+                    previous.pattern_str(),
+                    previous.line,
+                    key
+                ),
+            ));
+        }
+        if let KeyAction::Lookup(table) = &key_rule.action
+            && unknown_table(table)
+        {
+            diagnostics.push(diagnostic(
+                key_rule.line,
+                1,
+                Severity::Warning,
+                format!("unknown table `{}`", table),
+            ));
+        }
+    }
+
+    // signature -> rule that is in effect, and whether it is one of our own
+    let mut seen: HashMap<String, (Rule, bool)> = HashMap::new();
+    let mut includes = includes.iter().peekable();
+
+    static FIRST_KEY: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^\$(?:\.([^.\[]+)|\['([^']+)'\])").expect("Failed to compile regex")
+    });
+    for (position, parsed) in rules.iter().enumerate() {
+        while let Some(include) = includes.next_if(|include| include.position == position) {
+            include_into(&mut seen, &mut diagnostics, include);
+        }
+
+        let sign_str = parsed.key();
+        let signature = parsed.signature_str();
+        match seen.insert(sign_str, (parsed.rule.clone(), true)) {
+            Some((previous, true)) => diagnostics.push(diagnostic(
+                parsed.rule.line,
+                1,
+                Severity::Warning,
+                format!(
+                    "duplicate signature `{}` overrides the rule in line {}",
+                    signature, previous.line
+                ),
+            )),
+            Some((inherited, false)) if !parsed.is_override => diagnostics.push(diagnostic(
+                parsed.rule.line,
+                1,
+                Severity::Warning,
+                format!(
+                    "`{}` replaces the rule in {}, mark it with `@override` if that is intended",
+                    signature,
+                    inherited.location()
+                ),
+            )),
+            None if parsed.is_override => diagnostics.push(diagnostic(
+                parsed.rule.line,
+                1,
+                Severity::Warning,
+                format!(
+                    "`@override`, but there is no rule for `{}` to override",
+                    signature
+                ),
+            )),
+            _ => {}
+        }
+
+        if let Some(guard) = &parsed.rule.guard {
+            let line = mapping[parsed.rule.line - 1].trim();
+            let guard_offset = match parse_signature(line) {
+                Ok((_, Some(guard_str))) => line.len() - guard_str.len(),
+                _ => 0,
+            };
+            for condition in &guard.conditions {
+                let key = match condition.path.strip_prefix('%') {
+                    Some(expression) => FhirPath::parse(expression)
+                        .ok()
+                        .and_then(|fhir_path| fhir_path.first_key().map(|key| key.to_string())),
+                    None => FIRST_KEY.captures(&condition.path).map(|key_caps| {
+                        key_caps
+                            .get(1)
+                            .or(key_caps.get(2))
+                            .unwrap()
+                            .as_str()
+                            .to_string()
+                    }),
+                };
+                if let Some(key) = key
+                    && !parsed
+                        .signature
+                        .iter()
+                        .any(|k| matches_key(k, &key, &condition.path))
+                {
+                    diagnostics.push(diagnostic(
+                        parsed.rule.line,
+                        line[..guard_offset + condition.start].chars().count() + 1,
+                        Severity::Warning,
+                        format!(
+                            "`where` references `{}` which is not part of the signature",
+                            key
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let templates = match parsed.rule.fields.is_empty() {
+            true => vec![(parsed.format_line, parsed.rule.format.as_str())],
+            false => parsed
+                .rule
+                .fields
+                .iter()
+                .map(|field| (field.line, field.format.as_str()))
+                .collect(),
+        };
+        for (format_line, format) in templates {
+            // the template is the end of its line, e.g. after `@field key:`
+            let line = mapping[format_line - 1].trim_end();
+            let indent = line[..line.len() - format.len()].chars().count();
+            for caps in placeholder_regex().captures_iter(format) {
+                if let Some(table) = caps.name("table")
+                    && unknown_table(table.as_str())
+                {
+                    let column = indent + format[..table.start()].chars().count() + 1;
+                    diagnostics.push(diagnostic(
+                        format_line,
+                        column,
+                        Severity::Warning,
+                        format!("unknown table `{}`", table.as_str()),
+                    ));
+                }
+            }
+            let paths = match template_paths(format) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    diagnostics.push(diagnostic(format_line, indent + 1, Severity::Warning, e));
+                    continue;
+                }
+            };
+            for (path, start, relative) in paths {
+                let column = indent + format[..start].chars().count() + 1;
+                let key = match path.strip_prefix('%') {
+                    Some(expression) => match FhirPath::parse(expression) {
+                        Ok(fhir_path) => fhir_path.first_key().map(|key| key.to_string()),
+                        Err(e) => {
+                            diagnostics.push(diagnostic(
+                                format_line,
+                                column,
+                                Severity::Warning,
+                                format!("invalid FHIRPath `{}`: {}", expression, e),
+                            ));
+                            continue;
+                        }
+                    },
+                    None if jsonpath::Parser::compile(path).is_err() => {
+                        diagnostics.push(diagnostic(
+                            format_line,
+                            column,
+                            Severity::Warning,
+                            format!("invalid JSONPath `{}`", path),
+                        ));
+                        continue;
+                    }
+                    None => FIRST_KEY.captures(path).map(|key_caps| {
+                        let key = key_caps.get(1).or(key_caps.get(2)).unwrap().as_str();
+                        key.to_string()
+                    }),
+                };
+                // placeholders in `{#each}` blocks are relative to the items
+                if let Some(key) = key
+                    && relative
+                    && key != "*"
+                    && !parsed.signature.iter().any(|k| matches_key(k, &key, path))
+                {
+                    diagnostics.push(diagnostic(
+                        format_line,
+                        column,
+                        Severity::Warning,
+                        format!(
+                            "`{}` references `{}` which is not part of the signature",
+                            path, key
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    for include in includes {
+        include_into(&mut seen, &mut diagnostics, include);
+    }
+
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+/**
+ * Paths of a template with the offset of their tag, and whether they are relative to the current
+ * node rather than to an item of a `{#each}` block.
+ */
+fn template_paths(template: &str) -> Result<Vec<(&str, usize, bool)>, String> {
+    let blocks = each_blocks(template)?;
+    let in_body = |offset: usize| {
+        blocks
+            .iter()
+            .any(|b| offset >= b.body_start && offset < b.body_start + b.body.len())
+    };
+    let mut paths = Vec::new();
+    for caps in placeholder_regex().captures_iter(template) {
+        let start = caps.get(0).unwrap().start();
+        paths.push((caps.name("path").unwrap().as_str(), start, !in_body(start)));
+    }
+    for caps in each_regex().captures_iter(template) {
+        if let Some(path) = caps.get(1) {
+            let start = caps.get(0).unwrap().start();
+            paths.push((path.as_str(), start, !in_body(start)));
+        }
+    }
+    paths.sort_by_key(|(_, start, _)| *start);
+    Ok(paths)
+}
+
+/**
+ * FHIRPath navigates choice types by their base name, e.g. `value` for `valueQuantity`.
+ */
+fn matches_key(signature_key: &str, key: &str, path: &str) -> bool {
+    signature_key == key || (path.starts_with('%') && fhirpath::is_choice_of(signature_key, key))
+}
+
+/**
+ * Outcome of a `@test` line. `rule` is the rule that matched the test input, if any.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub test: MappingTest,
+    pub rule: Option<Rule>,
+    pub actual: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.rule.is_some() && self.actual == self.test.expected
+    }
+}
+
+/**
+ * Run the `@test` examples of a mapping: look up the rule for the signature of each test input
+ * and compare the output of `apply_rule` with the expected output.
+ */
+pub fn test_mapping(mapping: &[String]) -> Result<Vec<TestResult>, std::io::Error> {
+    let ProcessedMapping { rules, tables, .. } = process_mapping(mapping)?;
+    let tests = parse_rules(mapping).tests;
+    Ok(tests
+        .into_iter()
+        .map(|test| {
+            let keys = test
+                .input
+                .as_object()
+                .map(|obj| obj.keys().cloned().collect::<Vec<String>>())
+                .unwrap_or_default();
+            match rules
+                .get(&signature_to_str(keys))
+                .and_then(|rule| rule.select(&test.input))
+            {
+                Some(rule) => TestResult {
+                    actual: value_to_text(&apply_rule(
+                        &test.input,
+                        rule,
+                        &RenderContext::new(&rules, &tables),
+                    )),
+                    rule: Some(rule.clone()),
+                    test,
+                },
+                None => TestResult {
+                    actual: String::new(),
+                    rule: None,
+                    test,
+                },
+            }
+        })
+        .collect())
 }
 
 pub fn load_mapping_from_file(path: &Path) -> io::Result<Vec<String>> {
-    let file = File::open(path)?;
-    let reader = io::BufReader::new(file);
-    let filtered_lines = reader
-        .lines()
-        .filter_map(Result::ok)
-        .filter(|line| !line.trim_start().starts_with("//")) // remove comments
-        .collect::<Vec<String>>();
-    return Ok(filtered_lines);
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    let lines = if structured::is_structured(path) {
+        structured::load_structured(&std::fs::read_to_string(path)?)?
+    } else {
+        let file = File::open(path)?;
+        let reader = io::BufReader::new(file);
+        reader
+            .lines()
+            .map_while(Result::ok)
+            .collect::<Vec<String>>()
+    };
+    Ok(lines
+        .into_iter()
+        .map(|line| resolve_include_path(line, base_dir))
+        .collect())
+}
+
+/**
+ * Make the path of an `@include` line relative to the directory of the including file.
+ */
+fn resolve_include_path(line: String, base_dir: &Path) -> String {
+    match line
+        .trim()
+        .strip_prefix("@include ")
+        .map(|target| target.trim())
+    {
+        Some(target) if target != "default" && Path::new(target).is_relative() => {
+            format!("@include {}", base_dir.join(target).display())
+        }
+        _ => line,
+    }
 }
 
 pub fn load_mapping_from_str(mapping_str: &str) -> io::Result<Vec<String>> {
-    let filtered_lines = mapping_str
+    let lines = mapping_str
         .lines()
-        .filter(|line| !line.trim_start().starts_with("//")) // remove comments
-        .collect::<Vec<&str>>()
-        .iter()
-        .map(|&line| line.to_owned())
-        .collect();
-    return Ok(filtered_lines);
+        .map(|line| line.to_owned())
+        .collect::<Vec<String>>();
+    Ok(lines)
 }
 
 pub fn default_mapping() -> &'static str {
     include_str!("../../resources/mapping.hfc")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_process_mapping_skips_blank_lines() {
+        let mapping =
+            load_mapping_from_str("#[start, end]\n\n{$.start} - {$.end}\n\n#[low]\n{$.low}")
+                .unwrap();
+        let rules = process_mapping(&mapping).unwrap().rules;
+        assert_eq!(rules["end|start"].format, "{$.start} - {$.end}");
+        assert_eq!(rules["low"].line, 5);
+    }
+
+    #[test]
+    fn test_templates_starting_with_at() {
+        let mapping = load_mapping_from_str("#[handle]\n@{$.handle}\n@handle x").unwrap();
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(diagnostics, vec!["3:1: error: unknown directive `@handle`"]);
+        let rules = process_mapping(&mapping[..2]).unwrap().rules;
+        assert_eq!(rules["handle"].format, "@{$.handle}");
+    }
+
+    #[test]
+    fn test_validate_mapping() {
+        let mapping = load_mapping_from_str(
+            "#[system, value]\n{$.system}\n#[value, system]\n{$.system} | {$.code}\n#[a, b\n{$.a}\n#[c]",
+        )
+        .unwrap();
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "3:1: warning: duplicate signature `#[value, system]` overrides the rule in line 1",
+                "4:14: warning: `$.code` references `code` which is not part of the signature",
+                "5:1: error: malformed signature `#[a, b`, expected `#[key, ...]`",
+                "7:5: error: signature without template",
+            ]
+        );
+        assert!(process_mapping(&mapping).is_err());
+    }
+
+    #[test]
+    fn test_default_mapping_tests() {
+        let mapping = load_default_mapping().unwrap();
+        let results = test_mapping(&mapping).unwrap();
+        assert!(!results.is_empty());
+        for result in results {
+            assert!(result.passed(), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn test_include_default_mapping() {
+        let mapping = load_mapping_from_str(
+            "@include default\n@override\n#[start, end]\n{$.start} to {$.end}",
+        )
+        .unwrap();
+        let rules = process_mapping(&mapping).unwrap().rules;
+        assert_eq!(rules["end|start"].format, "{$.start} to {$.end}");
+        assert_eq!(rules["end|start"].location(), "line 3");
+        assert_eq!(rules["code|system"].source, Some("default".to_string()));
+        assert!(validate_mapping(&mapping).is_empty());
+
+        // replacing an inherited rule needs to be explicit
+        let base = std::env::temp_dir().join(format!("hff-include-{}.hfc", std::process::id()));
+        std::fs::write(&base, "// Period\n#[start, end]\n{$.start} - {$.end}\n").unwrap();
+        let mapping = load_mapping_from_str(&format!(
+            "@include {}\n#[start, end]\n{{$.start}} to {{$.end}}",
+            base.display()
+        ))
+        .unwrap();
+        let diagnostics = validate_mapping(&mapping);
+        std::fs::remove_file(&base).unwrap();
+        assert_eq!(
+            diagnostics[0].message,
+            format!(
+                "`#[start, end]` replaces the rule in {} line 2, mark it with `@override` if that is intended",
+                base.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_validate_rename_collisions() {
+        let mapping = load_mapping_from_str(
+            "@rename code.text -> label\n@rename effectiveDateTime -> effective\n@rename effectivePeriod -> effective\n@rename code.display -> label",
+        )
+        .unwrap();
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "4:1: warning: `code.display` and `code.text` in line 1 are both renamed to `label`, objects with both keep their names"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iteration_and_join() {
+        let address =
+            json!({ "line": ["Main St 1", "Apt 2"], "city": "Springfield", "postalCode": 12345 });
+        assert_eq!(
+            apply_format(
+                &address,
+                r#"{$.line[*] join ", "}, {$.postalCode} {$.city}"#
+            ),
+            "Main St 1, Apt 2, 12345 Springfield"
+        );
+        let contact = json!({
+            "telecom": [
+                { "system": "phone", "value": "555-1234" },
+                { "system": "email", "value": "jane@example.org" }
+            ],
+            "scores": [1, true, "x"]
+        });
+        assert_eq!(
+            apply_format(
+                &contact,
+                r#"{#each $.telecom join "; "}{$.system}:{$.value}{/each} | {%scores join "/"} | {$.scores}"#
+            ),
+            "phone:555-1234; email:jane@example.org | 1/true/x | 1 true x"
+        );
+
+        let mapping = load_mapping_from_str(
+            "#[telecom]\n{#each $.telecom}{$.value}{/each}\n#[line]\n{#each $.line}{$.x}",
+        )
+        .unwrap();
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            diagnostics,
+            vec!["4:1: warning: `{#each ...}` without `{/each}`"]
+        );
+    }
+
+    #[test]
+    fn test_validate_structured_rules() {
+        let mapping = load_mapping_from_str(
+            r#"#[line, city, postalCode]
+@field line: {$.line join ", "}
+@field city: {$.postalCode} {$.city}
+@field country: {$.country}
+@test {"line": ["Main St 1"], "city": "Berlin", "postalCode": "12345"} => {"line":"Main St 1","city":"12345 Berlin"}
+#[frequency, period, periodUnit]
+@item {$.frequency}x
+@item every {$.period}{$.periodUnit}"#,
+        )
+        .unwrap();
+        assert!(test_mapping(&mapping).unwrap()[0].passed());
+
+        let mapping =
+            load_mapping_from_str("#[a]\n@field a: {$.a}\n@item {$.a}\n@item {$.b}").unwrap();
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "1:1: error: a rule has either `@field` or `@item` lines, not both",
+                "4:7: warning: `$.b` references `b` which is not part of the signature",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_lookup_tables() {
+        let mapping = load_mapping_from_str(
+            r#"@include default
+@table gender { male: ♂ male, female: ♀ female }
+@table local { "X-1": Blood pressure }
+@lookup Patient.gender -> gender
+@override
+#[system, code]
+{$.code lookup local} ({$.system})
+@test {"system": "urn:local", "code": "X-1"} => Blood pressure (urn:local)
+#[display]
+{$.display lookup unknown}
+@lookup name -> nope"#,
+        )
+        .unwrap();
+        assert!(test_mapping(&mapping).unwrap()[0].passed());
+
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "10:19: warning: unknown table `unknown`",
+                "11:1: warning: unknown table `nope`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_rule_guards() {
+        let mapping = load_mapping_from_str(
+            r#"@include default
+#[system, code] where system = http://loinc.org
+LOINC {$.code}
+#[system, code] where system = "urn:local" and code != X-0
+local {$.code}
+#[family, given, use] where use = official
+{$.given} {$.family}
+#[family, given, use] where use != official
+{$.given} ({$.use})
+#[family, given, use] where status = active
+{$.family}
+#[a] where a = = x
+{$.a}"#,
+        )
+        .unwrap();
+        let diagnostics = validate_mapping(&mapping)
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "10:29: warning: `where` references `status` which is not part of the signature",
+                "12:12: error: expected `<path> = <value>` or `<path> != <value>` in `a = = x`",
+            ]
+        );
+
+        let mapping = &mapping[..mapping.len() - 2];
+        assert_eq!(validate_mapping(mapping).len(), 1);
+    }
+}
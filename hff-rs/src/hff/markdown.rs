@@ -0,0 +1,288 @@
+use crate::hff::table;
+
+/**
+ * Render a reformatted tree (see `reformat`) as Markdown: a heading per resource, nested lists for
+ * objects and tables for homogeneous arrays, i.e. arrays of objects with values that fit into a
+ * cell like `component` or `identifier`. Resources nested in the tree (Bundle entries, contained or resolved
+ * resources) get their own section after the one of their parent, one heading level deeper.
+ */
+pub fn to_markdown(tree: &serde_json::Value) -> String {
+    let mut sections = Vec::new();
+    section(tree, 1, &mut sections);
+    sections.join("\n")
+}
+
+fn section(v: &serde_json::Value, level: usize, sections: &mut Vec<String>) {
+    let mut out = String::new();
+    // resources found in this one, they become sections of their own
+    let mut nested = Vec::new();
+    let title = resource_title(v);
+    if let Some(title) = &title {
+        out.push_str(&format!(
+            "{} {}\n\n",
+            "#".repeat(level.min(6)),
+            escape(title)
+        ));
+    }
+    match v {
+        serde_json::Value::Object(map) => {
+            for (key, v2) in map {
+                // already part of the heading
+                if title.is_some() && (key == "resourceType" || key == "id") {
+                    continue;
+                }
+                item(&mut out, 0, Some(key), v2, &mut nested);
+            }
+        }
+        _ => item(&mut out, 0, None, v, &mut nested),
+    }
+    sections.push(out);
+    for resource in nested {
+        section(&resource, level + 1, sections);
+    }
+}
+
+/**
+ * `Patient/123` for a resource, or just its type if it has no id.
+ */
+fn resource_title(v: &serde_json::Value) -> Option<String> {
+    let resource_type = v.get("resourceType")?.as_str()?;
+    match v.get("id").and_then(|id| id.as_str()) {
+        Some(id) => Some(format!("{}/{}", resource_type, id)),
+        None => Some(resource_type.to_string()),
+    }
+}
+
+fn item(
+    out: &mut String,
+    depth: usize,
+    key: Option<&str>,
+    v: &serde_json::Value,
+    nested: &mut Vec<serde_json::Value>,
+) {
+    let indent = "  ".repeat(depth);
+    let label = key
+        .map(|key| format!("**{}**:", escape(key)))
+        .unwrap_or_default();
+    match v {
+        serde_json::Value::Object(_) if let Some(title) = resource_title(v) => {
+            nested.push(v.clone());
+            out.push_str(&format!(
+                "{}- {} *{} (see below)*\n",
+                indent,
+                label,
+                escape(&title)
+            ));
+        }
+        serde_json::Value::Object(map) => {
+            out.push_str(&format!("{}- {}\n", indent, label));
+            for (key, v2) in map {
+                item(out, depth + 1, Some(key), v2, nested);
+            }
+        }
+        serde_json::Value::Array(arr) if is_table(arr) => {
+            out.push_str(&format!("{}- {}\n\n", indent, label));
+            out.push_str(&table(arr, &format!("{}  ", indent)));
+            out.push('\n');
+        }
+        serde_json::Value::Array(arr) => {
+            out.push_str(&format!("{}- {}\n", indent, label));
+            for v2 in arr {
+                match v2 {
+                    // the fields of an element are a list in its list item
+                    serde_json::Value::Object(map) if resource_title(v2).is_none() => {
+                        let mut fields = String::new();
+                        for (key, v3) in map {
+                            item(&mut fields, depth + 2, Some(key), v3, nested);
+                        }
+                        let inner_indent = "  ".repeat(depth + 2);
+                        match fields.strip_prefix(inner_indent.as_str()) {
+                            Some(fields) => out.push_str(&format!("{}  - {}", indent, fields)),
+                            None => out.push_str(&fields),
+                        }
+                    }
+                    _ => item(out, depth + 1, None, v2, nested),
+                }
+            }
+        }
+        // multi-line text, e.g. a narrative rendered as Markdown, is kept as it is
+        serde_json::Value::String(s) if s.contains('\n') => {
+            out.push_str(&format!("{}- {}\n\n", indent, label));
+            for line in s.lines() {
+                match line.is_empty() {
+                    true => out.push('\n'),
+                    false => out.push_str(&format!("{}  {}\n", indent, line)),
+                }
+            }
+            out.push('\n');
+        }
+        _ => {
+            let separator = if label.is_empty() { "" } else { " " };
+            out.push_str(&format!(
+                "{}- {}{}{}\n",
+                indent,
+                label,
+                separator,
+                escape(&scalar(v))
+            ));
+        }
+    }
+}
+
+/**
+ * Arrays of objects with values that fit into a cell, e.g. `component` or `identifier`.
+ */
+fn is_table(arr: &[serde_json::Value]) -> bool {
+    !arr.is_empty()
+        && arr.iter().all(|v| {
+            v.as_object()
+                .is_some_and(|map| !map.contains_key("resourceType") && map.values().all(is_cell))
+        })
+}
+
+/**
+ * Scalars, and objects with a single key like the `coding` of a CodeableConcept, which
+ * `table::cell_text` reduces to their value.
+ */
+fn is_cell(v: &serde_json::Value) -> bool {
+    match v {
+        serde_json::Value::Object(map) => map.len() == 1 && map.values().all(is_cell),
+        serde_json::Value::Array(_) => false,
+        _ => true,
+    }
+}
+
+fn table(arr: &[serde_json::Value], indent: &str) -> String {
+    // columns in the order they first appear
+    let mut columns: Vec<&str> = Vec::new();
+    for map in arr.iter().filter_map(|v| v.as_object()) {
+        for key in map.keys() {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+    let cell = |s: &str| escape(s).replace('|', "\\|").replace('\n', " ");
+    let mut out = format!(
+        "{}| {} |\n{}|{}\n",
+        indent,
+        columns
+            .iter()
+            .map(|c| cell(c))
+            .collect::<Vec<String>>()
+            .join(" | "),
+        indent,
+        " --- |".repeat(columns.len())
+    );
+    for v in arr {
+        let cells = columns
+            .iter()
+            .map(|column| {
+                v.get(column)
+                    .map(|v2| cell(&table::cell_text(v2)))
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<String>>();
+        out.push_str(&format!("{}| {} |\n", indent, cells.join(" | ")));
+    }
+    out
+}
+
+fn scalar(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s.clone(),
+        _ => v.to_string(),
+    }
+}
+
+/**
+ * Escape the characters that Markdown would read as emphasis, code, links or HTML.
+ */
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_markdown() {
+        let tree = json!({
+            "resourceType": "Bundle",
+            "id": "b1",
+            "type": "collection",
+            "entry": [{
+                "fullUrl": "urn:uuid:1",
+                "resource": {
+                    "resourceType": "Observation",
+                    "code": { "coding": "http://loinc.org | 85354-9 | Blood pressure" },
+                    "component": [
+                        { "code": "8480-6", "value": "120 mm[Hg]" },
+                        { "code": "8462-4", "value": "80 mm[Hg]", "note": "a | b" }
+                    ],
+                    "category": ["vital-signs", "exam"],
+                    "text": { "div": "Blood *pressure*\n\n- measured" }
+                }
+            }]
+        });
+        assert_eq!(
+            to_markdown(&tree),
+            "# Bundle/b1\n\n\
+             - **type**: collection\n\
+             - **entry**:\n  \
+             - - **fullUrl**: urn:uuid:1\n    \
+             - **resource**: *Observation (see below)*\n\
+             \n\
+             ## Observation\n\n\
+             - **code**:\n  \
+             - **coding**: http://loinc.org | 85354-9 | Blood pressure\n\
+             - **component**:\n\n  \
+             | code | value | note |\n  \
+             | --- | --- | --- |\n  \
+             | 8480-6 | 120 mm\\[Hg\\] |  |\n  \
+             | 8462-4 | 80 mm\\[Hg\\] | a \\| b |\n\n\
+             - **category**:\n  \
+             - vital-signs\n  \
+             - exam\n\
+             - **text**:\n  \
+             - **div**:\n\n    \
+             Blood *pressure*\n\n    \
+             - measured\n\n"
+        );
+    }
+
+    #[test]
+    fn test_table_with_nested_values() {
+        let tree = json!({
+            "resourceType": "Observation",
+            "component": [
+                {
+                    "code": { "coding": "http://loinc.org | 8480-6 | Systolic blood pressure" },
+                    "valueQuantity": "120 mm[Hg]"
+                },
+                {
+                    "code": { "coding": "http://loinc.org | 8462-4 | Diastolic blood pressure" },
+                    "valueQuantity": "80 mm[Hg]"
+                }
+            ]
+        });
+        assert_eq!(
+            to_markdown(&tree),
+            "# Observation\n\n\
+             - **component**:\n\n  \
+             | code | valueQuantity |\n  \
+             | --- | --- |\n  \
+             | http://loinc.org \\| 8480-6 \\| Systolic blood pressure | 120 mm\\[Hg\\] |\n  \
+             | http://loinc.org \\| 8462-4 \\| Diastolic blood pressure | 80 mm\\[Hg\\] |\n\n"
+        );
+    }
+}
@@ -1,73 +1,243 @@
-use serde_json;
+use std::borrow::Cow;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+pub mod coverage;
+pub mod diff;
+pub mod fhirpath;
+pub mod fsh;
+pub mod highlight;
+pub mod html;
+pub mod keys;
 pub mod mapping;
+pub mod markdown;
+pub mod narrative;
 mod reformatting;
+pub mod resolve;
+pub mod structured;
+pub mod table;
+pub mod terminology;
+pub mod viewdef;
+pub mod yaml;
 
-pub struct HuffBuilder {}
+/**
+ * Rendering options shared by all builders.
+ */
+#[derive(Clone)]
+struct HuffOptions {
+    resolve_depth: usize,
+    resources: Option<Arc<resolve::DirectoryResolver>>,
+    explain: bool,
+    // shared by the clones of the options, terminologies can be large
+    terminology: Arc<terminology::Terminology>,
+    narrative: narrative::Narrative,
+    line_width: usize,
+}
+
+impl Default for HuffOptions {
+    fn default() -> Self {
+        HuffOptions {
+            resolve_depth: 0,
+            resources: None,
+            explain: false,
+            terminology: Arc::default(),
+            narrative: narrative::Narrative::default(),
+            line_width: yaml::DEFAULT_WIDTH,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HuffBuilder {
+    options: HuffOptions,
+}
 #[allow(dead_code)]
 impl HuffBuilder {
+    /**
+     * Follow `Reference(...)` targets found in the input (e.g. in a Bundle) up to `depth` levels
+     * and render them inline. The default of 0 leaves references untouched.
+     */
+    pub fn with_resolve_depth(mut self, depth: usize) -> HuffBuilder {
+        self.options.resolve_depth = depth;
+        self
+    }
+    /**
+     * Also resolve references against the resources of a local directory, e.g. for a standalone
+     * resource that is not part of a Bundle. Only used with a resolve depth above 0.
+     */
+    pub fn with_resources(mut self, resources: resolve::DirectoryResolver) -> HuffBuilder {
+        self.options.resources = Some(Arc::new(resources));
+        self
+    }
+    /**
+     * Annotate every object with its computed signature and the line of the mapping rule
+     * that was applied to it. Objects that no rule matched are flagged with `@rule: none`.
+     */
+    pub fn with_explain(mut self, explain: bool) -> HuffBuilder {
+        self.options.explain = explain;
+        self
+    }
+    /**
+     * Fill in missing `display` values of Codings from local CodeSystems, ValueSets or code tables.
+     * Filled in displays are marked with a leading `≈`.
+     */
+    pub fn with_terminology(mut self, terminology: terminology::Terminology) -> HuffBuilder {
+        self.options.terminology = Arc::new(terminology);
+        self
+    }
+    /**
+     * Convert the XHTML narrative (`text.div`) of resources to plain text or Markdown, or drop it
+     * from resources that have structured data. The default keeps it as it is.
+     */
+    pub fn with_narrative(mut self, narrative: narrative::Narrative) -> HuffBuilder {
+        self.options.narrative = narrative;
+        self
+    }
+    /**
+     * Fold long strings of the YAML output to keep lines within `width` characters, 0 for no limit.
+     * Also decides which short lists fit on one line in flow style, see `yaml::to_yaml`.
+     */
+    pub fn with_line_width(mut self, width: usize) -> HuffBuilder {
+        self.options.line_width = width;
+        self
+    }
     pub fn with_file(&self, mapping_file: &Path) -> HuffBuilderFromMappingFile {
         HuffBuilderFromMappingFile {
             mapping_file: mapping_file.to_path_buf(),
+            options: self.options.clone(),
         }
     }
     pub fn with_string(&self, mapping_str: &str) -> HuffBuilderFromMappingString {
         HuffBuilderFromMappingString {
             mapping_str: mapping_str.to_string(),
+            options: self.options.clone(),
         }
     }
     pub fn run(&self, fhir_obj: &serde_json::Value) -> Result<String, Box<dyn std::error::Error>> {
         // load custom mappers from file
         let mapping = mapping::load_default_mapping()?;
-        let _formatters = mapping::process_mapping(&mapping)?;
-        return reformatting::json_to_huff(fhir_obj, &_formatters);
+        render(fhir_obj, &mapping, &self.options)
+    }
+    /**
+     * Like `run`, but returns the reformatted tree instead of the YAML string.
+     */
+    pub fn reformat(
+        &self,
+        fhir_obj: &serde_json::Value,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mapping = mapping::load_default_mapping()?;
+        reformat_tree(fhir_obj, &mapping, &self.options)
     }
 }
 
 #[allow(dead_code)]
 pub struct HuffBuilderFromMappingFile {
     mapping_file: PathBuf,
+    options: HuffOptions,
 }
 #[allow(dead_code)]
 impl HuffBuilderFromMappingFile {
     pub fn run(&self, fhir_obj: &serde_json::Value) -> Result<String, Box<dyn std::error::Error>> {
         // load custom mappers from file
         let mapping = mapping::load_mapping_from_file(self.mapping_file.as_path())?;
-        let _formatters = mapping::process_mapping(&mapping)?;
-        return reformatting::json_to_huff(fhir_obj, &_formatters);
+        render(fhir_obj, &mapping, &self.options)
+    }
+    /**
+     * Like `run`, but returns the reformatted tree instead of the YAML string.
+     */
+    pub fn reformat(
+        &self,
+        fhir_obj: &serde_json::Value,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mapping = mapping::load_mapping_from_file(self.mapping_file.as_path())?;
+        reformat_tree(fhir_obj, &mapping, &self.options)
     }
 }
 
 #[allow(dead_code)]
 pub struct HuffBuilderFromMappingString {
     mapping_str: String,
+    options: HuffOptions,
 }
 #[allow(dead_code)]
 impl HuffBuilderFromMappingString {
     pub fn run(&self, fhir_obj: &serde_json::Value) -> Result<String, Box<dyn std::error::Error>> {
         // load custom mappers from file
         let mapping = mapping::load_mapping_from_str(self.mapping_str.as_str())?;
-        let _formatters = mapping::process_mapping(&mapping)?;
-        return reformatting::json_to_huff(fhir_obj, &_formatters);
+        render(fhir_obj, &mapping, &self.options)
+    }
+    /**
+     * Like `run`, but returns the reformatted tree instead of the YAML string.
+     */
+    pub fn reformat(
+        &self,
+        fhir_obj: &serde_json::Value,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mapping = mapping::load_mapping_from_str(self.mapping_str.as_str())?;
+        reformat_tree(fhir_obj, &mapping, &self.options)
     }
 }
 
 pub fn builder() -> HuffBuilder {
-    HuffBuilder {}
+    HuffBuilder {
+        options: HuffOptions::default(),
+    }
+}
+
+fn render(
+    fhir_obj: &serde_json::Value,
+    mapping: &[String],
+    options: &HuffOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let reformatted_obj = reformat_tree(fhir_obj, mapping, options)?;
+    Ok(yaml::to_yaml(&reformatted_obj, options.line_width))
+}
+
+fn reformat_tree(
+    fhir_obj: &serde_json::Value,
+    mapping: &[String],
+    options: &HuffOptions,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let processed = mapping::process_mapping(mapping)?;
+    let ctx = reformatting::Context {
+        formatters: &processed.rules,
+        keys: &processed.keys,
+        tables: &processed.tables,
+        explain: options.explain,
+    };
+    // passes over the input that run before the mapping, each only if it is switched on
+    let mut fhir_obj = Cow::Borrowed(fhir_obj);
+    if options.resolve_depth > 0 {
+        let mut resolver =
+            resolve::ChainResolver::default().with(resolve::BundleResolver::new(&fhir_obj));
+        if let Some(resources) = &options.resources {
+            resolver = resolver.with(resources.as_ref());
+        }
+        let resolved = resolve::resolve_references(&fhir_obj, options.resolve_depth, &mut resolver);
+        fhir_obj = Cow::Owned(resolved);
+    }
+    if !options.terminology.is_empty() {
+        fhir_obj = Cow::Owned(terminology::fill_displays(&fhir_obj, &options.terminology));
+    }
+    if options.narrative != narrative::Narrative::Keep {
+        fhir_obj = Cow::Owned(narrative::render_narratives(&fhir_obj, options.narrative));
+    }
+    reformatting::reformat_fhir(&fhir_obj, &ctx)
 }
 
 #[cfg(test)]
 mod tests {
     // importing names from outer (for mod tests) scope.
+    use super::mapping::*;
+    use super::reformatting::*;
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn test_read_mapping() {
-        let file_path = "../resources/mapping.hfc";
-        println!("Reading mapping file: {}", file_path);
-        let _ = process_mapping(&file_path);
-        //println!("{:?}", mapping);
+        let file_path = "resources/mapping.hfc";
+        let mapping = load_mapping_from_file(Path::new(file_path)).unwrap();
+        assert!(process_mapping(&mapping).is_ok());
     }
 
     #[test]
@@ -93,7 +263,7 @@ mod tests {
                     }
                     Err(e) => {
                         eprintln!("Error parsing JSON: {}", e);
-                        assert!(false);
+                        panic!();
                     }
                 }
                 assert_eq!(xjsonp_first(&v, "$..code"), "MR");
@@ -102,7 +272,7 @@ mod tests {
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -127,12 +297,12 @@ mod tests {
                 if let Some(_obj) = response.as_object() {
                     assert!(contains_objects_or_arrays(_obj));
                 } else {
-                    assert!(false);
+                    panic!();
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -155,12 +325,12 @@ mod tests {
                 if let Some(_obj) = response.as_object() {
                     assert!(contains_objects_or_arrays(_obj));
                 } else {
-                    assert!(false);
+                    panic!();
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -177,12 +347,12 @@ mod tests {
                 if let Some(_obj) = response.as_object() {
                     assert!(!contains_objects_or_arrays(_obj));
                 } else {
-                    assert!(false);
+                    panic!();
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -201,12 +371,12 @@ mod tests {
                         Some(json!("Anna-Maria Magdalena Luisa"))
                     );
                 } else {
-                    assert!(false);
+                    panic!();
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -222,12 +392,12 @@ mod tests {
                 if let Some(_obj) = response.as_object() {
                     assert_eq!(joined_arr(_obj, "given"), None);
                 } else {
-                    assert!(false);
+                    panic!();
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -247,12 +417,12 @@ mod tests {
                 if let Some(_obj) = response.as_object() {
                     assert_eq!(joined_arr(_obj, "given"), Some(json!("")));
                 } else {
-                    assert!(false);
+                    panic!();
                 }
             }
             Err(e) => {
                 eprintln!("Error parsing JSON: {}", e);
-                assert!(false);
+                panic!();
             }
         }
     }
@@ -0,0 +1,523 @@
+use regex::Regex;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/**
+ * What to do with the narrative (`text.div`) of resources.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Narrative {
+    // leave the XHTML as it is
+    #[default]
+    Keep,
+    // readable plain text, lists and tables are kept as lines
+    Text,
+    // Markdown with emphasis, links, lists and pipe tables
+    Markdown,
+    // drop the narrative of resources that have structured data
+    Drop,
+}
+
+impl FromStr for Narrative {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Narrative, String> {
+        match s {
+            "keep" => Ok(Narrative::Keep),
+            "text" => Ok(Narrative::Text),
+            "markdown" => Ok(Narrative::Markdown),
+            "drop" => Ok(Narrative::Drop),
+            _ => Err(format!(
+                "unknown narrative mode `{}`, expected keep, text, markdown or drop",
+                s
+            )),
+        }
+    }
+}
+
+/**
+ * Keys of a resource that are not structured data, a resource with nothing else only has its narrative.
+ */
+const NON_DATA_KEYS: [&str; 6] = [
+    "resourceType",
+    "id",
+    "meta",
+    "implicitRules",
+    "language",
+    "text",
+];
+
+/**
+ * Convert (or drop) the narrative of every resource in `v`, including contained and Bundle resources.
+ */
+pub fn render_narratives(v: &serde_json::Value, narrative: Narrative) -> serde_json::Value {
+    match v {
+        serde_json::Value::Object(map) => {
+            let is_resource = map.contains_key("resourceType");
+            let has_data = map.keys().any(|k| !NON_DATA_KEYS.contains(&k.as_str()));
+            let mut rendered = serde_json::Map::new();
+            for (k, v2) in map {
+                match (k.as_str(), v2.get("div").and_then(|div| div.as_str())) {
+                    ("text", Some(_))
+                        if is_resource && narrative == Narrative::Drop && has_data => {}
+                    ("text", Some(div))
+                        if is_resource
+                            && matches!(narrative, Narrative::Text | Narrative::Markdown) =>
+                    {
+                        let mut text = v2.as_object().cloned().unwrap_or_default();
+                        let markdown = narrative == Narrative::Markdown;
+                        text.insert("div".to_string(), xhtml_to_text(div, markdown).into());
+                        rendered.insert(k.clone(), serde_json::Value::Object(text));
+                    }
+                    _ => {
+                        rendered.insert(k.clone(), render_narratives(v2, narrative));
+                    }
+                }
+            }
+            serde_json::Value::Object(rendered)
+        }
+        serde_json::Value::Array(arr) => serde_json::Value::Array(
+            arr.iter()
+                .map(|v2| render_narratives(v2, narrative))
+                .collect(),
+        ),
+        _ => v.clone(),
+    }
+}
+
+/**
+ * A node of the (forgiving) XHTML tree of a narrative.
+ */
+#[derive(Debug)]
+enum Node {
+    Element {
+        name: String,
+        attributes: Vec<(String, String)>,
+        children: Vec<Node>,
+    },
+    Text(String),
+}
+
+impl Node {
+    fn attribute(&self, key: &str) -> Option<&str> {
+        match self {
+            Node::Element { attributes, .. } => attributes
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str()),
+            Node::Text(_) => None,
+        }
+    }
+}
+
+const VOID_ELEMENTS: [&str; 5] = ["br", "hr", "img", "col", "wbr"];
+
+/**
+ * Parse XHTML into a tree. Narratives are not always well-formed, so unclosed elements are closed
+ * by their parent and stray closing tags are ignored.
+ */
+fn parse(xhtml: &str) -> Vec<Node> {
+    static TAG: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?s)<!--.*?-->|<(/?)([A-Za-z][\w:-]*)([^>]*?)(/?)>").unwrap()
+    });
+    static ATTRIBUTE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"([\w:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+    // open elements, the root collects the top-level nodes
+    let mut stack = vec![Node::Element {
+        name: String::new(),
+        attributes: Vec::new(),
+        children: Vec::new(),
+    }];
+    fn children(stack: &mut [Node]) -> &mut Vec<Node> {
+        match stack.last_mut() {
+            Some(Node::Element { children, .. }) => children,
+            _ => unreachable!("only elements are open"),
+        }
+    }
+    let close = |stack: &mut Vec<Node>| {
+        let element = stack.pop().unwrap();
+        children(stack).push(element);
+    };
+    let mut offset = 0;
+    for caps in TAG.captures_iter(xhtml) {
+        let whole = caps.get(0).unwrap();
+        if whole.start() > offset {
+            let text = decode_entities(&xhtml[offset..whole.start()]);
+            children(&mut stack).push(Node::Text(text));
+        }
+        offset = whole.end();
+        let Some(name) = caps.get(2) else {
+            continue; // comment
+        };
+        let name = local_name(name.as_str()).to_ascii_lowercase();
+        if &caps[1] == "/" {
+            if let Some(open) = stack
+                .iter()
+                .rposition(|node| matches!(node, Node::Element { name: n, .. } if *n == name))
+                && open > 0
+            {
+                while stack.len() > open {
+                    close(&mut stack);
+                }
+            }
+            continue;
+        }
+        let attributes = ATTRIBUTE
+            .captures_iter(&caps[3])
+            .map(|a| {
+                let value = a.get(2).or(a.get(3)).unwrap().as_str();
+                (local_name(&a[1]).to_string(), decode_entities(value))
+            })
+            .collect();
+        let is_empty = &caps[4] == "/" || VOID_ELEMENTS.contains(&name.as_str());
+        stack.push(Node::Element {
+            name,
+            attributes,
+            children: Vec::new(),
+        });
+        if is_empty {
+            close(&mut stack);
+        }
+    }
+    if offset < xhtml.len() {
+        let text = decode_entities(&xhtml[offset..]);
+        children(&mut stack).push(Node::Text(text));
+    }
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    std::mem::take(children(&mut stack))
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn decode_entities(s: &str) -> String {
+    static ENTITY: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|[a-zA-Z]+);").unwrap());
+    ENTITY
+        .replace_all(s, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let code = match name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => name.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+            };
+            let decoded = match (code, name) {
+                (Some(code), _) => char::from_u32(code).map(String::from),
+                (None, "amp") => Some("&".to_string()),
+                (None, "lt") => Some("<".to_string()),
+                (None, "gt") => Some(">".to_string()),
+                (None, "quot") => Some("\"".to_string()),
+                (None, "apos") => Some("'".to_string()),
+                (None, "nbsp") => Some("\u{a0}".to_string()),
+                _ => None,
+            };
+            decoded.unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+/**
+ * Convert narrative XHTML to readable text, or to Markdown if `markdown` is set. Paragraphs and
+ * other blocks are separated by blank lines, lists become `- ` or `1. ` lines and tables one line
+ * per row (a pipe table in Markdown).
+ */
+pub fn xhtml_to_text(xhtml: &str, markdown: bool) -> String {
+    let mut renderer = Renderer {
+        markdown,
+        blocks: Vec::new(),
+        inline: String::new(),
+    };
+    for node in &parse(xhtml) {
+        renderer.block(node);
+    }
+    renderer.flush();
+    renderer.blocks.join("\n\n")
+}
+
+struct Renderer {
+    markdown: bool,
+    blocks: Vec<String>,
+    // inline content of the current paragraph
+    inline: String,
+}
+
+impl Renderer {
+    fn flush(&mut self) {
+        let paragraph = tidy(&std::mem::take(&mut self.inline));
+        if !paragraph.is_empty() {
+            self.blocks.push(paragraph);
+        }
+    }
+
+    fn push_block(&mut self, block: String) {
+        self.flush();
+        if !block.trim().is_empty() {
+            self.blocks.push(block);
+        }
+    }
+
+    fn block(&mut self, node: &Node) {
+        let Node::Element { name, children, .. } = node else {
+            self.inline.push_str(&self.inline_text(node));
+            return;
+        };
+        match name.as_str() {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let text = self.inline_text(node).replace('\n', " ");
+                let level = name[1..].parse::<usize>().unwrap_or(1);
+                match self.markdown {
+                    true => self.push_block(format!("{} {}", "#".repeat(level), tidy(&text))),
+                    false => self.push_block(tidy(&text)),
+                }
+            }
+            "ul" | "ol" => {
+                let lines = self.list_lines(node, 0);
+                self.push_block(lines.join("\n"));
+            }
+            "table" => {
+                let table = self.table(node);
+                self.push_block(table);
+            }
+            "pre" => {
+                let text = plain_text(node);
+                match self.markdown {
+                    true => self.push_block(format!("```\n{}\n```", text.trim_matches('\n'))),
+                    false => self.push_block(text.trim_matches('\n').to_string()),
+                }
+            }
+            "hr" => match self.markdown {
+                true => self.push_block("---".to_string()),
+                false => self.flush(),
+            },
+            "script" | "style" | "head" | "title" => {}
+            "div" | "p" | "section" | "article" | "blockquote" | "body" | "html" | "header"
+            | "footer" | "dl" | "dt" | "dd" => {
+                self.flush();
+                for child in children {
+                    self.block(child);
+                }
+                self.flush();
+            }
+            _ => self.inline.push_str(&self.inline_text(node)),
+        }
+    }
+
+    /**
+     * Inline content of a node, with Markdown markup for emphasis, code and links.
+     */
+    fn inline_text(&self, node: &Node) -> String {
+        let (name, children) = match node {
+            Node::Text(text) => return text.clone(),
+            Node::Element { name, children, .. } => (name.as_str(), children),
+        };
+        let content = || {
+            children
+                .iter()
+                .map(|child| self.inline_text(child))
+                .collect::<String>()
+        };
+        // the markup goes around the trimmed content, Markdown ignores `** x **`
+        let wrap = |mark: &str| {
+            let content = content();
+            let trimmed = content.trim();
+            match self.markdown && !trimmed.is_empty() {
+                true => {
+                    let before = &content[..content.len() - content.trim_start().len()];
+                    let after = &content[content.trim_end().len()..];
+                    format!("{}{}{}{}{}", before, mark, trimmed, mark, after)
+                }
+                false => content,
+            }
+        };
+        match name {
+            "br" => "\n".to_string(),
+            "b" | "strong" => wrap("**"),
+            "i" | "em" => wrap("*"),
+            "code" | "tt" => wrap("`"),
+            "a" => match (self.markdown, node.attribute("href")) {
+                (true, Some(href)) => format!("[{}]({})", tidy(&content()), href),
+                _ => content(),
+            },
+            "img" => node.attribute("alt").unwrap_or_default().to_string(),
+            "script" | "style" => String::new(),
+            // block content inside of inline content, e.g. a paragraph in a table cell
+            "p" | "div" | "li" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                format!(" {} ", content())
+            }
+            _ => content(),
+        }
+    }
+
+    fn list_lines(&self, list: &Node, depth: usize) -> Vec<String> {
+        let Node::Element { name, children, .. } = list else {
+            return Vec::new();
+        };
+        let mut lines = Vec::new();
+        let items = children
+            .iter()
+            .filter(|child| matches!(child, Node::Element { name, .. } if name == "li"));
+        for (index, item) in items.enumerate() {
+            let Node::Element { children, .. } = item else {
+                continue;
+            };
+            let is_list = |node: &&Node| matches!(node, Node::Element { name, .. } if name == "ul" || name == "ol");
+            let text = children
+                .iter()
+                .filter(|child| !is_list(child))
+                .map(|child| self.inline_text(child))
+                .collect::<String>();
+            let marker = match name.as_str() {
+                "ol" => format!("{}.", index + 1),
+                _ => "-".to_string(),
+            };
+            lines.push(format!(
+                "{}{} {}",
+                "  ".repeat(depth),
+                marker,
+                tidy(&text).replace('\n', " ")
+            ));
+            for nested in children.iter().filter(is_list) {
+                lines.extend(self.list_lines(nested, depth + 1));
+            }
+        }
+        lines
+    }
+
+    fn table(&self, table: &Node) -> String {
+        let mut rows = Vec::new();
+        collect_rows(table, &mut rows);
+        let rows = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| {
+                        let text = tidy(&self.inline_text(cell)).replace('\n', " ");
+                        match self.markdown {
+                            true => text.replace('|', "\\|"),
+                            false => text,
+                        }
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .filter(|row| !row.is_empty())
+            .collect::<Vec<Vec<String>>>();
+        if !self.markdown {
+            return rows
+                .iter()
+                .map(|row| row.join(" | "))
+                .collect::<Vec<String>>()
+                .join("\n");
+        }
+        let columns = rows.iter().map(|row| row.len()).max().unwrap_or_default();
+        let line = |cells: &[String]| {
+            let mut cells = cells.to_vec();
+            cells.resize(columns, String::new());
+            format!("| {} |", cells.join(" | "))
+        };
+        let mut lines = rows.iter().map(|row| line(row)).collect::<Vec<String>>();
+        if !lines.is_empty() {
+            lines.insert(1, line(&vec!["---".to_string(); columns]));
+        }
+        lines.join("\n")
+    }
+}
+
+/**
+ * The cells of the rows of a table, also those in `thead`, `tbody` and `tfoot`.
+ */
+fn collect_rows<'a>(node: &'a Node, rows: &mut Vec<Vec<&'a Node>>) {
+    let Node::Element { name, children, .. } = node else {
+        return;
+    };
+    match name.as_str() {
+        "tr" => rows.push(
+            children
+                .iter()
+                .filter(|child| {
+                    matches!(child, Node::Element { name, .. } if name == "td" || name == "th")
+                })
+                .collect(),
+        ),
+        // a nested table is rendered into its cell
+        "table" if !rows.is_empty() => {}
+        _ => children.iter().for_each(|child| collect_rows(child, rows)),
+    }
+}
+
+/**
+ * The text of a node as it is, for preformatted content.
+ */
+fn plain_text(node: &Node) -> String {
+    match node {
+        Node::Text(text) => text.clone(),
+        Node::Element { name, .. } if name == "br" => "\n".to_string(),
+        Node::Element { children, .. } => children.iter().map(plain_text).collect(),
+    }
+}
+
+/**
+ * Collapse whitespace within lines and drop empty lines at the start and end.
+ */
+fn tidy(s: &str) -> String {
+    s.split('\n')
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .collect::<Vec<String>>()
+        .join("\n")
+        .trim_matches('\n')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_xhtml_to_text() {
+        let div = r#"<div xmlns="http://www.w3.org/1999/xhtml">
+  <h3>Jane <b>Doe</b></h3>
+  <p>Born 1970 &amp; living in <a href="https://example.org">Berlin</a>.<br/>Second line</p>
+  <ul><li>one<ol><li>nested</li></ol></li><li>two</li></ul>
+  <table><thead><tr><th>Code</th><th>Value</th></tr></thead>
+  <tbody><tr><td>HR</td><td>72 /min</td></tr><tr><td>a|b</td></tr></tbody></table>
+  <p>unclosed <i>tags"#;
+        assert_eq!(
+            xhtml_to_text(div, false),
+            "Jane Doe\n\n\
+             Born 1970 & living in Berlin.\nSecond line\n\n\
+             - one\n  1. nested\n- two\n\n\
+             Code | Value\nHR | 72 /min\na|b\n\n\
+             unclosed tags"
+        );
+        assert_eq!(
+            xhtml_to_text(div, true),
+            "### Jane **Doe**\n\n\
+             Born 1970 & living in [Berlin](https://example.org).\nSecond line\n\n\
+             - one\n  1. nested\n- two\n\n\
+             | Code | Value |\n| --- | --- |\n| HR | 72 /min |\n| a\\|b |  |\n\n\
+             unclosed *tags*"
+        );
+
+        let patient = json!({
+            "resourceType": "Patient",
+            "text": { "status": "generated", "div": "<div><p>Jane</p></div>" },
+            "gender": "female",
+            "contained": [{ "resourceType": "Basic", "text": { "div": "<div>only text</div>" } }]
+        });
+        assert_eq!(
+            render_narratives(&patient, Narrative::Text)["text"],
+            json!({ "status": "generated", "div": "Jane" })
+        );
+        assert_eq!(
+            render_narratives(&patient, Narrative::Drop),
+            json!({
+                "resourceType": "Patient",
+                "gender": "female",
+                "contained": [{ "resourceType": "Basic", "text": { "div": "<div>only text</div>" } }]
+            })
+        );
+        assert_eq!(render_narratives(&patient, Narrative::Keep), patient);
+    }
+}
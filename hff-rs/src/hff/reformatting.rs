@@ -1,21 +1,179 @@
-use serde_json;
 use serde_json::json;
-use serde_yaml;
 use std::collections::HashMap;
 
+use crate::hff::fhirpath;
+use crate::hff::keys::KeyRules;
 use crate::hff::mapping;
 
-pub fn json_to_huff(
+/**
+ * Mapping rules and switches that are passed down the recursion.
+ */
+pub struct Context<'a> {
+    pub formatters: &'a HashMap<String, mapping::Rule>,
+    // `@rename`, `@hide` and `@lookup` rules
+    pub keys: &'a KeyRules,
+    pub tables: &'a HashMap<String, mapping::Table>,
+    // annotate objects with their signature and the rule that was applied
+    pub explain: bool,
+}
+
+/**
+ * Position of a node for key rules: the type of the nearest enclosing resource and the keys below it.
+ */
+#[derive(Clone, Default)]
+struct KeyPath<'a> {
+    resource_type: Option<&'a str>,
+    keys: Vec<&'a str>,
+}
+
+impl<'a> KeyPath<'a> {
+    fn child<'b>(&self, key: &'b str) -> KeyPath<'b>
+    where
+        'a: 'b,
+    {
+        let mut keys = self.keys.clone();
+        keys.push(key);
+        KeyPath {
+            resource_type: self.resource_type,
+            keys,
+        }
+    }
+
+    /**
+     * Path of the children of `obj`, resources start a new path.
+     */
+    fn enter<'b>(&self, obj: &'b serde_json::Map<String, serde_json::Value>) -> KeyPath<'b>
+    where
+        'a: 'b,
+    {
+        match obj.get("resourceType").and_then(|t| t.as_str()) {
+            Some(resource_type) => KeyPath {
+                resource_type: Some(resource_type),
+                keys: Vec::new(),
+            },
+            None => self.clone(),
+        }
+    }
+
+    /**
+     * Whether a `@hide` rule drops the key at the end of the path.
+     */
+    fn is_hidden(&self, ctx: &Context) -> bool {
+        ctx.keys.is_hidden(self.resource_type, &self.keys)
+    }
+
+    /**
+     * The label of a scalar value from the `@table` a `@lookup` rule names for this path.
+     */
+    fn lookup(&self, v: &serde_json::Value, ctx: &Context) -> serde_json::Value {
+        ctx.keys
+            .lookup_table(self.resource_type, &self.keys)
+            .and_then(|table| ctx.tables.get(table))
+            .zip(fhirpath::primitive_to_string(v))
+            .and_then(|(table, value)| table.get(&value))
+            .map(|label| json!(label))
+            .unwrap_or_else(|| v.clone())
+    }
+
+    /**
+     * The key at the end of the path, as a `@rename` rule names it in the output.
+     */
+    fn output_key<'c>(&'c self, ctx: &'c Context) -> &'c str {
+        let key = self.keys.last().copied().unwrap_or_default();
+        ctx.keys
+            .renamed(self.resource_type, &self.keys)
+            .unwrap_or(key)
+    }
+}
+
+/**
+ * The entries of an object without the keys hidden by `@hide` rules.
+ */
+fn visible_entries(
+    obj: &serde_json::Map<String, serde_json::Value>,
+    base: &KeyPath,
+    ctx: &Context,
+) -> serde_json::Map<String, serde_json::Value> {
+    obj.iter()
+        .filter(|(k, _)| !base.child(k).is_hidden(ctx))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/**
+ * Insert a reformatted entry of `obj` under its output key. A key renamed to a key the object
+ * already has (or that another key was renamed to) keeps its original name instead of
+ * overwriting it.
+ */
+fn insert_entry(
+    out: &mut serde_json::Map<String, serde_json::Value>,
+    obj: &serde_json::Map<String, serde_json::Value>,
+    path: &KeyPath,
+    value: serde_json::Value,
+    ctx: &Context,
+) {
+    let key = path.keys.last().copied().unwrap_or_default();
+    let renamed = path.output_key(ctx);
+    let output_key =
+        match renamed != key && (obj.contains_key(renamed) || out.contains_key(renamed)) {
+            true => key,
+            false => renamed,
+        };
+    out.insert(output_key.to_string(), value);
+}
+
+/**
+ * Reformat the entries of an object, with the keys renamed by `@rename` rules.
+ */
+fn reformat_entries(
+    obj: &serde_json::Map<String, serde_json::Value>,
+    base: &KeyPath,
+    ctx: &Context,
+    out: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (k, v) in obj {
+        let path = base.child(k);
+        let value = traverse_fhir(v, Some(k), &path, ctx)?;
+        insert_entry(out, obj, &path, value, ctx);
+    }
+    Ok(())
+}
+
+/**
+ * Reformat a FHIR object into the condensed HUFF tree without serializing it.
+ */
+pub fn reformat_fhir(
     fhir_obj: &serde_json::Value,
-    formatters: &HashMap<String, String>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    // reformat FHIR object
-    let reformatted_obj = traverse_fhir(fhir_obj, None, &formatters)?;
-    Ok(json_to_yaml(&reformatted_obj)?)
+    ctx: &Context,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    traverse_fhir(fhir_obj, None, &KeyPath::default(), ctx)
+}
+
+/**
+ * Check whether an object has any nested objects or arrays as direct children.
+ */
+#[allow(dead_code)]
+pub(crate) fn contains_objects_or_arrays(obj: &serde_json::Map<String, serde_json::Value>) -> bool {
+    obj.values().any(|v| v.is_object() || v.is_array())
 }
 
-fn json_to_yaml(obj: &serde_json::Value) -> Result<String, serde_yaml::Error> {
-    serde_yaml::to_string(obj)
+/**
+ * Join the string elements of the array under `key` with whitespace.
+ * Returns `None` if there is no array under `key`.
+ */
+#[allow(dead_code)]
+pub(crate) fn joined_arr(
+    obj: &serde_json::Map<String, serde_json::Value>,
+    key: &str,
+) -> Option<serde_json::Value> {
+    obj.get(key).and_then(|v| v.as_array()).map(|arr| {
+        json!(
+            arr.iter()
+                .filter_map(|v| v.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        )
+    })
 }
 
 /**
@@ -24,16 +182,17 @@ fn json_to_yaml(obj: &serde_json::Value) -> Result<String, serde_yaml::Error> {
 fn traverse_fhir(
     v: &serde_json::Value,
     k: Option<&str>,
-    _formatters: &HashMap<String, String>,
+    path: &KeyPath,
+    _ctx: &Context,
 ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
     match k {
         // First-pass of JSON structure
         None => {
             if let Some(obj) = v.as_object() {
-                let reformatted_obj: serde_json::Map<std::string::String, serde_json::Value> = obj
-                    .iter()
-                    .map(|(k, v)| Ok((k.clone(), traverse_fhir(v, Some(k), _formatters)?)))
-                    .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+                let base = path.enter(obj);
+                let mut reformatted_obj = serde_json::Map::new();
+                let visible = visible_entries(obj, &base, _ctx);
+                reformat_entries(&visible, &base, _ctx, &mut reformatted_obj)?;
                 Ok(serde_json::Value::Object(reformatted_obj))
             } else {
                 Err(format!("Expected dict, got {:?}", v).into())
@@ -42,13 +201,13 @@ fn traverse_fhir(
         Some(key) => {
             // object
             if v.is_object() {
-                reformat(v, key, _formatters)
+                reformat(v, key, path, _ctx)
             }
             // array
             else if let Some(arr) = v.as_array() {
                 let elements = arr
                     .iter()
-                    .map(|v2| traverse_fhir(v2, Some(key), _formatters))
+                    .map(|v2| traverse_fhir(v2, Some(key), path, _ctx))
                     .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
                 // unlist array if len==1
                 match elements.len() {
@@ -58,7 +217,7 @@ fn traverse_fhir(
             }
             // scalar
             else {
-                Ok(v.clone())
+                Ok(path.lookup(v, _ctx))
             }
         }
     }
@@ -70,56 +229,308 @@ fn traverse_fhir(
 fn reformat(
     _obj: &serde_json::Value,
     _key: &str,
-    _formatters: &HashMap<String, String>,
+    path: &KeyPath,
+    _ctx: &Context,
 ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let Some(_full_map) = _obj.as_object() else {
+        return Ok(_obj.clone());
+    };
+    // hidden keys are dropped before the signature is computed
+    let base = path.enter(_full_map);
+    let _visible;
+    let (_map, _obj) = if _full_map.keys().any(|k| base.child(k).is_hidden(_ctx)) {
+        _visible = serde_json::Value::Object(visible_entries(_full_map, &base, _ctx));
+        (_visible.as_object().unwrap(), &_visible)
+    } else {
+        (_full_map, _obj)
+    };
+
     // special case: Reference
     // Unnest the reference object and wrap into `Reference(...)` for better parseability.
-    if let Some(_map) = _obj.as_object() {
-        if let Some(_ref) = _map.get("reference") {
-            // if there is only the 'reference' key, we can just replace the object with the new value
-            if _map.keys().len() == 1 {
-                return Ok(json!(format!("Reference({})", _ref.as_str().unwrap())));
-            }
-            // in some cases, the 'reference' key is nested in a key named 'reference' :P
-            // https://hl7.org/fhir/R4/consent-definitions.html#Consent.provision.actor.reference
-            else if let Some(reference) = _ref.as_object().and_then(|obj| obj.get("reference")) {
-                let mut _new_map = _map.clone();
-                _new_map.insert(
-                    "reference".to_string(),
-                    json!(format!("Reference({})", reference.as_str().unwrap())),
-                );
-                return Ok(json!(_new_map));
-            }
-            // if there are other keys, we need to keep them and just modify the 'reference' key
-            else {
-                let mut _new_map = _map.clone();
-                _new_map.insert(
-                    "reference".to_string(),
-                    json!(format!("Reference({})", _ref.as_str().unwrap())),
-                );
-                return Ok(json!(_new_map));
-            }
+    if let Some(_ref) = _map.get("reference") {
+        // if there is only the 'reference' key, we can just replace the object with the new value
+        if _map.keys().len() == 1 && _ref.is_string() {
+            return Ok(json!(format!("Reference({})", _ref.as_str().unwrap())));
         }
+        // if there are other keys (e.g. `display` or a `resolved` target), we need to keep them
+        // (in their order) and pass them back to the recursion function, just the 'reference'
+        // key is modified
+        let mut _new_map = serde_json::Map::new();
+        for (k, v) in _map {
+            let path = base.child(k);
+            let value = match k.as_str() {
+                // in some cases, the 'reference' key is nested in a key named 'reference' :P
+                // https://hl7.org/fhir/R4/consent-definitions.html#Consent.provision.actor.reference
+                "reference" if v.is_object() => reformat(v, "reference", &path, _ctx)?,
+                "reference" => json!(format!(
+                    "Reference({})",
+                    fhirpath::primitive_to_string(v).unwrap_or_default()
+                )),
+                _ => traverse_fhir(v, Some(k), &path, _ctx)?,
+            };
+            insert_entry(&mut _new_map, _map, &path, value, _ctx);
+        }
+        return Ok(json!(_new_map));
+    }
 
-        // apply custom formatters if any
-        let _attr = _map.keys().map(|k| k.to_string()).collect::<Vec<String>>();
-        let _sign_str = mapping::signature_to_str(_attr);
+    // apply custom formatters if any
+    let _attr = _map.keys().map(|k| k.to_string()).collect::<Vec<String>>();
+    let _sign_str = mapping::signature_to_str(_attr);
 
-        match _formatters.get(&_sign_str) {
-            Some(format_str) => {
-                return Ok(json!(mapping::apply_format(_obj, format_str)));
-            }
-            None => {}
+    // guarded rules for the signature are checked against the values of the node
+    if let Some(rule) = _ctx
+        .formatters
+        .get(&_sign_str)
+        .and_then(|rule| rule.select(_obj))
+    {
+        let _formatted = mapping::apply_rule(
+            _obj,
+            rule,
+            &mapping::RenderContext::new(_ctx.formatters, _ctx.tables),
+        );
+        if _ctx.explain {
+            return Ok(json!({
+                "@signature": _sign_str,
+                "@rule": rule.location(),
+                "@value": _formatted,
+            }));
+        }
+        return Ok(_formatted);
+    };
+
+    // go deeper and pass subelements back to recursion function
+    let mut reformatted_obj = serde_json::Map::new();
+    if _ctx.explain {
+        // flag objects that no rule matched
+        reformatted_obj.insert("@signature".to_string(), json!(_sign_str));
+        reformatted_obj.insert("@rule".to_string(), json!("none"));
+    }
+    reformat_entries(_map, &base, _ctx, &mut reformatted_obj)?;
+
+    Ok(serde_json::Value::Object(reformatted_obj))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Reformat `v` with the rules of a mapping given as a string.
+     */
+    fn reformat_with(mapping: &str, explain: bool, v: &serde_json::Value) -> serde_json::Value {
+        let mapping = mapping::load_mapping_from_str(mapping).unwrap();
+        let processed = mapping::process_mapping(&mapping).unwrap();
+        let ctx = Context {
+            formatters: &processed.rules,
+            keys: &processed.keys,
+            tables: &processed.tables,
+            explain,
         };
+        reformat_fhir(v, &ctx).unwrap()
+    }
 
-        // go deeper and pass subelements back to recursion function
-        let reformatted_obj: serde_json::Map<std::string::String, serde_json::Value> = _map
-            .iter()
-            .map(|(k, v)| Ok((k.clone(), traverse_fhir(v, Some(k), _formatters)?)))
-            .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+    #[test]
+    fn test_explain() {
+        let mapping = "// Coding\n#[system, code]\n{$.system} | {$.code}";
+        let fhir_obj = json!({
+            "resourceType": "Observation",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4" }] }
+        });
 
-        return Ok(serde_json::Value::Object(reformatted_obj));
+        let explained = reformat_with(mapping, true, &fhir_obj);
+        assert_eq!(
+            explained["code"],
+            json!({
+                "@signature": "coding",
+                "@rule": "none",
+                "coding": {
+                    "@signature": "code|system",
+                    "@rule": "line 2",
+                    "@value": "http://loinc.org | 8867-4"
+                }
+            })
+        );
     }
 
-    return Ok(_obj.clone());
+    #[test]
+    fn test_render_placeholder() {
+        let mapping = "#[value, unit]\n{$.value} {$.unit}\n#[low, high]\n{@render $.low} - {@render %high}\n#[rec]\n<{@render %$this}>";
+        let fhir_obj = json!({
+            "resourceType": "Observation",
+            "valueRange": { "low": { "value": 1, "unit": "mg" }, "high": { "value": 2, "unit": "mg" } },
+            "note": { "rec": "x" }
+        });
+
+        let reformatted = reformat_with(mapping, false, &fhir_obj);
+        assert_eq!(reformatted["valueRange"], json!("1 mg - 2 mg"));
+        // self-rendering rules stop at a fixed depth
+        assert!(reformatted["note"].as_str().unwrap().starts_with("<<<"));
+    }
+
+    #[test]
+    fn test_typed_output() {
+        let mapping =
+            "#[value, unit]\n{$.value}\n#[flag]\n{%flag}\n#[value, code]\n{$.value} {$.code}";
+        let fhir_obj = json!({
+            "resourceType": "Observation",
+            "valueQuantity": { "value": 72.5, "unit": "kg" },
+            "extension": [{ "flag": true }, { "value": 1, "code": "x" }]
+        });
+
+        let reformatted = reformat_with(mapping, false, &fhir_obj);
+        assert_eq!(reformatted["valueQuantity"], json!(72.5));
+        assert_eq!(reformatted["extension"], json!([true, "1 x"]));
+        assert!(crate::hff::yaml::to_yaml(&reformatted, 0).contains("valueQuantity: 72.5\n"));
+    }
+
+    #[test]
+    fn test_structured_rules() {
+        let mapping = r#"#[line, city, postalCode]
+@field line: {$.line join ", "}
+@field city: {$.postalCode} {$.city}
+@field country: {$.country}
+#[frequency, period, periodUnit]
+@item {$.frequency}x
+@item every {$.period}{$.periodUnit}"#;
+        let fhir_obj = json!({
+            "resourceType": "Patient",
+            "address": { "line": ["Main St 1", "Apt 2"], "city": "Berlin", "postalCode": "12345" },
+            "timing": { "frequency": 1, "period": 8, "periodUnit": "h" }
+        });
+
+        let reformatted = reformat_with(mapping, false, &fhir_obj);
+        assert_eq!(
+            reformatted["address"],
+            json!({ "line": "Main St 1, Apt 2", "city": "12345 Berlin" })
+        );
+        assert_eq!(reformatted["timing"], json!(["1x", "every 8h"]));
+    }
+
+    #[test]
+    fn test_key_rules() {
+        let mapping = "@include default\n@hide meta.versionId\n@hide *.id\n@rename Observation.effectiveDateTime -> effective\n@rename valueQuantity -> value";
+        let fhir_obj = json!({
+            "resourceType": "Observation",
+            "id": "bp",
+            "meta": { "versionId": "3", "lastUpdated": "2024-01-01" },
+            "code": { "coding": [{ "id": "c1", "system": "http://loinc.org", "code": "8867-4" }] },
+            "effectiveDateTime": "2024-01-01",
+            "valueQuantity": { "value": 72, "unit": "/min" }
+        });
+
+        let reformatted = reformat_with(mapping, false, &fhir_obj);
+        assert_eq!(
+            reformatted,
+            json!({
+                "resourceType": "Observation",
+                "id": "bp",
+                "meta": { "lastUpdated": "2024-01-01" },
+                "code": { "coding": "http://loinc.org | 8867-4" },
+                "effective": "2024-01-01",
+                "value": "72 /min"
+            })
+        );
+        assert!(
+            mapping::load_mapping_from_str("@rename a")
+                .and_then(|m| mapping::process_mapping(&m))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_lookup_tables() {
+        let mapping = r#"@include default
+@table gender { male: ♂ male, female: ♀ female }
+@table local { "X-1": Blood pressure }
+@lookup Patient.gender -> gender
+#[system, code]
+{$.code lookup local} ({$.system})"#;
+        let fhir_obj = json!({
+            "resourceType": "Patient",
+            "gender": "female",
+            "extension": [{ "system": "urn:local", "code": "X-2" }]
+        });
+
+        let reformatted = reformat_with(mapping, false, &fhir_obj);
+        assert_eq!(reformatted["gender"], json!("♀ female"));
+        assert_eq!(reformatted["extension"], json!("X-2 (urn:local)"));
+    }
+
+    #[test]
+    fn test_rule_guards() {
+        let mapping = r#"@include default
+#[system, code] where system = http://loinc.org
+LOINC {$.code}
+#[system, code] where system = "urn:local" and code != X-0
+local {$.code}
+#[family, given, use] where use = official
+{$.given} {$.family}
+#[family, given, use] where use != official
+{$.given} ({$.use})
+#[family, given, use] where status = active
+{$.family}"#;
+        let fhir_obj = json!({
+            "resourceType": "Patient",
+            "name": [
+                { "family": "Doe", "given": "Jane", "use": "official" },
+                { "family": "Doe", "given": "Janie", "use": "nickname" }
+            ],
+            "extension": [
+                { "system": "http://loinc.org", "code": "8867-4" },
+                { "system": "urn:local", "code": "X-1" },
+                { "system": "urn:local", "code": "X-0" },
+                { "system": "urn:other", "code": "1" }
+            ]
+        });
+        let reformatted = reformat_with(mapping, false, &fhir_obj);
+        assert_eq!(reformatted["name"], json!(["Jane Doe", "Janie (nickname)"]));
+        assert_eq!(
+            reformatted["extension"],
+            json!([
+                "LOINC 8867-4",
+                "local X-1",
+                "urn:local | X-0",
+                "urn:other | 1"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_reference_keeps_key_order() {
+        let fhir_obj = json!({
+            "resourceType": "Encounter",
+            "subject": { "reference": "Patient/1", "display": "Jane Doe" },
+            "participant": { "individual": { "type": "Practitioner", "reference": "Practitioner/2" } }
+        });
+        let reformatted = reformat_with("", false, &fhir_obj);
+        assert_eq!(
+            serde_json::to_string(&reformatted).unwrap(),
+            json!({
+                "resourceType": "Encounter",
+                "subject": { "reference": "Reference(Patient/1)", "display": "Jane Doe" },
+                "participant": { "individual": { "type": "Practitioner", "reference": "Reference(Practitioner/2)" } }
+            })
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_rename_collision() {
+        let fhir_obj = json!({
+            "resourceType": "Observation",
+            "extension": { "url": "http://x.org/ext", "value": 1, "valueQuantity": { "value": 2 } }
+        });
+        // the renamed key keeps its name rather than overwriting `value`
+        let reformatted = reformat_with("@rename valueQuantity -> value", false, &fhir_obj);
+        assert_eq!(
+            reformatted["extension"],
+            json!({ "url": "http://x.org/ext", "value": 1, "valueQuantity": { "value": 2 } })
+        );
+        let reformatted =
+            reformat_with("@rename url -> uri\n@rename value -> uri", false, &fhir_obj);
+        assert_eq!(
+            serde_json::to_string(&reformatted["extension"]).unwrap(),
+            r#"{"uri":"http://x.org/ext","value":1,"valueQuantity":{"uri":2}}"#
+        );
+    }
 }
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+/**
+ * Key under which a resolved target is inlined next to the `reference` of a Reference object.
+ */
+pub const RESOLVED_KEY: &str = "resolved";
+
+/**
+ * Source of FHIR resources that references can be resolved against.
+ * Implement this to plug in other sources, e.g. a FHIR server.
+ */
+pub trait ReferenceResolver {
+    fn resolve(&mut self, reference: &str) -> Option<serde_json::Value>;
+}
+
+/**
+ * Resolves references against the resources that ship with the input itself,
+ * i.e. the entries of a Bundle (by `fullUrl` and by `Type/id`) and the root resource.
+ */
+pub struct BundleResolver {
+    resources: HashMap<String, serde_json::Value>,
+}
+
+impl BundleResolver {
+    pub fn new(root: &serde_json::Value) -> BundleResolver {
+        let mut resources = HashMap::new();
+        index_bundle(&mut resources, root);
+        BundleResolver { resources }
+    }
+}
+
+impl ReferenceResolver for BundleResolver {
+    fn resolve(&mut self, reference: &str) -> Option<serde_json::Value> {
+        lookup(&self.resources, reference)
+    }
+}
+
+/**
+ * Resolves references against the resources in the `*.json` and `*.ndjson` files of a local
+ * directory, e.g. an export of a FHIR server. Bundles contribute their entries.
+ */
+pub struct DirectoryResolver {
+    resources: HashMap<String, serde_json::Value>,
+}
+
+impl DirectoryResolver {
+    pub fn new(dir: &Path) -> Result<DirectoryResolver, String> {
+        let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let mut paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == "json" || ext == "ndjson")
+            })
+            .collect::<Vec<_>>();
+        // later files win, so the order must not depend on the file system
+        paths.sort();
+        let mut resources = HashMap::new();
+        for path in paths {
+            let content =
+                std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let parsed = match serde_json::from_str(&content) {
+                Ok(resource) => vec![resource],
+                Err(_) => content
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| !line.trim().is_empty())
+                    .map(|(i, line)| {
+                        serde_json::from_str(line)
+                            .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))
+                    })
+                    .collect::<Result<Vec<serde_json::Value>, String>>()?,
+            };
+            for resource in &parsed {
+                index_bundle(&mut resources, resource);
+            }
+        }
+        Ok(DirectoryResolver { resources })
+    }
+}
+
+impl ReferenceResolver for &DirectoryResolver {
+    fn resolve(&mut self, reference: &str) -> Option<serde_json::Value> {
+        lookup(&self.resources, reference)
+    }
+}
+
+/**
+ * Tries resolvers in turn, e.g. the entries of the input Bundle before a local directory.
+ */
+#[derive(Default)]
+pub struct ChainResolver<'a> {
+    resolvers: Vec<Box<dyn ReferenceResolver + 'a>>,
+}
+
+impl<'a> ChainResolver<'a> {
+    pub fn with(mut self, resolver: impl ReferenceResolver + 'a) -> ChainResolver<'a> {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+}
+
+impl ReferenceResolver for ChainResolver<'_> {
+    fn resolve(&mut self, reference: &str) -> Option<serde_json::Value> {
+        self.resolvers
+            .iter_mut()
+            .find_map(|resolver| resolver.resolve(reference))
+    }
+}
+
+fn lookup(
+    resources: &HashMap<String, serde_json::Value>,
+    reference: &str,
+) -> Option<serde_json::Value> {
+    resources
+        .get(reference)
+        .or_else(|| resources.get(&relative_key(reference)?))
+        .cloned()
+}
+
+/**
+ * Index a resource and, if it is a Bundle, its entries (also by `fullUrl`).
+ */
+fn index_bundle(resources: &mut HashMap<String, serde_json::Value>, root: &serde_json::Value) {
+    index_resource(resources, root, None);
+    if let Some(entries) = root.get("entry").and_then(|e| e.as_array()) {
+        for entry in entries {
+            if let Some(resource) = entry.get("resource") {
+                let full_url = entry.get("fullUrl").and_then(|u| u.as_str());
+                index_resource(resources, resource, full_url);
+            }
+        }
+    }
+}
+
+fn index_resource(
+    resources: &mut HashMap<String, serde_json::Value>,
+    resource: &serde_json::Value,
+    full_url: Option<&str>,
+) {
+    if let Some(url) = full_url {
+        resources.insert(url.to_string(), resource.clone());
+    }
+    if let Some(key) = resource_key(resource) {
+        resources.insert(key, resource.clone());
+    }
+}
+
+/**
+ * `Type/id` of a resource, if it has both.
+ */
+fn resource_key(resource: &serde_json::Value) -> Option<String> {
+    let resource_type = resource.get("resourceType")?.as_str()?;
+    let id = resource.get("id")?.as_str()?;
+    Some(format!("{}/{}", resource_type, id))
+}
+
+/**
+ * Reduce a relative or absolute reference to `Type/id`, dropping any `_history` suffix.
+ * E.g. `https://fhir.com/Patient/123/_history/2` becomes `Patient/123`.
+ */
+pub fn relative_key(reference: &str) -> Option<String> {
+    let path = match reference.find("/_history/") {
+        Some(pos) => &reference[..pos],
+        None => reference,
+    };
+    let mut segments = path.trim_end_matches('/').rsplit('/');
+    let id = segments.next()?;
+    let resource_type = segments.next()?;
+    if id.is_empty() || !resource_type.starts_with(|c: char| c.is_ascii_uppercase()) {
+        return None;
+    }
+    Some(format!("{}/{}", resource_type, id))
+}
+
+/**
+ * Follows references up to `depth` levels and inlines each target under `resolved`
+ * next to the `reference` it was found by. A resource is never inlined into itself,
+ * so cyclic references stop at the first repetition. Lookups are cached, so every
+ * distinct reference hits the resolver at most once.
+ */
+pub fn resolve_references(
+    root: &serde_json::Value,
+    depth: usize,
+    resolver: &mut dyn ReferenceResolver,
+) -> serde_json::Value {
+    let mut expander = Expander {
+        resolver,
+        cache: HashMap::new(),
+    };
+    let mut chain = Vec::new();
+    expander.expand(root, depth, &mut chain)
+}
+
+struct Expander<'a> {
+    resolver: &'a mut dyn ReferenceResolver,
+    cache: HashMap<String, Option<serde_json::Value>>,
+}
+
+impl Expander<'_> {
+    fn lookup(&mut self, reference: &str) -> Option<serde_json::Value> {
+        if let Some(cached) = self.cache.get(reference) {
+            return cached.clone();
+        }
+        let target = self.resolver.resolve(reference);
+        self.cache.insert(reference.to_string(), target.clone());
+        target
+    }
+
+    fn expand(
+        &mut self,
+        v: &serde_json::Value,
+        depth: usize,
+        chain: &mut Vec<String>,
+    ) -> serde_json::Value {
+        match v {
+            serde_json::Value::Object(map) => {
+                // keep track of the resources we are currently inside of
+                let own_key = resource_key(v);
+                if let Some(key) = &own_key {
+                    chain.push(key.clone());
+                }
+
+                let mut expanded: serde_json::Map<String, serde_json::Value> = map
+                    .iter()
+                    .map(|(k, v2)| (k.clone(), self.expand(v2, depth, chain)))
+                    .collect();
+
+                if depth > 0
+                    && !map.contains_key(RESOLVED_KEY)
+                    && let Some(reference) = map.get("reference").and_then(|r| r.as_str())
+                    && let Some(resolved) = self.follow(reference, depth, chain)
+                {
+                    expanded.insert(RESOLVED_KEY.to_string(), resolved);
+                }
+
+                if own_key.is_some() {
+                    chain.pop();
+                }
+                serde_json::Value::Object(expanded)
+            }
+            serde_json::Value::Array(arr) => serde_json::Value::Array(
+                arr.iter().map(|v2| self.expand(v2, depth, chain)).collect(),
+            ),
+            _ => v.clone(),
+        }
+    }
+
+    fn follow(
+        &mut self,
+        reference: &str,
+        depth: usize,
+        chain: &mut Vec<String>,
+    ) -> Option<serde_json::Value> {
+        // references to contained resources are already part of the output
+        if reference.starts_with('#') {
+            return None;
+        }
+        let target = self.lookup(reference)?;
+        let key = resource_key(&target).or_else(|| relative_key(reference))?;
+        if chain.contains(&key) {
+            return None;
+        }
+        Some(self.expand(&target, depth - 1, chain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_relative_key() {
+        assert_eq!(relative_key("Patient/123"), Some("Patient/123".to_string()));
+        assert_eq!(
+            relative_key("https://fhir.com/r4/Patient/123/_history/2"),
+            Some("Patient/123".to_string())
+        );
+        assert_eq!(relative_key("urn:uuid:61ebe359-bfdc-4613"), None);
+    }
+
+    #[test]
+    fn test_resolve_bundle_with_cycle() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                {
+                    "fullUrl": "https://fhir.com/Encounter/e1",
+                    "resource": {
+                        "resourceType": "Encounter",
+                        "id": "e1",
+                        "subject": { "reference": "https://fhir.com/Patient/p1" }
+                    }
+                },
+                {
+                    "fullUrl": "urn:uuid:p1",
+                    "resource": {
+                        "resourceType": "Patient",
+                        "id": "p1",
+                        "link": [{ "other": { "reference": "Encounter/e1" } }]
+                    }
+                }
+            ]
+        });
+
+        let mut resolver = BundleResolver::new(&bundle);
+        let resolved = resolve_references(&bundle, 2, &mut resolver);
+        let subject = &resolved["entry"][0]["resource"]["subject"];
+        assert_eq!(subject["resolved"]["id"], json!("p1"));
+        // the Patient links back to the Encounter we are coming from
+        assert!(
+            subject["resolved"]["link"][0]["other"]
+                .get("resolved")
+                .is_none()
+        );
+
+        let resolved = resolve_references(&bundle, 0, &mut resolver);
+        assert_eq!(resolved, bundle);
+    }
+
+    #[test]
+    fn test_resolve_from_directory() {
+        let dir = std::env::temp_dir().join(format!("hff-resolve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("Patient.ndjson"),
+            "{\"resourceType\": \"Patient\", \"id\": \"p1\"}\n\
+             {\"resourceType\": \"Patient\", \"id\": \"p2\"}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a resource").unwrap();
+        let directory = DirectoryResolver::new(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let directory = directory.unwrap();
+
+        // a standalone resource, without a Bundle around it
+        let encounter = json!({
+            "resourceType": "Encounter",
+            "id": "e1",
+            "subject": { "reference": "https://fhir.com/Patient/p2" },
+            "partOf": { "reference": "Encounter/e0" }
+        });
+        let mut resolver = ChainResolver::default()
+            .with(BundleResolver::new(&encounter))
+            .with(&directory);
+        let resolved = resolve_references(&encounter, 1, &mut resolver);
+        assert_eq!(resolved["subject"]["resolved"]["id"], json!("p2"));
+        assert!(resolved["partOf"].get("resolved").is_none());
+    }
+}
@@ -0,0 +1,628 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::hff::keys::{KeyAction, KeyRule};
+use crate::hff::mapping;
+use crate::hff::yaml::{self, Node, NodeKind};
+
+/**
+ * Structured (YAML) form of a mapping. It has the same semantics as the *.hfc format, and is in
+ * fact loaded by converting it into the equivalent *.hfc lines (at the lines of the YAML they come
+ * from), but every rule is a proper record that can carry a name, free-form metadata and its own
+ * tests:
+ *
+ * ```yaml
+ * rules:
+ *   - include: default
+ *   - name: Coding
+ *     signature: [system, code]
+ *     format: "{$.system} | {$.code}"
+ *     override: true
+ *     tests:
+ *       - input: { system: "http://loinc.org", code: "8867-4" }
+ *         expected: http://loinc.org | 8867-4
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StructuredMapping {
+    pub rules: Vec<Entry>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestCase>,
+}
+
+// only written untagged, reading dispatches on the keys of an entry, see `ENTRY_KEYS`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Entry {
+    Include {
+        include: String,
+    },
+    Hide {
+        hide: String,
+    },
+    Rename {
+        rename: String,
+        to: String,
+    },
+    Lookup {
+        lookup: String,
+        table: String,
+    },
+    Table {
+        table: String,
+        values: serde_json::Map<String, Value>,
+    },
+    Rule(RuleDef),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleDef {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub signature: Vec<String>,
+    // conditions on the values, like after `where` in a signature line
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    pub guard: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub format: String,
+    // templates of a structured rule that renders an object ...
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub fields: serde_json::Map<String, Value>,
+    // ... or a list
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<String>,
+    #[serde(rename = "override", skip_serializing_if = "is_false")]
+    pub is_override: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestCase>,
+    // not interpreted by hff, e.g. for authors, tickets or review state
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestCase {
+    pub input: Value,
+    pub expected: String,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/**
+ * Mapping files with a `.yaml` or `.yml` extension are structured, any other file is *.hfc.
+ */
+pub fn is_structured(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml") | Some("yml")
+    )
+}
+
+/**
+ * The keys of the entries in `rules` that are not rules, the first key tells the kind of entry.
+ */
+const ENTRY_KEYS: &[&[&str]] = &[
+    &["include"],
+    &["hide"],
+    &["rename", "to"],
+    &["lookup", "table"],
+    &["table", "values"],
+];
+
+const RULE_KEYS: &[&str] = &[
+    "name",
+    "signature",
+    "where",
+    "format",
+    "fields",
+    "items",
+    "override",
+    "tests",
+    "meta",
+];
+
+fn error<T>(node: &Node, message: String) -> Result<T, yaml::Error> {
+    Err(yaml::Error {
+        line: node.line,
+        message,
+    })
+}
+
+/**
+ * The values of a YAML mapping that may only have the given keys.
+ */
+struct Fields<'a> {
+    node: &'a Node,
+    entries: &'a [(String, Node)],
+    what: &'a str,
+}
+
+impl<'a> Fields<'a> {
+    fn new(node: &'a Node, what: &'a str, keys: &[&str]) -> Result<Self, yaml::Error> {
+        let NodeKind::Mapping(entries) = &node.kind else {
+            return error(node, format!("{} must be a mapping", what));
+        };
+        if let Some((key, value)) = entries.iter().find(|(k, _)| !keys.contains(&k.as_str())) {
+            let expected = keys
+                .iter()
+                .map(|key| format!("`{}`", key))
+                .collect::<Vec<String>>()
+                .join(", ");
+            return error(
+                value,
+                format!("unknown key `{}` in {}, expected {}", key, what, expected),
+            );
+        }
+        Ok(Fields {
+            node,
+            entries,
+            what,
+        })
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Node> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn required(&self, key: &str) -> Result<&'a Node, yaml::Error> {
+        match self.get(key) {
+            Some(value) => Ok(value),
+            None => error(self.node, format!("missing `{}` in {}", key, self.what)),
+        }
+    }
+}
+
+fn sequence<'a>(node: &'a Node, what: &str) -> Result<&'a [Node], yaml::Error> {
+    match &node.kind {
+        NodeKind::Sequence(items) => Ok(items),
+        _ => error(node, format!("{} must be a list", what)),
+    }
+}
+
+/**
+ * A scalar that goes into a single *.hfc line.
+ */
+fn single_line(node: &Node, what: &str) -> Result<String, yaml::Error> {
+    let value = match &node.kind {
+        NodeKind::Scalar(Value::String(s)) => s.clone(),
+        NodeKind::Scalar(v @ (Value::Number(_) | Value::Bool(_))) => v.to_string(),
+        _ => return error(node, format!("{} must be a string", what)),
+    };
+    if value.contains('\n') {
+        return error(node, format!("{} must be a single line: {}", what, value));
+    }
+    Ok(value)
+}
+
+fn test_line(test: &Node) -> Result<String, yaml::Error> {
+    let fields = Fields::new(test, "a test", &["input", "expected"])?;
+    Ok(format!(
+        "@test {} => {}",
+        fields.required("input")?.to_json(),
+        single_line(fields.required("expected")?, "`expected`")?
+    ))
+}
+
+/**
+ * `@table` line with the entries quoted, so that labels can hold any character.
+ */
+fn table_line(name: &str, values: &Node) -> Result<String, yaml::Error> {
+    let NodeKind::Mapping(entries) = &values.kind else {
+        return error(
+            values,
+            format!("`values` of table `{}` must be a mapping", name),
+        );
+    };
+    let entries = entries
+        .iter()
+        .map(|(k, v)| match &v.kind {
+            NodeKind::Scalar(label @ Value::String(_)) => {
+                Ok(format!("{}: {}", Value::from(k.clone()), label))
+            }
+            NodeKind::Scalar(label @ (Value::Number(_) | Value::Bool(_))) => {
+                Ok(format!("{}: \"{}\"", Value::from(k.clone()), label))
+            }
+            _ => error(v, format!("table `{}` must map values to labels", name)),
+        })
+        .collect::<Result<Vec<String>, yaml::Error>>()?;
+    Ok(format!("@table {} {{ {} }}", name, entries.join(", ")))
+}
+
+/**
+ * The *.hfc lines of a rule. Its name (as a comment) and `@override` get line 0, they only need to
+ * be above the signature.
+ */
+fn rule_lines(rule: &Fields, lines: &mut Vec<(usize, String)>) -> Result<(), yaml::Error> {
+    let signature_node = rule.required("signature")?;
+    let signature = sequence(signature_node, "`signature`")?
+        .iter()
+        .map(|key| single_line(key, "a key of `signature`"))
+        .collect::<Result<Vec<String>, yaml::Error>>()?
+        .join(", ");
+    if let Some(name) = rule.get("name") {
+        lines.push((0, format!("// {}", single_line(name, "`name`")?)));
+    }
+    match rule.get("override").map(|node| &node.kind) {
+        None | Some(NodeKind::Scalar(Value::Bool(false))) => {}
+        Some(NodeKind::Scalar(Value::Bool(true))) => lines.push((0, "@override".to_string())),
+        Some(_) => {
+            return error(
+                rule.required("override")?,
+                "`override` must be true or false".to_string(),
+            );
+        }
+    }
+    let signature_line = match rule.get("where") {
+        Some(guard) => format!("#[{}] where {}", signature, single_line(guard, "`where`")?),
+        None => format!("#[{}]", signature),
+    };
+    lines.push((signature_node.line, signature_line));
+
+    let templates = ["format", "fields", "items"];
+    if templates
+        .iter()
+        .filter(|key| rule.get(key).is_some())
+        .count()
+        != 1
+    {
+        return error(
+            rule.node,
+            format!(
+                "rule `[{}]` needs exactly one of `format`, `fields` or `items`",
+                signature
+            ),
+        );
+    }
+    if let Some(format) = rule.get("format") {
+        lines.push((format.line, single_line(format, "`format`")?));
+    }
+    if let Some(fields) = rule.get("fields") {
+        let NodeKind::Mapping(fields) = &fields.kind else {
+            return error(
+                fields,
+                format!(
+                    "fields of rule `[{}]` must map names to templates",
+                    signature
+                ),
+            );
+        };
+        for (key, format) in fields {
+            let template = single_line(format, "field")?;
+            lines.push((format.line, format!("@field {}: {}", key, template)));
+        }
+    }
+    if let Some(items) = rule.get("items") {
+        for item in sequence(items, "`items`")? {
+            lines.push((item.line, format!("@item {}", single_line(item, "item")?)));
+        }
+    }
+    if let Some(tests) = rule.get("tests") {
+        for test in sequence(tests, "`tests`")? {
+            lines.push((test.line, test_line(test)?));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * The *.hfc lines of a structured mapping, each with the YAML line it comes from.
+ */
+fn generate(mapping_str: &str) -> Result<Vec<(usize, String)>, yaml::Error> {
+    let root = yaml::parse(mapping_str)?;
+    let structured = Fields::new(&root, "a structured mapping", &["rules", "tests"])?;
+    let mut lines = Vec::new();
+    for entry in sequence(structured.required("rules")?, "`rules`")? {
+        let NodeKind::Mapping(entries) = &entry.kind else {
+            return error(entry, "entries of `rules` must be mappings".to_string());
+        };
+        let has = |key: &str| entries.iter().any(|(k, _)| k == key);
+        let Some(keys) = ENTRY_KEYS.iter().find(|keys| has(keys[0])) else {
+            rule_lines(&Fields::new(entry, "a rule", RULE_KEYS)?, &mut lines)?;
+            continue;
+        };
+        let what = format!("the `{}` entry", keys[0]);
+        let fields = Fields::new(entry, &what, keys)?;
+        let value = |key: &str| single_line(fields.required(key)?, &format!("`{}`", key));
+        let line = fields.required(keys[0])?.line;
+        let hfc = match keys[0] {
+            "include" => format!("@include {}", value("include")?),
+            "hide" => format!("@hide {}", value("hide")?),
+            "rename" => format!("@rename {} -> {}", value("rename")?, value("to")?),
+            "lookup" => format!("@lookup {} -> {}", value("lookup")?, value("table")?),
+            _ => table_line(&value("table")?, fields.required("values")?)?,
+        };
+        lines.push((line, hfc));
+    }
+    if let Some(tests) = structured.get("tests") {
+        for test in sequence(tests, "`tests`")? {
+            lines.push((test.line, test_line(test)?));
+        }
+    }
+    Ok(lines)
+}
+
+/**
+ * Convert a structured (YAML) mapping into the equivalent *.hfc lines.
+ */
+pub fn structured_to_hfc(mapping_str: &str) -> io::Result<Vec<String>> {
+    let lines =
+        generate(mapping_str).map_err(|e| invalid(format!("Invalid structured mapping: {}", e)))?;
+    Ok(lines.into_iter().map(|(_, line)| line).collect())
+}
+
+/**
+ * Like `structured_to_hfc`, but every *.hfc line is at the line of the YAML it comes from, with
+ * blank lines in between, so that diagnostics, test results and `--explain` point into the YAML.
+ * A name is left out if there is no room for it above the signature of its rule.
+ */
+pub fn load_structured(mapping_str: &str) -> io::Result<Vec<String>> {
+    let generated =
+        generate(mapping_str).map_err(|e| invalid(format!("Invalid structured mapping: {}", e)))?;
+    let mut lines: Vec<String> = Vec::new();
+    let mut above = Vec::new();
+    for (line, hfc) in generated {
+        if line == 0 {
+            above.push(hfc);
+            continue;
+        }
+        let free = line.saturating_sub(lines.len() + 1);
+        if above.len() > free {
+            above.retain(|hfc| !hfc.starts_with("//"));
+        }
+        let start = line.saturating_sub(above.len()).max(lines.len() + 1);
+        lines.resize(start - 1, String::new());
+        lines.append(&mut above);
+        lines.resize(lines.len().max(line - 1), String::new());
+        lines.push(hfc);
+    }
+    Ok(lines)
+}
+
+/**
+ * Convert *.hfc lines into a structured mapping. A comment right above a rule becomes its name.
+ */
+pub fn hfc_to_structured(mapping: &[String]) -> io::Result<StructuredMapping> {
+    let parsed = mapping::parse_rules(mapping);
+    if let Some(error) = mapping::first_error(&parsed.diagnostics) {
+        return Err(invalid(format!("Invalid mapping: {}", error)));
+    }
+
+    let name_of = |line: usize| {
+        mapping[..line - 1]
+            .iter()
+            .rev()
+            .map(|l| l.trim())
+            .find(|l| *l != "@override")
+            .and_then(|l| l.strip_prefix("//"))
+            .map(|comment| comment.trim().to_string())
+    };
+    let mut rules = Vec::new();
+    // the line of each rule and its index in `rules`
+    let mut rule_entries = Vec::new();
+    let mut includes = parsed.includes.iter().peekable();
+    let mut key_rules = parsed.key_rules.iter().peekable();
+    let mut tables = parsed.tables.iter().peekable();
+    for (position, parsed_rule) in parsed.rules.iter().enumerate() {
+        while let Some(include) = includes.next_if(|include| include.position == position) {
+            rules.push(Entry::Include {
+                include: include.target.clone(),
+            });
+        }
+        while let Some((_, key_rule)) = key_rules.next_if(|(p, _)| *p == position) {
+            rules.push(key_entry(key_rule));
+        }
+        while let Some(table) = tables.next_if(|table| table.position == position) {
+            rules.push(table_entry(table));
+        }
+        rule_entries.push((parsed_rule.rule.line, rules.len()));
+        rules.push(Entry::Rule(RuleDef {
+            name: name_of(parsed_rule.rule.line),
+            signature: parsed_rule.signature.clone(),
+            guard: parsed_rule
+                .rule
+                .guard
+                .as_ref()
+                .map(|guard| guard.to_string()),
+            format: parsed_rule.rule.format.clone(),
+            fields: parsed_rule
+                .rule
+                .fields
+                .iter()
+                .filter_map(|field| {
+                    let key = field.key.clone()?;
+                    Some((key, field.format.clone().into()))
+                })
+                .collect(),
+            items: parsed_rule
+                .rule
+                .fields
+                .iter()
+                .filter(|field| field.key.is_none())
+                .map(|field| field.format.clone())
+                .collect(),
+            is_override: parsed_rule.is_override,
+            tests: Vec::new(),
+            meta: BTreeMap::new(),
+        }));
+    }
+    rules.extend(includes.map(|include| Entry::Include {
+        include: include.target.clone(),
+    }));
+    rules.extend(key_rules.map(|(_, key_rule)| key_entry(key_rule)));
+    rules.extend(tables.map(table_entry));
+
+    // tests stay with the rule they follow
+    let mut tests = Vec::new();
+    for test in parsed.tests {
+        let rule = rule_entries
+            .iter()
+            .rev()
+            .find(|(line, _)| *line < test.line)
+            .map(|(_, index)| &mut rules[*index]);
+        let test_case = TestCase {
+            input: test.input,
+            expected: test.expected,
+        };
+        match rule {
+            Some(Entry::Rule(rule)) => rule.tests.push(test_case),
+            _ => tests.push(test_case),
+        }
+    }
+    Ok(StructuredMapping { rules, tests })
+}
+
+fn table_entry(table: &mapping::ParsedTable) -> Entry {
+    Entry::Table {
+        table: table.name.clone(),
+        values: table
+            .entries
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone().into()))
+            .collect(),
+    }
+}
+
+fn key_entry(key_rule: &KeyRule) -> Entry {
+    match &key_rule.action {
+        KeyAction::Hide => Entry::Hide {
+            hide: key_rule.pattern_str(),
+        },
+        KeyAction::Rename(to) => Entry::Rename {
+            rename: key_rule.pattern_str(),
+            to: to.clone(),
+        },
+        KeyAction::Lookup(table) => Entry::Lookup {
+            lookup: key_rule.pattern_str(),
+            table: table.clone(),
+        },
+    }
+}
+
+pub fn structured_to_yaml(structured: &StructuredMapping) -> io::Result<String> {
+    let value = serde_json::to_value(structured).map_err(|e| invalid(e.to_string()))?;
+    Ok(yaml::to_yaml(&value, yaml::DEFAULT_WIDTH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_mapping_round_trip() {
+        let hfc = mapping::load_default_mapping().unwrap();
+        let yaml = structured_to_yaml(&hfc_to_structured(&hfc).unwrap()).unwrap();
+
+        let converted = structured_to_hfc(&yaml).unwrap();
+        let formats = |lines: &[String]| {
+            mapping::process_mapping(lines)
+                .unwrap()
+                .rules
+                .into_iter()
+                .map(|(signature, rule)| (signature, rule.format))
+                .collect::<BTreeMap<String, String>>()
+        };
+        assert_eq!(formats(&converted), formats(&hfc));
+        assert_eq!(
+            mapping::test_mapping(&converted).unwrap().len(),
+            mapping::test_mapping(&hfc).unwrap().len()
+        );
+
+        // the format is told by the file extension, not by the content
+        assert!(is_structured(Path::new("mapping.yml")));
+        assert!(!is_structured(Path::new("mapping.hfc")));
+        let hfc = mapping::load_mapping_from_str("#[rules]\nrules: {$.rules}").unwrap();
+        let rules = mapping::process_mapping(&hfc).unwrap().rules;
+        assert_eq!(rules["rules"].format, "rules: {$.rules}");
+    }
+
+    #[test]
+    fn test_structured_to_hfc() {
+        let yaml = r#"
+rules:
+  - include: default
+  - hide: meta.versionId
+  - table: gender
+    values: { male: ♂ male, female: ♀ female }
+  - lookup: Patient.gender
+    table: gender
+  - rename: Observation.effectiveDateTime
+    to: effective
+  - name: Coding
+    signature: [system, code]
+    format: "{$.code}"
+    override: true
+    meta:
+      owner: team-a
+    tests:
+      - input: { system: "http://loinc.org", code: "8867-4" }
+        expected: "8867-4"
+  - signature: [system, code]
+    where: system = urn:local
+    format: "local {$.code}"
+"#;
+        assert_eq!(
+            structured_to_hfc(yaml).unwrap(),
+            vec![
+                "@include default",
+                "@hide meta.versionId",
+                r#"@table gender { "male": "♂ male", "female": "♀ female" }"#,
+                "@lookup Patient.gender -> gender",
+                "@rename Observation.effectiveDateTime -> effective",
+                "// Coding",
+                "@override",
+                "#[system, code]",
+                "{$.code}",
+                r#"@test {"system":"http://loinc.org","code":"8867-4"} => 8867-4"#,
+                "#[system, code] where system = urn:local",
+                "local {$.code}",
+            ]
+        );
+        let structured = hfc_to_structured(&structured_to_hfc(yaml).unwrap()).unwrap();
+        match &structured.rules[6] {
+            Entry::Rule(rule) => assert_eq!(rule.guard.as_deref(), Some("system = urn:local")),
+            entry => panic!("expected a rule, got {:?}", entry),
+        }
+        match &structured.rules[5] {
+            Entry::Rule(rule) => assert_eq!(rule.tests.len(), 1),
+            entry => panic!("expected a rule, got {:?}", entry),
+        }
+        assert!(structured.tests.is_empty());
+
+        // loaded at the lines of the YAML
+        let loaded = load_structured(yaml).unwrap();
+        assert_eq!(loaded[10], "@override");
+        assert_eq!(loaded[11], "#[system, code]");
+        assert_eq!(loaded[12], "{$.code}");
+        assert!(loaded[17].starts_with("@test "));
+        assert_eq!(loaded[19], "#[system, code] where system = urn:local");
+        let broken = "rules:\n  - signature: [a]\n    where: a ~ b\n    format: x";
+        let diagnostics = mapping::validate_mapping(&load_structured(broken).unwrap());
+        assert_eq!(diagnostics[0].line, 2);
+
+        let error = |yaml: &str| structured_to_hfc(yaml).unwrap_err().to_string();
+        assert!(
+            error("rules:\n  - signature: [a]\n    fromat: x")
+                .contains("line 3: unknown key `fromat` in a rule")
+        );
+        assert!(
+            error("rules:\n  - include: default\n    format: x").contains(
+                "line 3: unknown key `format` in the `include` entry, expected `include`"
+            )
+        );
+        assert!(
+            error("rules:\n  - rename: a").contains("line 2: missing `to` in the `rename` entry")
+        );
+        assert!(
+            error("rules:\n  - signature: a\n    format: x").contains("`signature` must be a list")
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use crate::hff::fhirpath;
+
+/**
+ * A column of a table export, selected by a dotted key path into the reformatted tree, e.g. `id`,
+ * `code` or `subject.display`. Like in FHIRPath, a key matches choice types by their base name,
+ * so `value` selects `valueQuantity` or `valueString`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    path: Vec<String>,
+}
+
+/**
+ * Parse a comma separated column list like `id, code, value, effective`.
+ */
+pub fn parse_columns(spec: &str) -> Result<Vec<Column>, String> {
+    let columns = spec
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let path = name
+                .split('.')
+                .map(|key| key.trim().to_string())
+                .collect::<Vec<String>>();
+            match path.iter().any(|key| key.is_empty()) {
+                true => Err(format!("invalid column `{}`", name)),
+                false => Ok(Column {
+                    name: name.to_string(),
+                    path,
+                }),
+            }
+        })
+        .collect::<Result<Vec<Column>, String>>()?;
+    if columns.is_empty() {
+        return Err("no columns given".to_string());
+    }
+    Ok(columns)
+}
+
+impl Column {
+    /**
+     * A column that selects a top-level key as it is, e.g. a column of a view (see `viewdef`).
+     */
+    pub fn key(name: &str) -> Column {
+        Column {
+            name: name.to_string(),
+            path: vec![name.to_string()],
+        }
+    }
+
+    /**
+     * The cell of a reformatted resource, empty if the path selects nothing.
+     */
+    pub fn cell(&self, tree: &serde_json::Value) -> String {
+        let mut nodes = vec![tree];
+        for key in &self.path {
+            nodes = nodes
+                .into_iter()
+                .flat_map(|node| match node {
+                    serde_json::Value::Array(arr) => arr.iter().collect(),
+                    _ => vec![node],
+                })
+                .filter_map(|node| child(node, key))
+                .collect();
+        }
+        nodes
+            .into_iter()
+            .map(cell_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<String>>()
+            .join("; ")
+    }
+}
+
+fn child<'a>(node: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    let map = node.as_object()?;
+    map.get(key).or_else(|| {
+        map.iter()
+            .find(|(k, _)| fhirpath::is_choice_of(k, key))
+            .map(|(_, v)| v)
+    })
+}
+
+/**
+ * Text of a cell: the HUFF string for formatted values and several values joined with `; `.
+ * Objects that no rule formatted are reduced to their values, e.g. the `coding` of a CodeableConcept,
+ * and keyed with `key: ` if there are more than one.
+ */
+pub(crate) fn cell_text(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::Array(arr) => arr
+            .iter()
+            .map(cell_text)
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<String>>()
+            .join("; "),
+        serde_json::Value::Object(map) if map.len() == 1 => {
+            map.values().next().map(cell_text).unwrap_or_default()
+        }
+        serde_json::Value::Object(map) => map
+            .iter()
+            .map(|(k, v2)| (k, cell_text(v2)))
+            .filter(|(_, text)| !text.is_empty())
+            .map(|(k, text)| format!("{}: {}", k, text))
+            .collect::<Vec<String>>()
+            .join("; "),
+        _ => v.to_string(),
+    }
+}
+
+/**
+ * Write a header row and one row per reformatted resource, separated by `delimiter`. With `,`
+ * cells are quoted as in CSV when needed; with a tab, tabs and line breaks in cells become spaces.
+ */
+pub fn to_delimited(columns: &[Column], trees: &[serde_json::Value], delimiter: char) -> String {
+    let field = |text: &str| match delimiter {
+        '\t' => text.replace(['\t', '\n', '\r'], " "),
+        _ if text.contains([delimiter, '"', '\n', '\r']) => {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        }
+        _ => text.to_string(),
+    };
+    let separator = delimiter.to_string();
+    let mut out = columns
+        .iter()
+        .map(|column| field(&column.name))
+        .collect::<Vec<String>>()
+        .join(&separator);
+    out.push('\n');
+    for tree in trees {
+        let row = columns
+            .iter()
+            .map(|column| field(&column.cell(tree)))
+            .collect::<Vec<String>>();
+        out.push_str(&row.join(&separator));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_delimited() {
+        let columns = parse_columns("id, code, value, effective, performer.display").unwrap();
+        let trees = vec![
+            json!({
+                "id": "o1",
+                "code": { "coding": "http://loinc.org | 8867-4 | Heart rate" },
+                "valueQuantity": "72 /min",
+                "effectiveDateTime": "2024-01-01",
+                "performer": [{ "display": "Dr. \"A\"" }, { "display": "B, C" }]
+            }),
+            json!({ "id": "o2", "valueBoolean": true, "code": { "text": "x", "coding": ["a", "b"] } }),
+        ];
+        assert_eq!(
+            to_delimited(&columns, &trees, ','),
+            "id,code,value,effective,performer.display\n\
+             o1,http://loinc.org | 8867-4 | Heart rate,72 /min,2024-01-01,\"Dr. \"\"A\"\"; B, C\"\n\
+             o2,text: x; coding: a; b,true,,\n"
+        );
+        assert_eq!(to_delimited(&columns[..1], &trees, '\t'), "id\no1\no2\n");
+        let request = json!({ "id": "m1", "statusReason": { "text": "on hold" } });
+        let columns = parse_columns("id, status").unwrap();
+        assert_eq!(to_delimited(&columns, &[request], ','), "id,status\nm1,\n");
+        assert!(parse_columns(" , ").is_err());
+        assert!(parse_columns("code..text").is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+/**
+ * Marks a `display` that was filled in from local terminology rather than taken from the input.
+ */
+pub const INFERRED_MARK: &str = "≈ ";
+
+/**
+ * Displays of codes from local terminology, to fill in Codings that only carry `system` and `code`.
+ * Sources are CodeSystem and ValueSet resources (or Bundles of them) in FHIR JSON, and CSV code
+ * tables with a header row naming the `system`, `code` and `display` columns. Codes of a table
+ * without `system` column match in any system.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Terminology {
+    displays: HashMap<(String, String), String>,
+}
+
+impl Terminology {
+    pub fn new() -> Terminology {
+        Terminology::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.displays.is_empty()
+    }
+
+    /**
+     * Load a terminology file, a CSV code table if its extension is `csv`, otherwise FHIR JSON.
+     */
+    pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let is_csv = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        let loaded = match is_csv {
+            true => self.add_csv(&content),
+            false => serde_json::from_str(&content)
+                .map_err(|e| e.to_string())
+                .and_then(|resource| self.add_resource(&resource)),
+        };
+        loaded.map_err(|e| {
+            io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        })
+    }
+
+    /**
+     * Add the concepts of a CodeSystem, the (expanded or listed) codes of a ValueSet, or those of
+     * the CodeSystems and ValueSets in a Bundle.
+     */
+    pub fn add_resource(&mut self, resource: &serde_json::Value) -> Result<(), String> {
+        match str_of(resource, "resourceType") {
+            Some("CodeSystem") => {
+                let system = str_of(resource, "url").ok_or("CodeSystem without `url`")?;
+                self.add_concepts(system, resource.get("concept"));
+            }
+            Some("ValueSet") => {
+                let includes = resource
+                    .pointer("/compose/include")
+                    .and_then(|i| i.as_array());
+                for include in includes.into_iter().flatten() {
+                    if let Some(system) = str_of(include, "system") {
+                        self.add_concepts(system, include.get("concept"));
+                    }
+                }
+                self.add_contains(resource.pointer("/expansion/contains"));
+            }
+            Some("Bundle") => {
+                let entries = resource.get("entry").and_then(|e| e.as_array());
+                for resource in entries
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|e| e.get("resource"))
+                {
+                    self.add_resource(resource)?;
+                }
+            }
+            Some(other) => return Err(format!("expected a CodeSystem or ValueSet, got {}", other)),
+            None => return Err("not a FHIR resource".to_string()),
+        }
+        Ok(())
+    }
+
+    fn add_concepts(&mut self, system: &str, concepts: Option<&serde_json::Value>) {
+        for concept in concepts.and_then(|c| c.as_array()).into_iter().flatten() {
+            if let (Some(code), Some(display)) =
+                (str_of(concept, "code"), str_of(concept, "display"))
+            {
+                self.insert(system, code, display);
+            }
+            // hierarchical code systems nest their concepts
+            self.add_concepts(system, concept.get("concept"));
+        }
+    }
+
+    fn add_contains(&mut self, contains: Option<&serde_json::Value>) {
+        for entry in contains.and_then(|c| c.as_array()).into_iter().flatten() {
+            if let (Some(system), Some(code), Some(display)) = (
+                str_of(entry, "system"),
+                str_of(entry, "code"),
+                str_of(entry, "display"),
+            ) {
+                self.insert(system, code, display);
+            }
+            self.add_contains(entry.get("contains"));
+        }
+    }
+
+    /**
+     * Add the rows of a CSV code table. The header row needs a `code` and a `display` column,
+     * `system` is optional and other columns are ignored.
+     */
+    pub fn add_csv(&mut self, csv: &str) -> Result<(), String> {
+        let mut rows = csv
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        let header = rows
+            .next()
+            .map(|(_, line)| csv_fields(line))
+            .unwrap_or_default();
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(name))
+        };
+        let (Some(code), Some(display)) = (column("code"), column("display")) else {
+            return Err(
+                "CSV code table needs a header with `code` and `display` columns".to_string(),
+            );
+        };
+        let system = column("system");
+        for (index, line) in rows {
+            let fields = csv_fields(line);
+            let field = |i: usize| fields.get(i).map(|f| f.trim());
+            match (field(code), field(display)) {
+                (Some(code), Some(display)) if !code.is_empty() => {
+                    let system = system.and_then(field).unwrap_or_default();
+                    self.insert(system, code, display);
+                }
+                _ => return Err(format!("line {}: missing code or display", index + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, system: &str, code: &str, display: &str) {
+        self.displays
+            .insert((system.to_string(), code.to_string()), display.to_string());
+    }
+
+    /**
+     * The display of `code` in `system`, or of `code` from a table without systems.
+     */
+    pub fn display(&self, system: &str, code: &str) -> Option<&str> {
+        self.displays
+            .get(&(system.to_string(), code.to_string()))
+            .or_else(|| self.displays.get(&(String::new(), code.to_string())))
+            .map(|display| display.as_str())
+    }
+}
+
+fn str_of<'a>(v: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    v.get(key).and_then(|s| s.as_str())
+}
+
+/**
+ * Split a CSV line into its fields. Fields can be quoted with `"`, which is escaped as `""`.
+ */
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.next_if_eq(&'"').is_some() => field.push('"'),
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/**
+ * Fill in the `display` of Codings (objects with `system` and `code`, but no `value` or `unit`
+ * like a Quantity) that have none, if the terminology knows the code. Filled in displays start with `INFERRED_MARK`.
+ */
+pub fn fill_displays(v: &serde_json::Value, terminology: &Terminology) -> serde_json::Value {
+    match v {
+        serde_json::Value::Object(map) => {
+            let mut filled: serde_json::Map<String, serde_json::Value> = map
+                .iter()
+                .map(|(k, v2)| (k.clone(), fill_displays(v2, terminology)))
+                .collect();
+            // Quantities have a `system` and `code` as well, but no `display`
+            if !map.contains_key("display")
+                && !["value", "unit", "comparator"]
+                    .iter()
+                    .any(|k| map.contains_key(*k))
+                && let (Some(system), Some(code)) = (str_of(v, "system"), str_of(v, "code"))
+                && let Some(display) = terminology.display(system, code)
+            {
+                filled.insert(
+                    "display".to_string(),
+                    serde_json::Value::String(format!("{}{}", INFERRED_MARK, display)),
+                );
+            }
+            serde_json::Value::Object(filled)
+        }
+        serde_json::Value::Array(arr) => serde_json::Value::Array(
+            arr.iter()
+                .map(|v2| fill_displays(v2, terminology))
+                .collect(),
+        ),
+        _ => v.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fill_displays() {
+        let mut terminology = Terminology::new();
+        terminology
+            .add_resource(&json!({
+                "resourceType": "CodeSystem",
+                "url": "urn:local",
+                "concept": [{
+                    "code": "VS",
+                    "display": "Vital signs",
+                    "concept": [{ "code": "HR", "display": "Heart rate" }]
+                }]
+            }))
+            .unwrap();
+        terminology
+            .add_resource(&json!({
+                "resourceType": "ValueSet",
+                "expansion": { "contains": [
+                    { "system": "http://loinc.org", "code": "8867-4", "display": "Heart rate" }
+                ]}
+            }))
+            .unwrap();
+        terminology
+            .add_csv(
+                "system,code,display\nhttp://unitsofmeasure.org,mm[Hg],millimeter of mercury\n",
+            )
+            .unwrap();
+        terminology
+            .add_csv("code,display,comment\nX-1,\"Blood pressure, systolic\",\"says \"\"hi\"\"\"\n")
+            .unwrap();
+        assert_eq!(terminology.display("urn:local", "HR"), Some("Heart rate"));
+        assert_eq!(
+            terminology.display("urn:other", "X-1"),
+            Some("Blood pressure, systolic")
+        );
+        assert!(terminology.add_csv("code;display\n1;a").is_err());
+        assert!(
+            terminology
+                .add_resource(&json!({ "resourceType": "Patient" }))
+                .is_err()
+        );
+
+        let filled = fill_displays(
+            &json!({ "coding": [
+                { "system": "http://loinc.org", "code": "8867-4" },
+                { "system": "urn:local", "code": "HR", "display": "HR" },
+                { "system": "urn:local", "code": "unknown" }
+            ],
+            "valueQuantity": { "value": 120, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" }
+            }),
+            &terminology,
+        );
+        assert_eq!(
+            filled,
+            json!({ "coding": [
+                { "system": "http://loinc.org", "code": "8867-4", "display": "≈ Heart rate" },
+                { "system": "urn:local", "code": "HR", "display": "HR" },
+                { "system": "urn:local", "code": "unknown" }
+            ],
+            "valueQuantity": { "value": 120, "unit": "mmHg", "system": "http://unitsofmeasure.org", "code": "mm[Hg]" }
+            })
+        );
+    }
+
+    #[test]
+    fn test_terminology() {
+        let mut terminology = Terminology::new();
+        terminology
+            .add_csv("system,code,display\nhttp://loinc.org,8867-4,Heart rate\nhttp://unitsofmeasure.org,/min,per minute\n")
+            .unwrap();
+        let fhir_obj = json!({
+            "resourceType": "Observation",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4" }] },
+            "valueQuantity": { "value": 72, "unit": "/min", "system": "http://unitsofmeasure.org", "code": "/min" }
+        });
+        let reformatted = crate::hff::builder()
+            .with_terminology(terminology)
+            .reformat(&fhir_obj)
+            .unwrap();
+        assert_eq!(
+            reformatted["code"]["coding"],
+            json!("http://loinc.org | 8867-4 | ≈ Heart rate")
+        );
+        // the Quantity rule still matches
+        assert_eq!(
+            reformatted["valueQuantity"],
+            json!("72 /min | http://unitsofmeasure.org | /min")
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use crate::hff::fhirpath::{self, FhirPath};
+use regex::Regex;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+/**
+ * A row of a view, the values of its columns by name in the order of the view.
+ */
+pub type Row = serde_json::Map<String, Value>;
+
+/**
+ * A SQL on FHIR `ViewDefinition` (https://sql-on-fhir.org/ig/latest/StructureDefinition-ViewDefinition.html)
+ * that flattens resources of one type into rows: `select` with `column`, `forEach`, `forEachOrNull`,
+ * nested `select` and `unionAll`, `where` filters and `constant`s referenced as `%name`.
+ * Paths are evaluated with the FHIRPath subset of `fhirpath`, `%resource` is the resource of the row.
+ */
+#[derive(Debug, Clone)]
+pub struct ViewDefinition {
+    pub resource: String,
+    // the top-level `select`s are nested in a select without columns
+    select: Select,
+    filters: Vec<FhirPath>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Select {
+    columns: Vec<ViewColumn>,
+    for_each: Option<FhirPath>,
+    // a row with empty columns if `for_each` finds nothing
+    or_null: bool,
+    select: Vec<Select>,
+    union_all: Vec<Select>,
+}
+
+#[derive(Debug, Clone)]
+struct ViewColumn {
+    name: String,
+    path: FhirPath,
+    collection: bool,
+}
+
+impl ViewDefinition {
+    /**
+     * Read a `ViewDefinition` resource in JSON form.
+     */
+    pub fn from_json(v: &Value) -> Result<ViewDefinition, String> {
+        if let Some(t) = v.get("resourceType").and_then(|t| t.as_str())
+            && t != "ViewDefinition"
+        {
+            return Err(format!("expected a ViewDefinition, got a {}", t));
+        }
+        let resource = v
+            .get("resource")
+            .and_then(|r| r.as_str())
+            .ok_or("the view has no `resource`")?
+            .to_string();
+        let constants = constants(v)?;
+        let selects = array(v, "select")
+            .iter()
+            .map(|s| Select::from_json(s, &constants))
+            .collect::<Result<Vec<Select>, String>>()?;
+        if selects.is_empty() {
+            return Err("the view has no `select`".to_string());
+        }
+        let filters = array(v, "where")
+            .iter()
+            .map(|w| {
+                let path = w.get("path").and_then(|p| p.as_str()).unwrap_or_default();
+                parse_path(path, &constants).map_err(|e| format!("`where` {}", e))
+            })
+            .collect::<Result<Vec<FhirPath>, String>>()?;
+        let view = ViewDefinition {
+            resource,
+            select: Select {
+                select: selects,
+                ..Default::default()
+            },
+            filters,
+        };
+        let names = view.columns();
+        if let Some((i, name)) = names
+            .iter()
+            .enumerate()
+            .find(|(i, name)| names[..*i].contains(name))
+        {
+            return Err(format!("column `{}` is defined twice (#{})", name, i + 1));
+        }
+        Ok(view)
+    }
+
+    /**
+     * Names of the columns, in the order of the rows.
+     */
+    pub fn columns(&self) -> Vec<String> {
+        self.select.names()
+    }
+
+    /**
+     * The rows of a resource: none if it has another type or does not pass the `where` filters.
+     * Fails if a path can't be evaluated or a column that is not a `collection` has several values.
+     */
+    pub fn rows(&self, resource: &Value) -> Result<Vec<Row>, String> {
+        if resource.get("resourceType").and_then(|t| t.as_str()) != Some(self.resource.as_str()) {
+            return Ok(Vec::new());
+        }
+        for filter in &self.filters {
+            let result = filter.evaluate(resource)?;
+            if !matches!(result.as_slice(), [Value::Bool(true)]) {
+                return Ok(Vec::new());
+            }
+        }
+        self.select.rows(resource, resource)
+    }
+}
+
+impl Select {
+    fn from_json(v: &Value, constants: &[(String, String)]) -> Result<Select, String> {
+        let columns = array(v, "column")
+            .iter()
+            .map(|c| {
+                let name = c
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .ok_or("a column has no `name`")?;
+                let path = c.get("path").and_then(|p| p.as_str()).unwrap_or_default();
+                Ok(ViewColumn {
+                    name: name.to_string(),
+                    path: parse_path(path, constants)
+                        .map_err(|e| format!("column `{}`: {}", name, e))?,
+                    collection: c.get("collection").and_then(|b| b.as_bool()) == Some(true),
+                })
+            })
+            .collect::<Result<Vec<ViewColumn>, String>>()?;
+        let (for_each, or_null) = match (
+            v.get("forEach").and_then(|p| p.as_str()),
+            v.get("forEachOrNull").and_then(|p| p.as_str()),
+        ) {
+            (Some(_), Some(_)) => return Err("`forEach` and `forEachOrNull` together".to_string()),
+            (Some(path), None) => (Some(path), false),
+            (None, Some(path)) => (Some(path), true),
+            (None, None) => (None, false),
+        };
+        let for_each = for_each
+            .map(|path| parse_path(path, constants).map_err(|e| format!("`forEach` {}", e)))
+            .transpose()?;
+        let nested = |key: &str| {
+            array(v, key)
+                .iter()
+                .map(|s| Select::from_json(s, constants))
+                .collect::<Result<Vec<Select>, String>>()
+        };
+        let select = Select {
+            columns,
+            for_each,
+            or_null,
+            select: nested("select")?,
+            union_all: nested("unionAll")?,
+        };
+        if let Some(first) = select.union_all.first() {
+            let names = first.names();
+            if select.union_all.iter().any(|s| s.names() != names) {
+                return Err(format!(
+                    "the selects of a `unionAll` need the same columns ({})",
+                    names.join(", ")
+                ));
+            }
+        }
+        Ok(select)
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = self
+            .columns
+            .iter()
+            .map(|c| c.name.clone())
+            .collect::<Vec<String>>();
+        for s in &self.select {
+            names.extend(s.names());
+        }
+        if let Some(first) = self.union_all.first() {
+            names.extend(first.names());
+        }
+        names
+    }
+
+    fn rows(&self, node: &Value, resource: &Value) -> Result<Vec<Row>, String> {
+        let foci = match &self.for_each {
+            Some(path) => path.evaluate_in(node, resource)?,
+            None => vec![node.clone()],
+        };
+        if foci.is_empty() && self.or_null {
+            return Ok(vec![
+                self.names()
+                    .into_iter()
+                    .map(|name| (name, Value::Null))
+                    .collect(),
+            ]);
+        }
+        let mut out = Vec::new();
+        for focus in &foci {
+            let mut row = Row::new();
+            for column in &self.columns {
+                row.insert(column.name.clone(), column.value(focus, resource)?);
+            }
+            let mut rows = vec![row];
+            for s in &self.select {
+                rows = product(rows, s.rows(focus, resource)?);
+            }
+            if !self.union_all.is_empty() {
+                let mut union = Vec::new();
+                for s in &self.union_all {
+                    union.extend(s.rows(focus, resource)?);
+                }
+                rows = product(rows, union);
+            }
+            out.extend(rows);
+        }
+        Ok(out)
+    }
+}
+
+impl ViewColumn {
+    fn value(&self, focus: &Value, resource: &Value) -> Result<Value, String> {
+        let mut values = self.path.evaluate_in(focus, resource)?;
+        if self.collection {
+            return Ok(Value::Array(values));
+        }
+        match values.len() {
+            0 => Ok(Value::Null),
+            1 => Ok(values.remove(0)),
+            n => Err(format!(
+                "column `{}` has {} values, but is not a `collection`",
+                self.name, n
+            )),
+        }
+    }
+}
+
+/**
+ * Every row of `left` joined with every row of `right`.
+ */
+fn product(left: Vec<Row>, right: Vec<Row>) -> Vec<Row> {
+    left.iter()
+        .flat_map(|l| {
+            right.iter().map(move |r| {
+                let mut row = l.clone();
+                row.extend(r.clone());
+                row
+            })
+        })
+        .collect()
+}
+
+fn array<'a>(v: &'a Value, key: &str) -> &'a [Value] {
+    v.get(key)
+        .and_then(|a| a.as_array())
+        .map(|a| a.as_slice())
+        .unwrap_or_default()
+}
+
+/**
+ * The constants of a view as FHIRPath literals, e.g. `'home'` for `valueString`, `5` for `valueInteger`
+ * or `@2024-01-31` for `valueDate`.
+ */
+fn constants(v: &Value) -> Result<Vec<(String, String)>, String> {
+    array(v, "constant")
+        .iter()
+        .map(|c| {
+            let name = c
+                .get("name")
+                .and_then(|n| n.as_str())
+                .ok_or("a constant has no `name`")?;
+            let value = c
+                .as_object()
+                .and_then(|map| map.iter().find(|(k, _)| k.starts_with("value")));
+            let literal = match value {
+                Some((key, Value::String(s)))
+                    if ["valueDate", "valueDateTime", "valueInstant"].contains(&key.as_str()) =>
+                {
+                    format!("@{}", s)
+                }
+                Some((key, Value::String(s))) if key == "valueTime" => format!("@T{}", s),
+                Some((_, Value::String(s))) => {
+                    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+                }
+                Some((_, value @ (Value::Number(_) | Value::Bool(_)))) => value.to_string(),
+                _ => return Err(format!("constant `{}` has no value", name)),
+            };
+            Ok((name.to_string(), literal))
+        })
+        .collect()
+}
+
+/**
+ * Parse a path with the `%name` of constants replaced by their values, string literals and the
+ * environment variables of FHIRPath, like `%resource`, are left alone.
+ */
+fn parse_path(path: &str, constants: &[(String, String)]) -> Result<FhirPath, String> {
+    static TOKEN: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"'(?:[^'\\]|\\.)*'|%([A-Za-z_][A-Za-z0-9_]*)").unwrap());
+    let mut unknown = None;
+    let substituted = TOKEN.replace_all(path, |caps: &regex::Captures| match caps.get(1) {
+        Some(name) => match constants.iter().find(|(c, _)| c == name.as_str()) {
+            Some((_, literal)) => literal.clone(),
+            None if fhirpath::VARIABLES.contains(&name.as_str()) => caps[0].to_string(),
+            None => {
+                unknown.get_or_insert_with(|| name.as_str().to_string());
+                caps[0].to_string()
+            }
+        },
+        None => caps[0].to_string(),
+    });
+    if let Some(name) = unknown {
+        return Err(format!("`{}`: unknown constant `%{}`", path, name));
+    }
+    FhirPath::parse(&substituted).map_err(|e| format!("`{}`: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_view_definition() {
+        let view = ViewDefinition::from_json(&json!({
+            "resourceType": "ViewDefinition",
+            "resource": "Patient",
+            "constant": [{ "name": "use", "valueCode": "home" }],
+            "where": [{ "path": "active" }],
+            "select": [
+                { "column": [
+                    { "name": "id", "path": "getResourceKey()" },
+                    { "name": "given", "path": "name.given", "collection": true }
+                ] },
+                { "forEachOrNull": "address.where(use = %use)", "column": [
+                    { "name": "city", "path": "city" }
+                ] },
+                { "unionAll": [
+                    { "forEach": "telecom", "column": [{ "name": "contact", "path": "value" }] },
+                    { "forEach": "contact.telecom", "column": [{ "name": "contact", "path": "value" }] }
+                ] }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(view.columns(), vec!["id", "given", "city", "contact"]);
+
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "p1",
+            "active": true,
+            "name": [{ "given": ["Ann", "Marie"] }],
+            "address": [{ "use": "work", "city": "Bern" }],
+            "telecom": [{ "value": "555-1" }],
+            "contact": [{ "telecom": [{ "value": "555-2" }] }]
+        });
+        let rows = view.rows(&patient).unwrap();
+        assert_eq!(
+            serde_json::to_value(&rows).unwrap(),
+            json!([
+                { "id": "p1", "given": ["Ann", "Marie"], "city": null, "contact": "555-1" },
+                { "id": "p1", "given": ["Ann", "Marie"], "city": null, "contact": "555-2" }
+            ])
+        );
+        // filtered by `where` and resource type
+        assert!(
+            view.rows(&json!({ "resourceType": "Patient", "active": false }))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            view.rows(&json!({ "resourceType": "Observation" }))
+                .unwrap()
+                .is_empty()
+        );
+
+        let invalid = |v: Value| ViewDefinition::from_json(&v).unwrap_err();
+        assert_eq!(
+            invalid(
+                json!({ "resource": "Patient", "select": [{ "column": [{ "name": "a", "path": "%x" }] }] })
+            ),
+            "column `a`: `%x`: unknown constant `%x`"
+        );
+        assert_eq!(
+            invalid(json!({ "resource": "Patient", "select": [
+                { "column": [{ "name": "a", "path": "id" }] },
+                { "forEach": "name", "column": [{ "name": "a", "path": "family" }] }
+            ] })),
+            "column `a` is defined twice (#2)"
+        );
+        let multiple = ViewDefinition::from_json(&json!({
+            "resource": "Patient",
+            "select": [{ "column": [{ "name": "given", "path": "name.given" }] }]
+        }))
+        .unwrap();
+        assert_eq!(
+            multiple.rows(&patient).unwrap_err(),
+            "column `given` has 2 values, but is not a `collection`"
+        );
+
+        // environment variables and date constants
+        let view = ViewDefinition::from_json(&json!({
+            "resource": "Patient",
+            "constant": [{ "name": "since", "valueDate": "2020-01-01" }],
+            "where": [{ "path": "birthDate >= %since" }],
+            "select": [{ "forEach": "name", "column": [
+                { "name": "patient", "path": "%resource.id" },
+                { "name": "given", "path": "%context.given.first()" }
+            ] }]
+        }))
+        .unwrap();
+        let patient = json!({ "resourceType": "Patient", "id": "p2", "birthDate": "2021-05-03", "name": [{ "given": ["Eve"] }] });
+        assert_eq!(
+            serde_json::to_value(view.rows(&patient).unwrap()).unwrap(),
+            json!([{ "patient": "p2", "given": "Eve" }])
+        );
+        assert_eq!(
+            constants(&json!({ "constant": [
+                { "name": "d", "valueDateTime": "2020-01-01T10:00:00Z" },
+                { "name": "t", "valueTime": "10:00:00" },
+                { "name": "s", "valueString": "it's" }
+            ] }))
+            .unwrap(),
+            vec![
+                ("d".to_string(), "@2020-01-01T10:00:00Z".to_string()),
+                ("t".to_string(), "@T10:00:00".to_string()),
+                ("s".to_string(), "'it\\'s'".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,662 @@
+use regex::Regex;
+use saphyr_parser::{Event, ScalarStyle};
+use serde_json::Value;
+use std::sync::LazyLock;
+
+/**
+ * Line width of the YAML output unless another one is given, see `HuffBuilder::with_line_width`.
+ */
+pub const DEFAULT_WIDTH: usize = 100;
+
+/**
+ * Emit YAML for the HUFF output, in block style like most YAML emitters, but:
+ * - arrays of short scalars (without whitespace, like codes or given names) are written in flow
+ *   style (`[vital-signs, exam]`) if they fit into the line,
+ * - multi-line strings (like a rendered narrative) are literal blocks (`|`),
+ * - strings are only quoted if a YAML parser would read them otherwise, i.e. as another type
+ *   (including YAML 1.1 booleans like `yes`) or as YAML syntax,
+ * - long plain strings are folded into several lines at spaces to keep within `width`.
+ *
+ * A `width` of 0 means unlimited: no folding, and all arrays of short scalars in flow style.
+ */
+pub fn to_yaml(v: &Value, width: usize) -> String {
+    let emitter = Emitter { width };
+    let mut out = String::new();
+    match v {
+        Value::Object(map) if !map.is_empty() => emitter.mapping(map, 0, &mut out),
+        Value::Array(arr) if !emitter.is_inline(v, 0) => emitter.sequence(arr, 0, &mut out),
+        _ => {
+            out.push_str(&emitter.value(v, 0, 0));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+struct Emitter {
+    width: usize,
+}
+
+impl Emitter {
+    fn mapping(&self, map: &serde_json::Map<String, Value>, indent: usize, out: &mut String) {
+        for (k, v) in map {
+            let key = format!("{}{}:", " ".repeat(indent), scalar(k));
+            if self.is_inline(v, key.len() + 1) {
+                out.push_str(&format!(
+                    "{} {}\n",
+                    key,
+                    self.value(v, indent, key.len() + 1)
+                ));
+                continue;
+            }
+            out.push_str(&key);
+            out.push('\n');
+            match v {
+                Value::Object(map2) => self.mapping(map2, indent + 2, out),
+                // like most emitters, sequences in mappings are not indented
+                Value::Array(arr) => self.sequence(arr, indent, out),
+                _ => {}
+            }
+        }
+    }
+
+    fn sequence(&self, arr: &[Value], indent: usize, out: &mut String) {
+        let marker = format!("{}- ", " ".repeat(indent));
+        for v in arr {
+            if self.is_inline(v, marker.len()) {
+                out.push_str(&format!(
+                    "{}{}\n",
+                    marker,
+                    self.value(v, indent, marker.len())
+                ));
+                continue;
+            }
+            // the first line of a nested block starts right after the marker
+            let mut nested = String::new();
+            match v {
+                Value::Object(map) => self.mapping(map, indent + 2, &mut nested),
+                Value::Array(arr2) => self.sequence(arr2, indent + 2, &mut nested),
+                _ => {}
+            }
+            out.push_str(&marker);
+            out.push_str(&nested[indent + 2..]);
+        }
+    }
+
+    /**
+     * Values that are written on the line of their key or list marker, starting at `column`.
+     */
+    fn is_inline(&self, v: &Value, column: usize) -> bool {
+        match v {
+            Value::Object(map) => map.is_empty(),
+            Value::Array(arr) if arr.is_empty() => true,
+            Value::Array(arr) => {
+                arr.iter().all(|v2| match v2 {
+                    Value::String(s) => !s.is_empty() && !s.contains(char::is_whitespace),
+                    _ => !v2.is_object() && !v2.is_array(),
+                }) && (self.width == 0 || column + flow(arr).len() <= self.width)
+            }
+            _ => true,
+        }
+    }
+
+    /**
+     * An inline value, continuation lines (of folded strings and literal blocks) are indented
+     * deeper than `indent`, the indentation of their key or list marker.
+     */
+    fn value(&self, v: &Value, indent: usize, column: usize) -> String {
+        match v {
+            Value::Object(_) => "{}".to_string(),
+            Value::Array(arr) => flow(arr),
+            // a string of only line breaks would be an empty literal block, which reads as ""
+            Value::String(s)
+                if s.contains('\n')
+                    && s.contains(|c| c != '\n')
+                    && !s.contains(is_special_char) =>
+            {
+                literal(s, indent + 2)
+            }
+            Value::String(s) if needs_quotes(s) => quote(s),
+            Value::String(s) => self.fold(s, indent + 2, column),
+            _ => v.to_string(),
+        }
+    }
+
+    /**
+     * Break a plain string into lines at single spaces before words. A parser joins the lines
+     * with a space again.
+     */
+    fn fold(&self, s: &str, indent: usize, column: usize) -> String {
+        if self.width == 0 || column + s.chars().count() <= self.width {
+            return s.to_string();
+        }
+        let mut words = Vec::new();
+        let mut start = 0;
+        for (i, _) in s.match_indices(' ') {
+            let before = s[..i].chars().last();
+            let after = s[i + 1..].chars().next();
+            if before.is_some_and(|c| c != ' ') && after.is_some_and(|c| c.is_alphanumeric()) {
+                words.push(&s[start..i]);
+                start = i + 1;
+            }
+        }
+        words.push(&s[start..]);
+        let mut out = String::new();
+        let mut line_length = column;
+        for (i, word) in words.iter().enumerate() {
+            let length = word.chars().count();
+            if i > 0 && line_length + 1 + length > self.width {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                line_length = indent;
+            } else if i > 0 {
+                out.push(' ');
+                line_length += 1;
+            }
+            out.push_str(word);
+            line_length += length;
+        }
+        out
+    }
+}
+
+/**
+ * A key or a scalar in a flow sequence, which can't be folded. In flow context, `:` can also
+ * end a plain scalar and `-`, `?` or `:` can't start one, even without a space after them.
+ */
+fn scalar(s: &str) -> String {
+    let flow_syntax = s.contains([',', '[', ']', '{', '}', ':']) || s.starts_with(['-', '?']);
+    match needs_quotes(s) || flow_syntax {
+        true => quote(s),
+        false => s.to_string(),
+    }
+}
+
+fn flow(arr: &[Value]) -> String {
+    let items = arr
+        .iter()
+        .map(|v| match v {
+            Value::String(s) => scalar(s),
+            _ => v.to_string(),
+        })
+        .collect::<Vec<String>>();
+    format!("[{}]", items.join(", "))
+}
+
+/**
+ * A literal block scalar with the lines of `s` indented by `indent`.
+ */
+fn literal(s: &str, indent: usize) -> String {
+    // strip: no line break at the end, keep: more than one
+    let (chomping, content) = match s.strip_suffix('\n') {
+        None => ("-", s),
+        Some(rest) if rest.ends_with('\n') => ("+", rest),
+        Some(rest) => ("", rest),
+    };
+    // the indentation of the block is taken from its first line, unless it is given
+    let indentation = match content.lines().find(|line| !line.is_empty()) {
+        Some(line) if line.starts_with(' ') => "2",
+        _ => "",
+    };
+    let mut out = format!("|{}{}", indentation, chomping);
+    for line in content.split('\n') {
+        out.push('\n');
+        if !line.is_empty() {
+            out.push_str(&" ".repeat(indent));
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+fn quote(s: &str) -> String {
+    match s.contains(is_special_char) || s.contains('\n') {
+        // double quotes have the same escapes as JSON
+        true => Value::String(s.to_string()).to_string(),
+        false => format!("'{}'", s.replace('\'', "''")),
+    }
+}
+
+/**
+ * Control characters other than line breaks, they need escaping in double quotes.
+ */
+fn is_special_char(c: char) -> bool {
+    c.is_control() && c != '\n'
+}
+
+/**
+ * Whether a plain scalar would not be read as this string.
+ */
+fn needs_quotes(s: &str) -> bool {
+    const WORDS: &[&str] = &[
+        "~", "null", "Null", "NULL", "true", "True", "TRUE", "false", "False", "FALSE", "yes",
+        "Yes", "YES", "no", "No", "NO", "on", "On", "ON", "off", "Off", "OFF", "y", "Y", "n", "N",
+    ];
+    static NUMBER: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(concat!(
+            r"^(?:[-+]?[0-9][0-9_]*(?::[0-5]?[0-9])*(?:\.[0-9_]*)?(?:[eE][-+]?[0-9]+)?",
+            r"|[-+]?\.[0-9][0-9_]*(?:[eE][-+]?[0-9]+)?",
+            r"|0x[0-9a-fA-F_]+|0o[0-7_]+|0b[01_]+",
+            r"|[-+]?\.(?:inf|Inf|INF)|\.(?:nan|NaN|NAN))$"
+        ))
+        .unwrap()
+    });
+    let mut chars = s.chars();
+    let first = chars.next();
+    let second = chars.next();
+    s.is_empty()
+        || s.trim() != s
+        || WORDS.contains(&s)
+        || NUMBER.is_match(s)
+        || s.starts_with("---")
+        || s.starts_with("...")
+        || first.is_some_and(|c| "#,[]{}&*!|>'\"%@`".contains(c))
+        || (first.is_some_and(|c| "-?:".contains(c)) && second.is_none_or(|c| c.is_whitespace()))
+        || s.contains(": ")
+        || s.contains(" #")
+        || s.ends_with(':')
+        || s.contains(|c: char| c.is_control())
+}
+
+/**
+ * A parsed YAML node with the (1-based) line it starts on, so that errors in YAML input can point
+ * at the source; for values in a mapping, that is the line of their key. Scalars are typed like
+ * JSON (YAML 1.2 core schema), mapping keys are strings.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub line: usize,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeKind {
+    Scalar(Value),
+    Sequence(Vec<Node>),
+    Mapping(Vec<(String, Node)>),
+}
+
+impl Node {
+    pub fn to_json(&self) -> Value {
+        match &self.kind {
+            NodeKind::Scalar(v) => v.clone(),
+            NodeKind::Sequence(items) => Value::Array(items.iter().map(Node::to_json).collect()),
+            NodeKind::Mapping(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.to_json()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+ * Parse a YAML document into JSON, see `parse`.
+ */
+pub fn from_yaml(s: &str) -> Result<Value, Error> {
+    Ok(parse(s)?.to_json())
+}
+
+/**
+ * Parse a single YAML document with `saphyr-parser`. Anchors, aliases, tags and complex keys are
+ * rejected, they are not needed for mappings and tables.
+ */
+pub fn parse(s: &str) -> Result<Node, Error> {
+    let mut events = Events {
+        parser: saphyr_parser::Parser::new_from_str(s),
+        line: 1,
+    };
+    let mut node = None;
+    loop {
+        match events.next()? {
+            Event::StreamStart | Event::DocumentEnd => {}
+            Event::DocumentStart(_) if node.is_some() => {
+                return events.error("only a single document is supported");
+            }
+            Event::DocumentStart(_) => {
+                let event = events.next()?;
+                node = Some(events.node(event)?);
+            }
+            Event::StreamEnd => break,
+            _ => return events.error("unexpected content"),
+        }
+    }
+    Ok(node.unwrap_or(Node {
+        line: 1,
+        kind: NodeKind::Scalar(Value::Null),
+    }))
+}
+
+/**
+ * The events of the parser with the line of the last one.
+ */
+struct Events<'a> {
+    parser: saphyr_parser::Parser<'a, saphyr_parser::StrInput<'a>>,
+    line: usize,
+}
+
+impl<'a> Events<'a> {
+    fn next(&mut self) -> Result<Event<'a>, Error> {
+        match self.parser.next_event() {
+            Some(Ok((event, span))) => {
+                self.line = span.start.line();
+                Ok(event)
+            }
+            Some(Err(e)) => Err(Error {
+                line: e.marker().line(),
+                message: e.info().to_string(),
+            }),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        Err(Error {
+            line: self.line,
+            message: message.to_string(),
+        })
+    }
+
+    fn node(&mut self, event: Event<'a>) -> Result<Node, Error> {
+        let line = self.line;
+        let kind = match event {
+            Event::Alias(_) => return self.error("anchors and aliases are not supported"),
+            Event::Scalar(_, _, anchor, _)
+            | Event::SequenceStart(anchor, _)
+            | Event::MappingStart(anchor, _)
+                if anchor > 0 =>
+            {
+                return self.error("anchors and aliases are not supported");
+            }
+            Event::Scalar(_, _, _, Some(_))
+            | Event::SequenceStart(_, Some(_))
+            | Event::MappingStart(_, Some(_)) => return self.error("tags are not supported"),
+            Event::Scalar(value, ScalarStyle::Plain, _, _) => NodeKind::Scalar(resolve(&value)),
+            Event::Scalar(value, _, _, _) => NodeKind::Scalar(Value::String(value.into_owned())),
+            Event::SequenceStart(_, _) => {
+                let mut items = Vec::new();
+                loop {
+                    match self.next()? {
+                        Event::SequenceEnd => break,
+                        event => items.push(self.node(event)?),
+                    }
+                }
+                NodeKind::Sequence(items)
+            }
+            Event::MappingStart(_, _) => {
+                let mut entries: Vec<(String, Node)> = Vec::new();
+                loop {
+                    let key = match self.next()? {
+                        Event::MappingEnd => break,
+                        Event::Scalar(key, _, 0, None) => key.into_owned(),
+                        _ => return self.error("only scalars are supported as keys"),
+                    };
+                    // values in a mapping get the line of their key
+                    let key_line = self.line;
+                    if entries.iter().any(|(k, _)| *k == key) {
+                        return self.error(&format!("duplicate key `{}`", key));
+                    }
+                    let event = self.next()?;
+                    let value = self.node(event)?;
+                    entries.push((
+                        key,
+                        Node {
+                            line: key_line,
+                            ..value
+                        },
+                    ));
+                }
+                NodeKind::Mapping(entries)
+            }
+            _ => return self.error("unexpected content"),
+        };
+        Ok(Node { line, kind })
+    }
+}
+
+/**
+ * The type of a plain (unquoted) scalar, like in the YAML 1.2 core schema.
+ */
+fn resolve(s: &str) -> Value {
+    match s {
+        "" | "~" | "null" | "Null" | "NULL" => return Value::Null,
+        "true" | "True" | "TRUE" => return Value::Bool(true),
+        "false" | "False" | "FALSE" => return Value::Bool(false),
+        _ => {}
+    }
+    let digits = s.trim_start_matches(['-', '+']);
+    let radix = [("0x", 16), ("0o", 8)]
+        .into_iter()
+        .find_map(|(prefix, radix)| Some((s.strip_prefix(prefix)?, radix)));
+    if let Some((digits, radix)) = radix {
+        return match i64::from_str_radix(digits, radix) {
+            Ok(n) if !digits.starts_with(['-', '+']) => Value::from(n),
+            _ => Value::String(s.to_string()),
+        };
+    }
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        if let Ok(n) = s.trim_start_matches('+').parse::<i64>() {
+            return Value::from(n);
+        }
+        if let Ok(n) = s.trim_start_matches('+').parse::<u64>() {
+            return Value::from(n);
+        }
+    }
+    let is_float = digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && digits.contains(|c: char| c.is_ascii_digit())
+        && digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || ".eE+-".contains(c));
+    match s.parse::<f64>().ok().filter(|_| is_float) {
+        Some(n) if n.is_finite() => Value::from(n),
+        _ => Value::String(s.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_yaml() {
+        let tree = json!({
+            "resourceType": "Observation",
+            "@rule": "line 3",
+            "category": ["vital-signs", "exam"],
+            "flags": [":wordnull", "-x", "?y", "a:b"],
+            "code": { "coding": ["http://loinc.org | 8480-6 | Systolic", "http://snomed.info/sct | 271649006"] },
+            "valueString": "120",
+            "note": ["yes", "a: b", "it's", "", " x", "tab\there", "- item", " x\ny"],
+            "interpretation": "The blood pressure is a bit higher than it should be, please measure again tomorrow",
+            "component": [{ "code": "8480-6", "value": 120 }, []],
+            "empty": {},
+            "issued": "2024-01-01T10:00:00Z",
+            "time": "12:30",
+            "text": { "div": "line 1\n  line 2\n", "indented": "  a\nb", "kept": "a\n\n" }
+        });
+        let yaml = to_yaml(&tree, 60);
+        assert_eq!(
+            yaml,
+            "resourceType: Observation\n\
+             '@rule': line 3\n\
+             category: [vital-signs, exam]\n\
+             flags: [':wordnull', '-x', '?y', 'a:b']\n\
+             code:\n  \
+             coding:\n  \
+             - http://loinc.org | 8480-6 | Systolic\n  \
+             - http://snomed.info/sct | 271649006\n\
+             valueString: '120'\n\
+             note:\n\
+             - 'yes'\n\
+             - 'a: b'\n\
+             - it's\n\
+             - ''\n\
+             - ' x'\n\
+             - \"tab\\there\"\n\
+             - '- item'\n\
+             - |2-\n   x\n  y\n\
+             interpretation: The blood pressure is a bit higher than it\n  \
+             should be, please measure again tomorrow\n\
+             component:\n\
+             - code: 8480-6\n  \
+             value: 120\n\
+             - []\n\
+             empty: {}\n\
+             issued: 2024-01-01T10:00:00Z\n\
+             time: '12:30'\n\
+             text:\n  \
+             div: |\n    line 1\n      line 2\n  \
+             indented: |2-\n      a\n    b\n  \
+             kept: |+\n    a\n\n"
+        );
+        // parsers read the same tree back
+        let parsed = from_yaml(&yaml).unwrap();
+        assert_eq!(parsed, tree);
+        assert_eq!(to_yaml(&json!(["a", "b"]), 0), "[a, b]\n");
+        assert_eq!(to_yaml(&json!(null), 0), "null\n");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let strings = [
+            "\n",
+            "\n\n",
+            "\na",
+            "a\n\n\n",
+            " lead",
+            "trail ",
+            " \n",
+            "key: value",
+            "key:",
+            "#",
+            "# comment",
+            "a #b",
+            "- ",
+            "-",
+            "- item",
+            "-x",
+            "[a]",
+            "{}",
+            "*a",
+            "&a",
+            "!a",
+            "|",
+            ">",
+            "@a",
+            "%a",
+            "`a`",
+            "'",
+            "\"",
+            "a'b",
+            "1e3",
+            "0x1F",
+            ".5",
+            "12:30",
+            "~",
+            "null",
+            "no",
+            "---",
+            "...",
+            "a\tb",
+            "tab\t\nb",
+        ];
+        for s in strings {
+            let tree = json!({ "key": s, "list": [s, { "k": s }], "flow": [s, "x"] });
+            for width in [0, 10] {
+                let yaml = to_yaml(&tree, width);
+                assert_eq!(from_yaml(&yaml).unwrap(), tree, "{:?} as\n{}", s, yaml);
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_yaml() {
+        let yaml = r#"
+# a comment
+--- 
+rules:
+  - include: default   # trailing comment
+  - signature: [system, 'code']
+    format: "{$.system}\t| \u00e9"
+    values: { male: ♂ male, "1": http://x.org/a, empty: , n: 1.5 }
+    note: a long line
+      folded # not part of it
+    folded: >
+      one
+      two
+
+      three
+    kept: |+
+      a
+
+  -
+  - - nested
+    - 12
+empty:
+list:
+- yes
+- ~
+- 'it''s'
+- -3
+- "multi
+  line"
+"#;
+        assert_eq!(
+            from_yaml(yaml).unwrap(),
+            json!({
+                "rules": [
+                    { "include": "default" },
+                    {
+                        "signature": ["system", "code"],
+                        "format": "{$.system}\t| é",
+                        "values": { "male": "♂ male", "1": "http://x.org/a", "empty": null, "n": 1.5 },
+                        "note": "a long line folded",
+                        "folded": "one two\nthree\n",
+                        "kept": "a\n\n"
+                    },
+                    null,
+                    ["nested", 12]
+                ],
+                "empty": null,
+                "list": ["yes", null, "it's", -3, "multi line"]
+            })
+        );
+        assert_eq!(from_yaml("").unwrap(), Value::Null);
+        assert_eq!(from_yaml("[a, b]").unwrap(), json!(["a", "b"]));
+
+        let error = |yaml: &str| parse(yaml).unwrap_err().to_string();
+        // syntax errors come from the parser, with the line they are in
+        assert!(error("a: 1\nb: [1, 2\n").starts_with("line 3: "));
+        assert!(error("a:\n  b: 1\n   c: 2").starts_with("line 3: "));
+        assert_eq!(error("a: 1\na: 2"), "line 2: duplicate key `a`");
+        assert_eq!(
+            error("a: &x 1\nb: *x"),
+            "line 1: anchors and aliases are not supported"
+        );
+        assert_eq!(error("a: !!str 1"), "line 1: tags are not supported");
+        assert_eq!(
+            error("? [a]\n: 1"),
+            "line 1: only scalars are supported as keys"
+        );
+        assert_eq!(
+            error("a: 1\n---\nb: 2"),
+            "line 2: only a single document is supported"
+        );
+    }
+}
@@ -1,19 +1,242 @@
-use clap::Parser;
-use serde_json;
-use std::io::{self, BufRead};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::{self, BufRead, IsTerminal};
 use std::path::Path;
+use std::process::ExitCode;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(flatten)]
+    render: RenderArgs,
+
+    #[command(flatten)]
+    yaml: YamlArgs,
+
+    /// Annotate each object of the YAML or HTML output with its signature and the line of the mapping rule that matched
+    #[arg(long)]
+    explain: bool,
+
+    /// Output format: yaml, html for a standalone page with collapsible sections and links, markdown,
+    /// or fsh for a FHIR Shorthand instance of the input (the mapping does not apply)
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = OutputFormat::Yaml)]
+    format: OutputFormat,
+
+    /// Base URL of the FHIR server that references are linked to in HTML output
+    #[arg(long, value_name = "URL")]
+    base_url: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/**
+ * How resources are rendered, for the commands that render them.
+ */
+#[derive(clap::Args, Debug)]
+struct RenderArgs {
     /// Path to a custom mapping file in *.hfc format
     #[arg(short, long)]
     mapping: Option<String>,
+
+    /// Follow references up to N levels and render the referenced resources inline
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    resolve_depth: usize,
+
+    /// Directory with FHIR resources (*.json, *.ndjson) that references are also resolved against
+    #[arg(long, value_name = "DIR")]
+    resources: Option<String>,
+
+    /// CodeSystem or ValueSet (FHIR JSON) or CSV code table to fill in missing displays from, can be repeated
+    #[arg(long, value_name = "FILE")]
+    terminology: Vec<String>,
+
+    /// What to do with the XHTML narrative of resources: keep, text, markdown or drop (if there is structured data)
+    #[arg(long, value_name = "MODE", default_value = "keep")]
+    narrative: hff_rs::narrative::Narrative,
+}
+
+/**
+ * How YAML output is written, for the commands that print HUFF YAML.
+ */
+#[derive(clap::Args, Debug)]
+struct YamlArgs {
+    /// Highlight the output with ANSI colors, `auto` if stdout is a terminal and NO_COLOR is not set
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Fold long strings of the YAML output at this line width, 0 for no limit
+    #[arg(long, value_name = "N", default_value_t = hff_rs::yaml::DEFAULT_WIDTH)]
+    width: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Yaml,
+    Html,
+    Markdown,
+    Fsh,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /**
+     * Whether to color stdout: `auto` colors terminals unless `NO_COLOR` is set (to anything but
+     * an empty string) or `TERM` is `dumb`.
+     */
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                io::stdout().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::env::var_os("TERM").is_none_or(|term| term != "dumb")
+            }
+        }
+    }
 }
 
-fn main() {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show a structural diff of two FHIR resources in HUFF form
+    Diff {
+        #[command(flatten)]
+        render: RenderArgs,
+        /// Highlight the diff with ANSI colors, `auto` if stdout is a terminal and NO_COLOR is not set
+        #[arg(long, value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+        color: ColorChoice,
+        /// Path to the old FHIR resource in JSON format
+        old: String,
+        /// Path to the new FHIR resource in JSON format
+        new: String,
+    },
+    /// Flatten FHIR resources into a CSV (or TSV) table of HUFF-rendered values
+    Table {
+        #[command(flatten)]
+        render: RenderArgs,
+        /// Comma separated key paths of the reformatted resources, e.g. 'id, code, value, effective'
+        #[arg(long, required = true)]
+        columns: String,
+        /// Separate cells with tabs instead of commas
+        #[arg(long)]
+        tsv: bool,
+        /// FHIR resources in JSON or NDJSON format, Bundles contribute a row per entry
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Flatten FHIR resources with a SQL on FHIR ViewDefinition into CSV (or TSV) or HUFF rows
+    View {
+        #[command(flatten)]
+        render: RenderArgs,
+        #[command(flatten)]
+        yaml: YamlArgs,
+        /// Path to the ViewDefinition in JSON format
+        view: String,
+        /// Separate cells with tabs instead of commas
+        #[arg(long, conflicts_with = "huff")]
+        tsv: bool,
+        /// Print the rows as HUFF YAML instead of a table
+        #[arg(long)]
+        huff: bool,
+        /// FHIR resources in JSON or NDJSON format, Bundles contribute the rows of their entries
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Tools for working on mapping files
+    Mapping {
+        #[command(subcommand)]
+        command: MappingCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MappingCommand {
+    /// Report object signatures in a corpus that no rule matches and suggest rules for them
+    Suggest {
+        #[command(flatten)]
+        render: RenderArgs,
+        /// FHIR resources in JSON or NDJSON format, the entries of Bundles are covered one by one
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Check mapping files for errors and suspicious rules
+    Check {
+        /// Path to a custom mapping file, used if no mapping files are given
+        #[arg(short, long)]
+        mapping: Option<String>,
+        /// Mapping files in *.hfc format, defaults to the mapping given with -m or the default mapping
+        files: Vec<String>,
+    },
+    /// Run the `@test` examples of mapping files
+    Test {
+        /// Path to a custom mapping file, used if no mapping files are given
+        #[arg(short, long)]
+        mapping: Option<String>,
+        /// Mapping files in *.hfc format, defaults to the mapping given with -m or the default mapping
+        files: Vec<String>,
+    },
+    /// Convert a mapping file from *.hfc to the structured YAML format or back
+    Convert {
+        /// Mapping file in *.hfc or YAML format
+        file: String,
+    },
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
+    match &args.command {
+        Some(Command::Diff {
+            render,
+            color,
+            old,
+            new,
+        }) => with_builder(render, |huff| diff(render, &huff, *color, old, new)),
+        Some(Command::Table {
+            render,
+            columns,
+            tsv,
+            files,
+        }) => with_builder(render, |huff| table(render, &huff, columns, *tsv, files)),
+        Some(Command::View {
+            render,
+            yaml,
+            view: view_path,
+            tsv,
+            huff: huff_rows,
+            files,
+        }) => with_builder(render, |huff| {
+            let huff = huff.with_line_width(yaml.width);
+            view(render, &huff, yaml, view_path, *tsv, *huff_rows, files)
+        }),
+        Some(Command::Mapping {
+            command: MappingCommand::Suggest { render, files },
+        }) => with_builder(render, |huff| suggest(render, &huff, files)),
+        Some(Command::Mapping {
+            command: MappingCommand::Check { mapping, files },
+        }) => check(mapping.as_ref(), files),
+        Some(Command::Mapping {
+            command: MappingCommand::Test { mapping, files },
+        }) => test(mapping.as_ref(), files),
+        Some(Command::Mapping {
+            command: MappingCommand::Convert { file },
+        }) => convert(file),
+        None => with_builder(&args.render, |huff| {
+            let huff = huff.with_explain(args.explain);
+            convert_stdin(&args, &huff.with_line_width(args.yaml.width))
+        }),
+    }
+}
 
+/**
+ * Render the resource on stdin, or print the error and exit with 1.
+ */
+fn convert_stdin(args: &Args, huff: &hff_rs::HuffBuilder) -> ExitCode {
     let stdin = io::stdin();
     let mut handle = stdin.lock();
 
@@ -24,28 +247,424 @@ fn main() {
             Ok(_) => {}
             Err(e) => {
                 eprintln!("Error reading from stdin: {}", e);
-                break; // Stop on error
+                return ExitCode::FAILURE;
             }
         }
     }
 
-    match &serde_json::from_str(&buffer) {
-        Ok(response) => match args.mapping {
-            Some(mapping) => {
-                println!(
-                    "{}",
-                    hff_rs::builder()
-                        .with_file(&Path::new(&mapping))
-                        .run(response)
-                        .unwrap()
-                );
+    let response = match serde_json::from_str(&buffer) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error parsing JSON: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let output = match args.format {
+        OutputFormat::Yaml => {
+            render(&args.render, huff, &response).map(|yaml| match args.yaml.color.enabled() {
+                true => format!("{}\n", hff_rs::highlight::highlight_yaml(&yaml)),
+                false => format!("{}\n", yaml),
+            })
+        }
+        OutputFormat::Html => {
+            let options = hff_rs::html::HtmlOptions {
+                base_url: args.base_url.clone(),
+                links: true,
+            };
+            reformat(&args.render, huff, &response)
+                .map(|tree| hff_rs::html::to_html_document(&tree, &options))
+        }
+        OutputFormat::Markdown => {
+            reformat(&args.render, huff, &response).map(|tree| hff_rs::markdown::to_markdown(&tree))
+        }
+        OutputFormat::Fsh => hff_rs::fsh::to_fsh(&response).map_err(|e| e.into()),
+    };
+    match output {
+        Ok(output) => {
+            print!("{}", output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/**
+ * The builder for the options given on the command line, with the terminology files and the
+ * resources directory loaded once.
+ */
+fn builder(render: &RenderArgs) -> io::Result<hff_rs::HuffBuilder> {
+    let mut terminology = hff_rs::terminology::Terminology::new();
+    for path in &render.terminology {
+        terminology.load_file(Path::new(path))?;
+    }
+    let mut huff = hff_rs::builder()
+        .with_resolve_depth(render.resolve_depth)
+        .with_terminology(terminology)
+        .with_narrative(render.narrative);
+    if let Some(dir) = &render.resources {
+        let resources = hff_rs::resolve::DirectoryResolver::new(Path::new(dir));
+        huff = huff.with_resources(resources.map_err(io::Error::other)?);
+    }
+    Ok(huff)
+}
+
+/**
+ * Run a command with the builder, or exit with 2 if the files it needs can't be loaded.
+ */
+fn with_builder(
+    render: &RenderArgs,
+    run: impl FnOnce(hff_rs::HuffBuilder) -> ExitCode,
+) -> ExitCode {
+    match builder(render) {
+        Ok(huff) => run(huff),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/**
+ * Render a FHIR resource with the mapping given on the command line.
+ */
+fn render(
+    render: &RenderArgs,
+    huff: &hff_rs::HuffBuilder,
+    fhir_obj: &serde_json::Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match &render.mapping {
+        Some(mapping) => huff.with_file(Path::new(mapping)).run(fhir_obj),
+        None => huff.run(fhir_obj),
+    }
+}
+
+/**
+ * Reformat a FHIR resource with the mapping given on the command line.
+ */
+fn reformat(
+    render: &RenderArgs,
+    huff: &hff_rs::HuffBuilder,
+    fhir_obj: &serde_json::Value,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    match &render.mapping {
+        Some(mapping) => huff.with_file(Path::new(mapping)).reformat(fhir_obj),
+        None => huff.reformat(fhir_obj),
+    }
+}
+
+fn read_json_file(path: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(serde_json::from_str(&content).map_err(|e| format!("{}: {}", path, e))?)
+}
+
+/**
+ * Read a file with either a single JSON resource or one resource per line (NDJSON).
+ */
+fn read_resources(path: &str) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    if let Ok(resource) = serde_json::from_str(&content) {
+        return Ok(vec![resource]);
+    }
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e).into())
+        })
+        .collect()
+}
+
+fn table(
+    render: &RenderArgs,
+    huff: &hff_rs::HuffBuilder,
+    columns: &str,
+    tsv: bool,
+    files: &[String],
+) -> ExitCode {
+    let columns = match hff_rs::table::parse_columns(columns) {
+        Ok(columns) => columns,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let mut trees = Vec::new();
+    for path in files {
+        let reformatted = read_resources(path).and_then(|resources| {
+            resources
+                .iter()
+                .flat_map(bundle_entries)
+                .map(|r| reformat(render, huff, r))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        match reformatted {
+            Ok(reformatted) => trees.extend(reformatted),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    let delimiter = if tsv { '\t' } else { ',' };
+    print!(
+        "{}",
+        hff_rs::table::to_delimited(&columns, &trees, delimiter)
+    );
+    ExitCode::SUCCESS
+}
+
+/**
+ * Run a ViewDefinition over the resources of the files. Values that are not primitives, like a
+ * Coding or a Reference, are rendered with the mapping, just like the rest of a row.
+ */
+fn view(
+    render: &RenderArgs,
+    huff: &hff_rs::HuffBuilder,
+    yaml_args: &YamlArgs,
+    view_path: &str,
+    tsv: bool,
+    huff_rows: bool,
+    files: &[String],
+) -> ExitCode {
+    let view = match read_json_file(view_path)
+        .and_then(|v| Ok(hff_rs::viewdef::ViewDefinition::from_json(&v)?))
+    {
+        Ok(view) => view,
+        Err(e) => {
+            eprintln!("Error: {}: {}", view_path, e);
+            return ExitCode::from(2);
+        }
+    };
+    let mut trees = Vec::new();
+    for path in files {
+        let reformatted = read_resources(path).and_then(|resources| {
+            let mut rows = Vec::new();
+            for resource in resources.iter().flat_map(bundle_entries) {
+                for row in view.rows(resource)? {
+                    rows.push(reformat(render, huff, &serde_json::Value::Object(row))?);
+                }
+            }
+            Ok(rows)
+        });
+        match reformatted {
+            Ok(reformatted) => trees.extend(reformatted),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path, e);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    if huff_rows {
+        let yaml = hff_rs::yaml::to_yaml(&serde_json::Value::Array(trees), yaml_args.width);
+        match yaml_args.color.enabled() {
+            true => print!("{}", hff_rs::highlight::highlight_yaml(&yaml)),
+            false => print!("{}", yaml),
+        }
+        return ExitCode::SUCCESS;
+    }
+    let columns = view
+        .columns()
+        .iter()
+        .map(|name| hff_rs::table::Column::key(name))
+        .collect::<Vec<_>>();
+    let delimiter = if tsv { '\t' } else { ',' };
+    print!(
+        "{}",
+        hff_rs::table::to_delimited(&columns, &trees, delimiter)
+    );
+    ExitCode::SUCCESS
+}
+
+/**
+ * The resources of a Bundle, or the resource itself.
+ */
+fn bundle_entries(resource: &serde_json::Value) -> Vec<&serde_json::Value> {
+    match resource.get("resourceType").and_then(|t| t.as_str()) {
+        Some("Bundle") => resource
+            .get("entry")
+            .and_then(|e| e.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.get("resource"))
+            .collect(),
+        _ => vec![resource],
+    }
+}
+
+fn suggest(render: &RenderArgs, huff: &hff_rs::HuffBuilder, files: &[String]) -> ExitCode {
+    let huff = huff.clone().with_explain(true);
+    let mut coverage = hff_rs::coverage::Coverage::new();
+    for path in files {
+        let explained = read_resources(path).and_then(|resources| {
+            resources
+                .iter()
+                .flat_map(bundle_entries)
+                .map(|r| reformat(render, &huff, r))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        match explained {
+            Ok(trees) => trees.iter().for_each(|tree| coverage.add(tree)),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return ExitCode::from(2);
+            }
+        }
+    }
+    print!("{}", coverage.to_report());
+    ExitCode::SUCCESS
+}
+
+/**
+ * Load the given mapping files, or the mapping given with -m, or the default mapping.
+ */
+fn load_mappings(
+    mapping: Option<&String>,
+    files: &[String],
+) -> Vec<(String, io::Result<Vec<String>>)> {
+    let mut files = files.to_vec();
+    if files.is_empty() {
+        files.extend(mapping.cloned());
+    }
+    if files.is_empty() {
+        return vec![(
+            "default".to_string(),
+            hff_rs::mapping::load_default_mapping(),
+        )];
+    }
+    files
+        .iter()
+        .map(|path| {
+            let mapping = hff_rs::mapping::load_mapping_from_file(Path::new(path));
+            (path.clone(), mapping)
+        })
+        .collect()
+}
+
+/**
+ * Exits with 1 if any of the mappings has errors, warnings are just reported.
+ */
+fn check(mapping: Option<&String>, files: &[String]) -> ExitCode {
+    let mut has_errors = false;
+    for (name, mapping) in load_mappings(mapping, files) {
+        match mapping {
+            Ok(lines) => {
+                for d in hff_rs::mapping::validate_mapping(&lines) {
+                    has_errors |= d.severity == hff_rs::mapping::Severity::Error;
+                    println!("{}:{}", name, d);
+                }
             }
-            None => {
-                println!("{}", hff_rs::builder().run(response).unwrap());
+            Err(e) => {
+                has_errors = true;
+                eprintln!("Error: {}: {}", name, e);
             }
-        },
+        }
+    }
+    if has_errors {
+        ExitCode::from(1)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/**
+ * Exits with 1 if any of the tests fails.
+ */
+fn test(mapping: Option<&String>, files: &[String]) -> ExitCode {
+    let (mut passed, mut failed) = (0, 0);
+    for (name, mapping) in load_mappings(mapping, files) {
+        match mapping.and_then(|lines| hff_rs::mapping::test_mapping(&lines)) {
+            Ok(results) => {
+                for result in results {
+                    if result.passed() {
+                        passed += 1;
+                        continue;
+                    }
+                    failed += 1;
+                    match &result.rule {
+                        Some(rule) => println!(
+                            "{}:{}: expected `{}`, got `{}` (rule in {})",
+                            name,
+                            result.test.line,
+                            result.test.expected,
+                            result.actual,
+                            rule.location()
+                        ),
+                        None => println!(
+                            "{}:{}: no rule matches the test input",
+                            name, result.test.line
+                        ),
+                    }
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error: {}: {}", name, e);
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        ExitCode::from(1)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/**
+ * Structured mappings are converted to *.hfc, anything else to the structured format.
+ */
+fn convert(path: &str) -> ExitCode {
+    let converted = std::fs::read_to_string(path).and_then(|content| {
+        if hff_rs::structured::is_structured(Path::new(path)) {
+            hff_rs::structured::structured_to_hfc(&content).map(|lines| lines.join("\n") + "\n")
+        } else {
+            let lines = content.lines().map(String::from).collect::<Vec<String>>();
+            hff_rs::structured::hfc_to_structured(&lines)
+                .and_then(|structured| hff_rs::structured::structured_to_yaml(&structured))
+        }
+    });
+    match converted {
+        Ok(output) => {
+            print!("{}", output);
+            ExitCode::SUCCESS
+        }
         Err(e) => {
-            eprintln!("Error parsing JSON: {}", e);
+            eprintln!("Error: {}: {}", path, e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/**
+ * Exits with 0 if both resources render the same, 1 if they differ and 2 on errors (like diff(1)).
+ */
+fn diff(
+    render: &RenderArgs,
+    huff: &hff_rs::HuffBuilder,
+    color: ColorChoice,
+    old: &str,
+    new: &str,
+) -> ExitCode {
+    let trees = read_json_file(old)
+        .and_then(|a| reformat(render, huff, &a))
+        .and_then(|a| Ok((a, reformat(render, huff, &read_json_file(new)?)?)));
+    match trees {
+        Ok((a, b)) => {
+            let lines = hff_rs::diff::diff_trees(&a, &b);
+            print!("{}", hff_rs::diff::to_unified(&lines, color.enabled()));
+            if hff_rs::diff::has_changes(&lines) {
+                ExitCode::from(1)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(2)
         }
     }
 }
@@ -1,4 +1,4 @@
-use serde_json;
+use serde::Deserialize;
 use serde_json::json;
 use wasm_bindgen::prelude::*;
 
@@ -36,6 +36,141 @@ pub fn js_fhir_to_huff_custom(fhir_str: &str, mapping_str: &str) -> String {
     }
 }
 
+/**
+ * Same as `js_fhir_to_huff`, but every object is annotated with its signature and the line of the
+ * mapping rule that matched (or `@rule: none`), to debug mappings.
+ */
+#[wasm_bindgen]
+pub fn js_fhir_to_huff_explain(fhir_str: &str) -> String {
+    match &serde_json::from_str(fhir_str) {
+        Ok(fhir_obj) => match hff::builder().with_explain(true).run(fhir_obj) {
+            Ok(friendly_yaml) => json!({ "success": true, "yaml": friendly_yaml }).to_string(),
+            Err(e) => json!({ "success": false, "error": e.to_string() }).to_string(),
+        },
+
+        Err(e) => json!({ "success": false, "error": e.to_string() }).to_string(),
+    }
+}
+
+/**
+ * Same as `js_fhir_to_huff_custom`, but with the annotations of `js_fhir_to_huff_explain`.
+ */
+#[wasm_bindgen]
+pub fn js_fhir_to_huff_custom_explain(fhir_str: &str, mapping_str: &str) -> String {
+    match &serde_json::from_str(fhir_str) {
+        Ok(fhir_obj) => match hff::builder()
+            .with_explain(true)
+            .with_string(mapping_str)
+            .run(fhir_obj)
+        {
+            Ok(friendly_yaml) => json!({ "success": true, "yaml": friendly_yaml }).to_string(),
+            Err(e) => json!({ "success": false, "error": e.to_string() }).to_string(),
+        },
+
+        Err(e) => json!({ "success": false, "error": e.to_string() }).to_string(),
+    }
+}
+
+/**
+ * Options of `js_fhir_to_huff_with_options`, all of them optional.
+ */
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct JsOptions {
+    // a custom mapping, the default mapping if missing
+    mapping: Option<String>,
+    explain: bool,
+    // `keep`, `text`, `markdown` or `drop`
+    narrative: Option<String>,
+    // for YAML: line width to fold long strings at, 0 for no limit
+    width: Option<usize>,
+    // `yaml` (the default), `html`, `markdown` or `fsh`
+    format: Option<String>,
+    // for HTML: base URL of the FHIR server that references are linked to
+    base_url: Option<String>,
+    // for HTML: turn URLs into links
+    links: bool,
+}
+
+/**
+ * To be called from JavaScript. Input should be a JSON-FHIR string and a JSON string with options:
+ * `{"mapping": "...", "explain": true, "narrative": "markdown", "width": 80}`, or for HTML output
+ * `{"format": "html", "base_url": "https://fhir.com/r4", "links": true}`, all of them optional.
+ * `"format": "markdown"` renders Markdown and `"format": "fsh"` a FHIR Shorthand instance. Result is
+ * a JSON string with a "success" boolean and a "yaml", "html", "markdown", "fsh" or "error" string.
+ */
+#[wasm_bindgen]
+pub fn js_fhir_to_huff_with_options(fhir_str: &str, options_str: &str) -> String {
+    match fhir_to_huff_with_options(fhir_str, options_str) {
+        Ok((format, output)) => json!({ "success": true, format: output }).to_string(),
+        Err(e) => json!({ "success": false, "error": e.to_string() }).to_string(),
+    }
+}
+
+fn fhir_to_huff_with_options(
+    fhir_str: &str,
+    options_str: &str,
+) -> Result<(&'static str, String), Box<dyn std::error::Error>> {
+    let fhir_obj: serde_json::Value = serde_json::from_str(fhir_str)?;
+    let options: JsOptions = serde_json::from_str(options_str)?;
+    let mut builder = hff::builder().with_explain(options.explain);
+    if let Some(narrative) = &options.narrative {
+        builder = builder.with_narrative(narrative.parse()?);
+    }
+    if let Some(width) = options.width {
+        builder = builder.with_line_width(width);
+    }
+    let format = options.format.as_deref().unwrap_or("yaml");
+    if format == "yaml" {
+        let friendly_yaml = match &options.mapping {
+            Some(mapping_str) => builder.with_string(mapping_str).run(&fhir_obj)?,
+            None => builder.run(&fhir_obj)?,
+        };
+        return Ok(("yaml", friendly_yaml));
+    }
+    if format == "fsh" {
+        return Ok(("fsh", hff::fsh::to_fsh(&fhir_obj)?));
+    }
+    if format != "html" && format != "markdown" {
+        return Err(format!(
+            "unknown format `{}`, expected yaml, html, markdown or fsh",
+            format
+        )
+        .into());
+    }
+    let tree = match &options.mapping {
+        Some(mapping_str) => builder.with_string(mapping_str).reformat(&fhir_obj)?,
+        None => builder.reformat(&fhir_obj)?,
+    };
+    match format {
+        "html" => {
+            let html_options = hff::html::HtmlOptions {
+                base_url: options.base_url,
+                links: options.links,
+            };
+            Ok(("html", hff::html::to_html(&tree, &html_options)))
+        }
+        _ => Ok(("markdown", hff::markdown::to_markdown(&tree))),
+    }
+}
+
+/**
+ * Guess the base URL of the FHIR server from the URL of the page a response is shown on,
+ * e.g. `https://fhir.com/r4` for `https://fhir.com/r4/Patient/123`. Empty if there is no resource type in the path.
+ */
+#[wasm_bindgen]
+pub fn js_guess_base_url(page_url: &str) -> String {
+    hff::html::guess_base_url(page_url).unwrap_or_default()
+}
+
+/**
+ * The default styles of the HTML output, which lay out the collapsible sections and color the `hff-*` classes.
+ */
+#[wasm_bindgen]
+pub fn js_stylesheet() -> String {
+    hff::html::STYLESHEET.to_string()
+}
+
 /**
  * Pass the default mapping to the caller. This will most likely be used as a starting point for custom mappings.
  */